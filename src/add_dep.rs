@@ -0,0 +1,155 @@
+//! Adding a dependency on a crate of the registry to a manifest, with its `registry` key, like
+//! `cargo add` but reading the versions from the index directly.
+use std::path::Path;
+
+use cargo_metadata::semver::{Version, VersionReq};
+use log::*;
+
+use crate::{IndexMeta, Registry};
+
+/// Section of the manifest the dependency is added to
+#[derive(Clone, Copy)]
+pub enum DependencyKind {
+    Normal,
+    Dev,
+    Build,
+}
+impl DependencyKind {
+    fn section(self) -> &'static str {
+        match self {
+            Self::Normal => "dependencies",
+            Self::Dev => "dev-dependencies",
+            Self::Build => "build-dependencies",
+        }
+    }
+}
+
+pub struct AddOptions {
+    /// Name of the registry in the cargo configuration
+    pub registry_name: String,
+    pub kind: DependencyKind,
+    pub features: Vec<String>,
+    pub optional: bool,
+}
+
+/// A `rust-version`, e.g. `1.70`, as a full version
+fn parse_rust_version(version: &str) -> Option<Version> {
+    match version.matches('.').count() {
+        0 => format!("{}.0.0", version).parse().ok(),
+        1 => format!("{}.0", version).parse().ok(),
+        _ => version.parse().ok(),
+    }
+}
+
+/// `rust-version` of a manifest, unless inherited from the workspace
+fn rust_version(manifest: &toml_edit::DocumentMut) -> Option<Version> {
+    parse_rust_version(manifest.get("package")?.get("rust-version")?.as_str()?)
+}
+
+/// Highest version, preferring releases over pre-releases
+fn latest(versions: Vec<IndexMeta>) -> Option<IndexMeta> {
+    versions
+        .into_iter()
+        .max_by_key(|m| (m.vers.pre.is_empty(), m.vers.clone()))
+}
+
+impl Registry {
+    /// Highest non-yanked version matching `req`, preferring releases over pre-releases, and
+    /// versions whose `rust-version` is at most `rust_version` when there are some
+    pub fn compatible_version(
+        &self,
+        name: &str,
+        req: Option<&VersionReq>,
+        rust_version: Option<&Version>,
+    ) -> anyhow::Result<IndexMeta> {
+        let versions = self.read_package(name)?;
+        anyhow::ensure!(!versions.is_empty(), "{} not found in the registry", name);
+        let (supported, unsupported): (Vec<_>, Vec<_>) = versions
+            .into_iter()
+            .filter(|m| !m.yanked && req.map_or(true, |req| req.matches(&m.vers)))
+            .partition(|m| {
+                match (
+                    m.rust_version.as_deref().and_then(parse_rust_version),
+                    rust_version,
+                ) {
+                    (Some(required), Some(available)) => &required <= available,
+                    _ => true,
+                }
+            });
+        if let Some(meta) = latest(supported) {
+            return Ok(meta);
+        }
+        let Some(meta) = latest(unsupported) else {
+            anyhow::bail!("No matching version of {} in the registry", name);
+        };
+        warn!(
+            "{} {} requires Rust {}, newer than the rust-version of the manifest",
+            meta.name,
+            meta.vers,
+            meta.rust_version.as_deref().unwrap_or_default()
+        );
+        Ok(meta)
+    }
+}
+
+/// Add a dependency on the crate `name` of the registry to the manifest, or update it, with the
+/// latest version matching `req`. Returns the chosen version.
+pub fn add_dependency(
+    registry: &Registry,
+    manifest_path: &Path,
+    name: &str,
+    req: Option<&VersionReq>,
+    options: &AddOptions,
+) -> anyhow::Result<Version> {
+    let mut manifest: toml_edit::DocumentMut = std::fs::read_to_string(manifest_path)?.parse()?;
+    let meta = registry.compatible_version(name, req, rust_version(&manifest).as_ref())?;
+    let section = manifest
+        .entry(options.kind.section())
+        .or_insert(toml_edit::table())
+        .as_table_like_mut()
+        .ok_or_else(|| anyhow::anyhow!("[{}] is not a table", options.kind.section()))?;
+    // Keep the other keys of an existing dependency, e.g. `default-features`
+    let mut dependency = match section.remove(&meta.name) {
+        Some(toml_edit::Item::Value(toml_edit::Value::InlineTable(table))) => table,
+        Some(toml_edit::Item::Table(table)) => table.into_inline_table(),
+        _ => Default::default(),
+    };
+    dependency.insert("version", meta.vers.to_string().into());
+    dependency.insert("registry", options.registry_name.as_str().into());
+    if !options.features.is_empty() {
+        let mut features = dependency
+            .get("features")
+            .and_then(|f| f.as_array())
+            .cloned()
+            .unwrap_or_default();
+        for feature in &options.features {
+            // Optional dependencies not referenced with `dep:` are implicit features
+            anyhow::ensure!(
+                meta.all_features().any(|(f, _)| f == feature)
+                    || meta.deps.iter().any(|d| d.optional && &d.name == feature),
+                "{} {} has no feature {}",
+                meta.name,
+                meta.vers,
+                feature
+            );
+            if !features.iter().any(|f| f.as_str() == Some(feature)) {
+                features.push(feature.as_str());
+            }
+        }
+        dependency.insert("features", features.into());
+    }
+    if options.optional {
+        dependency.insert("optional", true.into());
+    }
+    dependency.fmt();
+    section.insert(&meta.name, toml_edit::value(dependency));
+    std::fs::write(manifest_path, manifest.to_string())?;
+    info!(
+        "Added {} {} to [{}] of {:?}",
+        meta.name,
+        meta.vers,
+        options.kind.section(),
+        manifest_path
+    );
+    Ok(meta.vers)
+}
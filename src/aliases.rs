@@ -0,0 +1,102 @@
+//! Named versions of a crate (e.g. `stable`), stored in `crates/<crate>/aliases.json` next to
+//! the archives, so that tools other than cargo can find them on a plain file server. The
+//! `latest` alias is maintained automatically.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use cargo_metadata::semver::Version;
+
+use crate::{Registry, CRATES};
+
+pub const LATEST: &str = "latest";
+
+pub type Aliases = BTreeMap<String, Version>;
+
+impl Registry {
+    fn aliases_path(&self, name: &str) -> PathBuf {
+        self.0.join(CRATES).join(name).join("aliases.json")
+    }
+    pub fn read_aliases(&self, name: &str) -> anyhow::Result<Aliases> {
+        let path = self.aliases_path(name);
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+    fn write_aliases(&self, name: &str, aliases: &Aliases) -> anyhow::Result<()> {
+        let path = self.aliases_path(name);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        crate::write_atomic(&path, serde_json::to_string_pretty(aliases)?)
+    }
+    pub fn set_alias(&self, name: &str, alias: &str, version: &Version) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        anyhow::ensure!(alias != LATEST, "The {} alias is set automatically", LATEST);
+        anyhow::ensure!(
+            self.read_package(name)?.iter().any(|m| &m.vers == version),
+            "{} {} not found in the registry",
+            name,
+            version
+        );
+        let mut aliases = self.read_aliases(name)?;
+        aliases.insert(alias.into(), version.clone());
+        self.write_aliases(name, &aliases)
+    }
+    pub fn remove_alias(&self, name: &str, alias: &str) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        anyhow::ensure!(alias != LATEST, "The {} alias is set automatically", LATEST);
+        let mut aliases = self.read_aliases(name)?;
+        anyhow::ensure!(
+            aliases.remove(alias).is_some(),
+            "{} has no alias {}",
+            name,
+            alias
+        );
+        self.write_aliases(name, &aliases)
+    }
+    /// Highest non-yanked version, preferring releases over pre-releases
+    fn latest_version(&self, name: &str) -> anyhow::Result<Option<Version>> {
+        Ok(self
+            .read_package(name)?
+            .into_iter()
+            .filter(|m| !m.yanked)
+            .map(|m| m.vers)
+            .max_by_key(|v| (v.pre.is_empty(), v.clone())))
+    }
+    pub(crate) fn update_latest(&self, name: &str) -> anyhow::Result<()> {
+        let latest = self.latest_version(name)?;
+        let mut aliases = self.read_aliases(name)?;
+        if aliases.get(LATEST) == latest.as_ref() {
+            return Ok(());
+        }
+        match latest {
+            Some(latest) => aliases.insert(LATEST.into(), latest),
+            None => aliases.remove(LATEST),
+        };
+        self.write_aliases(name, &aliases)
+    }
+    /// Drop the aliases pointing to a removed version
+    pub(crate) fn remove_version_aliases(
+        &self,
+        name: &str,
+        version: &Version,
+    ) -> anyhow::Result<()> {
+        let mut aliases = self.read_aliases(name)?;
+        let count = aliases.len();
+        aliases.retain(|_, v| v != version);
+        if aliases.len() == count {
+            return Ok(());
+        }
+        self.write_aliases(name, &aliases)
+    }
+    pub fn resolve_alias(&self, name: &str, alias: &str) -> anyhow::Result<Version> {
+        let version = match alias {
+            // Registries created before aliases have no aliases.json
+            LATEST => self.latest_version(name)?,
+            alias => self.read_aliases(name)?.remove(alias),
+        };
+        let Some(version) = version else {
+            anyhow::bail!("{} has no alias {}", name, alias);
+        };
+        Ok(version)
+    }
+}
@@ -0,0 +1,103 @@
+//! Public API comparison between two published versions, based on rustdoc's JSON output
+//! (requires a nightly toolchain).
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use cargo_metadata::semver::Version;
+use log::*;
+
+use crate::{unpack_crate, Registry};
+
+/// Remove identifiers, which are not stable between rustdoc runs
+fn normalize(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.remove("id");
+            map.values_mut().for_each(normalize);
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(normalize),
+        _ => {}
+    }
+}
+
+/// Public items of the crate in `dir`, by path, with their kind and normalized definition
+fn public_api(dir: &Path) -> anyhow::Result<BTreeMap<String, (String, serde_json::Value)>> {
+    let out = std::process::Command::new("cargo")
+        .args(["+nightly", "rustdoc", "--lib", "--all-features", "--"])
+        .args(["-Z", "unstable-options", "--output-format", "json"])
+        .current_dir(dir)
+        .status()?;
+    anyhow::ensure!(out.success(), "Failed to run rustdoc");
+    let Some(json) = std::fs::read_dir(dir.join("target").join("doc"))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|p| p.extension().map_or(false, |e| e == "json"))
+    else {
+        anyhow::bail!("rustdoc did not produce a JSON file");
+    };
+    let doc: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(json)?)?;
+    let mut items = BTreeMap::new();
+    for (id, summary) in doc["paths"].as_object().into_iter().flatten() {
+        // Local crate only
+        if summary["crate_id"] != 0 {
+            continue;
+        }
+        let path = summary["path"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|s| s.as_str())
+            .collect::<Vec<_>>()
+            .join("::");
+        let mut inner = doc["index"][id]["inner"].clone();
+        normalize(&mut inner);
+        let kind = summary["kind"].as_str().unwrap_or_default().to_string();
+        items.insert(path, (kind, inner));
+    }
+    Ok(items)
+}
+
+/// Human-readable list of added (+), removed (-) and changed (~) public items
+pub fn api_diff(
+    registry: &Registry,
+    name: &str,
+    old: &Version,
+    new: &Version,
+) -> anyhow::Result<String> {
+    let mut apis = vec![];
+    for version in [old, new] {
+        let path = registry.crate_path(name, version);
+        anyhow::ensure!(
+            path.exists(),
+            "{} {} not found in the registry",
+            name,
+            version
+        );
+        info!("Generating rustdoc JSON for {} {}", name, version);
+        let dir = unpack_crate(&path)?;
+        apis.push(public_api(
+            &dir.path().join(format!("{}-{}", name, version)),
+        )?);
+    }
+    let (old_api, new_api) = (&apis[0], &apis[1]);
+    let mut report = format!("Public API changes in {} from {} to {}\n", name, old, new);
+    for (path, (kind, inner)) in old_api {
+        match new_api.get(path) {
+            None => writeln!(report, "- {} {}", kind, path)?,
+            // Changes to modules are reflected by their items
+            Some((new_kind, new_inner))
+                if new_kind != kind || (kind != "module" && new_inner != inner) =>
+            {
+                writeln!(report, "~ {} {}", new_kind, path)?
+            }
+            Some(_) => {}
+        }
+    }
+    for (path, (kind, _)) in new_api {
+        if !old_api.contains_key(path) {
+            writeln!(report, "+ {} {}", kind, path)?;
+        }
+    }
+    Ok(report)
+}
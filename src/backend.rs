@@ -0,0 +1,619 @@
+//! Remote storage for the registry. The registry is checked out into a local directory, on
+//! which all the operations run, and the modified files are uploaded at the end of the run.
+//!
+//! A lock object is held during the run, and uploads are conditional on the objects being
+//! unchanged since the checkout, so that concurrent runs fail instead of overwriting each
+//! other's changes.
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use hmac::Mac;
+use itertools::Itertools;
+use log::*;
+use sha2::Digest;
+
+use crate::sha256_file;
+
+/// Key of the lock object held during a run
+pub const REMOTE_LOCK: &str = "checkout.lock";
+
+/// Precondition of a write
+pub enum Condition {
+    /// The object does not exist
+    Absent,
+    /// The object has this ETag
+    Matches(String),
+}
+
+/// Object storage, addressed by keys relative to the root of the registry
+pub trait Backend {
+    /// Contents and ETag of an object
+    fn get(&self, key: &str) -> anyhow::Result<Option<(Vec<u8>, String)>>;
+    /// Returns false, without writing, if the condition does not hold
+    fn put(&self, key: &str, data: &[u8], condition: Option<&Condition>) -> anyhow::Result<bool>;
+    fn delete(&self, key: &str) -> anyhow::Result<()>;
+    /// Keys starting with a prefix, with their ETags
+    fn list(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>>;
+}
+
+/// S3-compatible object storage, configured with the usual `AWS_ACCESS_KEY_ID`,
+/// `AWS_SECRET_ACCESS_KEY`, `AWS_SESSION_TOKEN`, `AWS_REGION` and `AWS_ENDPOINT_URL`
+/// environment variables.
+pub struct S3 {
+    endpoint: String,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+/// RFC 3986 percent-encoding, keeping `/` when encoding paths
+fn uri_encode(s: &str, keep_slash: bool) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b'/' if keep_slash => "/".into(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(key).expect("any key size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// `YYYYMMDD` and `YYYYMMDDTHHMMSSZ` timestamps in UTC
+fn amz_date(time: SystemTime) -> (String, String) {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let datetime = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    );
+    (date, datetime)
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Contents of the `<tag>` elements in an XML document
+fn xml_values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|s| s.split_once(close.as_str()).map(|(v, _)| v))
+        .collect()
+}
+
+impl S3 {
+    /// From `s3://<bucket>/<prefix>`
+    pub fn from_url(url: &str) -> anyhow::Result<Self> {
+        let Some(path) = url.strip_prefix("s3://") else {
+            anyhow::bail!("Expected s3://<bucket>/<prefix>, got {}", url);
+        };
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        let env = |key: &str| std::env::var(key).ok().filter(|v| !v.is_empty());
+        let region = env("AWS_REGION")
+            .or_else(|| env("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".into());
+        let (Some(access_key), Some(secret_key)) =
+            (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY"))
+        else {
+            anyhow::bail!("Set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY to use S3");
+        };
+        Ok(Self {
+            endpoint: env("AWS_ENDPOINT_URL")
+                .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region))
+                .trim_end_matches('/')
+                .into(),
+            bucket: bucket.into(),
+            prefix: prefix.trim_matches('/').into(),
+            region,
+            access_key,
+            secret_key,
+            session_token: env("AWS_SESSION_TOKEN"),
+        })
+    }
+    fn object_key(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.into()
+        } else {
+            format!("{}/{}", self.prefix, key)
+        }
+    }
+    /// Build a request signed with AWS Signature Version 4, using path-style addressing
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        payload: &[u8],
+    ) -> ureq::Request {
+        let path = match key {
+            "" => format!("/{}", self.bucket),
+            key => format!("/{}/{}", self.bucket, uri_encode(key, true)),
+        };
+        let query = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, false), uri_encode(v, false)))
+            .sorted()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .join("&");
+        let host = self
+            .endpoint
+            .split_once("://")
+            .map_or(self.endpoint.as_str(), |(_, h)| h);
+        let payload_hash = format!("{:x}", sha2::Sha256::digest(payload));
+        let (date, datetime) = amz_date(SystemTime::now());
+
+        let mut headers = vec![
+            ("host", host.to_string()),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", datetime.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers.iter().map(|(k, _)| *k).join(";");
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            path,
+            query,
+            headers
+                .iter()
+                .map(|(k, v)| format!("{}:{}\n", k, v.trim()))
+                .join(""),
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            datetime,
+            scope,
+            sha2::Sha256::digest(canonical_request.as_bytes())
+        );
+        let mut key = format!("AWS4{}", self.secret_key).into_bytes();
+        for part in [date.as_str(), &self.region, "s3", "aws4_request"] {
+            key = hmac_sha256(&key, part);
+        }
+        let signature = hmac_sha256(&key, &string_to_sign)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .join("");
+
+        let mut url = format!("{}{}", self.endpoint, path);
+        if !query.is_empty() {
+            url = format!("{}?{}", url, query);
+        }
+        let mut request = crate::http::request(method, &url).set(
+            "Authorization",
+            &format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, scope, signed_headers, signature
+            ),
+        );
+        for (k, v) in headers.iter().filter(|(k, _)| *k != "host") {
+            request = request.set(k, v);
+        }
+        request
+    }
+}
+
+impl Backend for S3 {
+    fn get(&self, key: &str) -> anyhow::Result<Option<(Vec<u8>, String)>> {
+        match self.request("GET", &self.object_key(key), &[], &[]).call() {
+            Ok(response) => {
+                let etag = response.header("ETag").unwrap_or_default().to_string();
+                let mut data = vec![];
+                response.into_reader().read_to_end(&mut data)?;
+                Ok(Some((data, etag)))
+            }
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    fn put(&self, key: &str, data: &[u8], condition: Option<&Condition>) -> anyhow::Result<bool> {
+        let mut request = self.request("PUT", &self.object_key(key), &[], data);
+        request = match condition {
+            Some(Condition::Absent) => request.set("If-None-Match", "*"),
+            Some(Condition::Matches(etag)) => request.set("If-Match", etag),
+            None => request,
+        };
+        match request.send_bytes(data) {
+            Ok(_) => Ok(true),
+            // 409 when a concurrent conditional write is in progress
+            Err(ureq::Error::Status(412 | 409, _)) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.request("DELETE", &self.object_key(key), &[], &[])
+            .call()?;
+        Ok(())
+    }
+    fn list(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let full_prefix = self.object_key(prefix);
+        let mut keys = vec![];
+        let mut continuation: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", full_prefix.as_str())];
+            if let Some(token) = &continuation {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let xml = self.request("GET", "", &query, &[]).call()?.into_string()?;
+            for object in xml_values(&xml, "Contents") {
+                let Some(key) = xml_values(object, "Key").first().map(|k| xml_unescape(k)) else {
+                    continue;
+                };
+                let etag = xml_values(object, "ETag")
+                    .first()
+                    .map(|e| xml_unescape(e))
+                    .unwrap_or_default();
+                let key = match self.prefix.is_empty() {
+                    true => key.as_str(),
+                    false => key
+                        .strip_prefix(&self.prefix)
+                        .and_then(|k| k.strip_prefix('/'))
+                        .unwrap_or(&key),
+                };
+                keys.push((key.to_string(), etag));
+            }
+            continuation = xml_values(&xml, "NextContinuationToken")
+                .first()
+                .map(|t| xml_unescape(t));
+            if xml_values(&xml, "IsTruncated").first() != Some(&"true") || continuation.is_none() {
+                return Ok(keys);
+            }
+        }
+    }
+}
+
+/// Local copy of a remote registry. Archives are only downloaded by `fetch_archives`, for the
+/// commands reading them, and prebuilt binaries are only uploaded when added.
+pub struct Checkout {
+    backend: Box<dyn Backend>,
+    dir: tempfile::TempDir,
+    /// Hashes of the downloaded files, to detect modifications
+    downloaded: BTreeMap<String, String>,
+    /// ETags of the objects present in the storage at checkout
+    etags: BTreeMap<String, String>,
+    /// Archives present in the storage, which are not downloaded by default
+    archives: Vec<String>,
+}
+
+fn is_large(key: &str) -> bool {
+    key.ends_with(".crate") || key.starts_with(&format!("{}/", crate::dist::DIST))
+}
+
+/// Take the lock object, failing if another run holds it
+fn lock(backend: &dyn Backend) -> anyhow::Result<()> {
+    let seconds = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    let owner = format!(
+        "pid {} on {} since {}",
+        std::process::id(),
+        std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown host".into()),
+        String::from(crate::freeze::Time(seconds))
+    );
+    if backend.put(REMOTE_LOCK, owner.as_bytes(), Some(&Condition::Absent))? {
+        return Ok(());
+    }
+    let holder = backend
+        .get(REMOTE_LOCK)?
+        .map(|(data, _)| String::from_utf8_lossy(&data).into_owned())
+        .unwrap_or_default();
+    anyhow::bail!(
+        "The registry is locked by another run ({}). If that run was interrupted, delete {} from the storage.",
+        holder,
+        REMOTE_LOCK
+    )
+}
+
+impl Checkout {
+    pub fn new(backend: Box<dyn Backend>) -> anyhow::Result<Self> {
+        let dir = crate::temp::tempdir()?;
+        lock(backend.as_ref())?;
+        let mut checkout = Self {
+            backend,
+            dir,
+            downloaded: BTreeMap::new(),
+            etags: BTreeMap::new(),
+            archives: vec![],
+        };
+        // Dropping the checkout releases the lock
+        checkout.download()?;
+        Ok(checkout)
+    }
+    fn download(&mut self) -> anyhow::Result<()> {
+        self.etags = self
+            .backend
+            .list("")?
+            .into_iter()
+            .filter(|(key, _)| key != REMOTE_LOCK)
+            .collect();
+        self.archives = self
+            .etags
+            .keys()
+            .filter(|k| k.starts_with(&format!("{}/", crate::CRATES)) && k.ends_with(".crate"))
+            .cloned()
+            .collect();
+        let keys = self
+            .etags
+            .keys()
+            .filter(|k| !is_large(k))
+            .cloned()
+            .collect_vec();
+        info!("Checking out {} files", keys.len());
+        for key in keys {
+            self.get(&key)?;
+        }
+        Ok(())
+    }
+    fn get(&mut self, key: &str) -> anyhow::Result<()> {
+        let Some((data, etag)) = self.backend.get(key)? else {
+            return Ok(());
+        };
+        let path = self.dir.path().join(key);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, data)?;
+        self.downloaded.insert(key.into(), sha256_file(&path)?);
+        self.etags.insert(key.into(), etag);
+        Ok(())
+    }
+    /// Download the archives, for the commands reading them
+    pub fn fetch_archives(&mut self) -> anyhow::Result<()> {
+        info!("Downloading {} archives", self.archives.len());
+        for key in self.archives.clone() {
+            self.get(&key)?;
+        }
+        Ok(())
+    }
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+    /// Upload new and modified files, archives first and manifest.json last, so that the index
+    /// never references missing files.
+    pub fn commit(&self) -> anyhow::Result<()> {
+        let mut files: Vec<(String, PathBuf)> = vec![];
+        let mut stack = vec![self.path().to_path_buf()];
+        while let Some(dir) = stack.pop() {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    stack.push(path);
+                    continue;
+                }
+                let key = path
+                    .strip_prefix(self.path())?
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .join("/");
+                // Only meaningful for the local checkout
+                if key == crate::lock::LOCK {
+                    continue;
+                }
+                if self.downloaded.get(&key) != Some(&sha256_file(&path)?) {
+                    files.push((key, path));
+                }
+            }
+        }
+        files.sort_by_key(|(key, _)| {
+            if key.starts_with(&format!("{}/", crate::CRATES)) || is_large(key) {
+                0
+            } else if key == crate::MANIFEST {
+                2
+            } else {
+                1
+            }
+        });
+        for (key, path) in files {
+            info!("Uploading {}", key);
+            let condition = match self.etags.get(&key) {
+                Some(etag) => Condition::Matches(etag.clone()),
+                None => Condition::Absent,
+            };
+            anyhow::ensure!(
+                self.backend
+                    .put(&key, &std::fs::read(path)?, Some(&condition))?,
+                "{} was modified in the storage since the checkout, aborting the upload",
+                key
+            );
+        }
+        // Deletions go last, once the index no longer references the files
+        for key in self.removed()? {
+            info!("Deleting {}", key);
+            self.backend.delete(&key)?;
+        }
+        Ok(())
+    }
+    /// Downloaded files deleted locally, and archives whose version was removed from the index
+    /// during the run (e.g. by `remove`)
+    fn removed(&self) -> anyhow::Result<Vec<String>> {
+        let mut removed: Vec<String> = self
+            .downloaded
+            .keys()
+            .filter(|key| !self.path().join(key).exists())
+            .cloned()
+            .collect();
+        let registry = crate::Registry(self.path().into());
+        for key in &self.archives {
+            let Some((name, file)) = key
+                .strip_prefix(&format!("{}/", crate::CRATES))
+                .and_then(|k| k.split_once('/'))
+            else {
+                continue;
+            };
+            let Some(version) = file
+                .strip_suffix(".crate")
+                .and_then(|f| f.strip_prefix(&format!("{}-", name)))
+                .and_then(|v| v.parse::<cargo_metadata::semver::Version>().ok())
+            else {
+                continue;
+            };
+            let index = registry.package_index(name);
+            let index_key = index
+                .strip_prefix(self.path())?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .join("/");
+            let modified =
+                !index.exists() || self.downloaded.get(&index_key) != Some(&sha256_file(&index)?);
+            if modified
+                && !registry
+                    .read_package(name)?
+                    .iter()
+                    .any(|m| m.vers == version)
+                && !removed.contains(key)
+            {
+                removed.push(key.clone());
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        if let Err(e) = self.backend.delete(REMOTE_LOCK) {
+            warn!("Failed to release the lock {}: {:#}", REMOTE_LOCK, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// Contents and version of each object
+    type Objects = BTreeMap<String, (Vec<u8>, usize)>;
+
+    /// In-memory storage, with the version counter as ETag
+    #[derive(Clone, Default)]
+    struct Memory(Rc<RefCell<Objects>>);
+
+    impl Backend for Memory {
+        fn get(&self, key: &str) -> anyhow::Result<Option<(Vec<u8>, String)>> {
+            Ok(self
+                .0
+                .borrow()
+                .get(key)
+                .map(|(data, version)| (data.clone(), version.to_string())))
+        }
+        fn put(
+            &self,
+            key: &str,
+            data: &[u8],
+            condition: Option<&Condition>,
+        ) -> anyhow::Result<bool> {
+            let mut objects = self.0.borrow_mut();
+            let current = objects.get(key).map(|(_, version)| version.to_string());
+            let holds = match condition {
+                Some(Condition::Absent) => current.is_none(),
+                Some(Condition::Matches(etag)) => current.as_ref() == Some(etag),
+                None => true,
+            };
+            if holds {
+                let version = objects.get(key).map_or(0, |(_, version)| version + 1);
+                objects.insert(key.into(), (data.to_vec(), version));
+            }
+            Ok(holds)
+        }
+        fn delete(&self, key: &str) -> anyhow::Result<()> {
+            self.0.borrow_mut().remove(key);
+            Ok(())
+        }
+        fn list(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
+            Ok(self
+                .0
+                .borrow()
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix))
+                .map(|(key, (_, version))| (key.clone(), version.to_string()))
+                .collect())
+        }
+    }
+
+    #[test]
+    fn lock() {
+        let storage = Memory::default();
+        let checkout = Checkout::new(Box::new(storage.clone())).unwrap();
+        assert!(storage.get(REMOTE_LOCK).unwrap().is_some());
+        let error = Checkout::new(Box::new(storage.clone())).err().unwrap();
+        assert!(error.to_string().contains("locked by another run"));
+        drop(checkout);
+        assert!(storage.get(REMOTE_LOCK).unwrap().is_none());
+        Checkout::new(Box::new(storage)).unwrap();
+    }
+
+    #[test]
+    fn concurrent_modification() {
+        let storage = Memory::default();
+        storage.put("index/config.json", b"{}", None).unwrap();
+        storage.put("crates/a/a-0.1.0.crate", b"a", None).unwrap();
+
+        let mut checkout = Checkout::new(Box::new(storage.clone())).unwrap();
+        assert!(!checkout.path().join("crates/a/a-0.1.0.crate").exists());
+        checkout.fetch_archives().unwrap();
+        assert!(checkout.path().join("crates/a/a-0.1.0.crate").exists());
+        std::fs::write(checkout.path().join("index/config.json"), "{\"dl\":\"\"}").unwrap();
+        checkout.commit().unwrap();
+        assert_eq!(
+            storage.get("index/config.json").unwrap().unwrap().0,
+            b"{\"dl\":\"\"}"
+        );
+        drop(checkout);
+
+        // Written by another client after the checkout
+        let checkout = Checkout::new(Box::new(storage.clone())).unwrap();
+        storage.put("index/config.json", b"{}", None).unwrap();
+        std::fs::write(checkout.path().join("index/config.json"), "{\"dl\":\"x\"}").unwrap();
+        let error = checkout.commit().unwrap_err();
+        assert!(error.to_string().contains("modified in the storage"));
+        assert_eq!(storage.get("index/config.json").unwrap().unwrap().0, b"{}");
+        drop(checkout);
+
+        // Created by another client after the checkout
+        let checkout = Checkout::new(Box::new(storage.clone())).unwrap();
+        storage.put("index/1/b", b"b", None).unwrap();
+        std::fs::create_dir_all(checkout.path().join("index/1")).unwrap();
+        std::fs::write(checkout.path().join("index/1/b"), "c").unwrap();
+        assert!(checkout.commit().is_err());
+        assert_eq!(storage.get("index/1/b").unwrap().unwrap().0, b"b");
+    }
+}
@@ -0,0 +1,635 @@
+//! Reading manifests and building archives without invoking cargo (`--no-cargo`), for
+//! environments where cargo cannot run. Only a subset of the manifest format is supported, and
+//! the unsupported features are reported as errors.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use cargo_util_schemas::manifest::{
+    InheritableDependency, InheritableField, PackageName, StringOrBool, TomlDependency,
+    TomlManifest, TomlTarget, VecStringOrBool,
+};
+use itertools::Itertools;
+use log::*;
+use serde_json::json;
+
+const CRATES_IO: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+/// Keys inheriting their value from the workspace (`workspace = true`)
+fn inherited_keys(value: &toml::Value, path: &str, keys: &mut Vec<String>) {
+    let Some(table) = value.as_table() else {
+        return;
+    };
+    if table.get("workspace").and_then(|w| w.as_bool()) == Some(true) {
+        keys.push(path.into());
+    }
+    for (key, value) in table {
+        if path.is_empty() && key == "workspace" {
+            continue;
+        }
+        let path = match path {
+            "" => key.clone(),
+            path => format!("{}.{}", path, key),
+        };
+        inherited_keys(value, &path, keys);
+    }
+}
+
+fn read_manifest(path: &Path) -> anyhow::Result<TomlManifest> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut inherited = vec![];
+    inherited_keys(&toml::from_str(&contents)?, "", &mut inherited);
+    anyhow::ensure!(
+        inherited.is_empty(),
+        "{:?}: inheriting values from the workspace ({}) is not supported with --no-cargo",
+        path,
+        inherited.join(", ")
+    );
+    let manifest: TomlManifest = toml::from_str(&contents)?;
+    anyhow::ensure!(
+        manifest
+            .cargo_features
+            .as_ref()
+            .map_or(true, |f| f.is_empty()),
+        "{:?}: unstable cargo features are not supported with --no-cargo",
+        path
+    );
+    Ok(manifest)
+}
+
+fn value<T>(field: &Option<InheritableField<T>>) -> Option<&T> {
+    field.as_ref().and_then(|f| f.as_value())
+}
+
+/// Package directories matching workspace member patterns
+fn expand_members(root: &Path, patterns: &[String]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut members = vec![];
+    for pattern in patterns {
+        for path in glob::glob(&root.join(pattern).to_string_lossy())? {
+            let path = path?;
+            if path.join("Cargo.toml").exists() {
+                members.push(path.canonicalize()?);
+            }
+        }
+    }
+    Ok(members)
+}
+
+/// The README reported by cargo: the declared one, or a `README.md`, `README.txt` or `README`
+/// file in the package directory.
+fn readme(manifest: &TomlManifest, dir: &Path) -> Option<String> {
+    let package = manifest.package.as_ref()?;
+    match value(&package.readme) {
+        Some(StringOrBool::String(readme)) => Some(readme.clone()),
+        Some(StringOrBool::Bool(false)) => None,
+        Some(StringOrBool::Bool(true)) => Some("README.md".into()),
+        None => ["README.md", "README.txt", "README"]
+            .into_iter()
+            .find(|f| dir.join(f).is_file())
+            .map(String::from),
+    }
+}
+
+fn target(
+    name: &str,
+    kind: &str,
+    crate_types: Vec<String>,
+    src_path: PathBuf,
+) -> serde_json::Value {
+    json!({"name": name, "kind": [kind], "crate_types": crate_types, "src_path": src_path})
+}
+
+fn targets(manifest: &TomlManifest, dir: &Path) -> Vec<serde_json::Value> {
+    let package = manifest.package.as_ref().expect("checked by the caller");
+    let mut targets = vec![];
+    let lib_path = manifest
+        .lib
+        .as_ref()
+        .and_then(|l| l.path.as_ref())
+        .map_or_else(|| dir.join("src/lib.rs"), |p| dir.join(&p.0));
+    if manifest.lib.is_some() || lib_path.exists() {
+        let lib = manifest.lib.clone().unwrap_or_default();
+        let name = lib
+            .name
+            .clone()
+            .unwrap_or_else(|| package.name.replace('-', "_"));
+        let kind = if lib.proc_macro() == Some(true) {
+            "proc-macro".to_string()
+        } else {
+            lib.crate_types()
+                .and_then(|t| t.first().cloned())
+                .unwrap_or_else(|| "lib".into())
+        };
+        let crate_types = lib.crate_types().cloned().unwrap_or(vec![kind.clone()]);
+        targets.push(target(&name, &kind, crate_types, lib_path));
+    }
+    let mut bins: Vec<TomlTarget> = manifest.bin.clone().unwrap_or_default();
+    if package.autobins != Some(false)
+        && dir.join("src/main.rs").exists()
+        && !bins
+            .iter()
+            .any(|b| b.name.as_deref() == Some(package.name.as_str()))
+    {
+        bins.push(TomlTarget {
+            name: Some(package.name.to_string()),
+            ..Default::default()
+        });
+    }
+    for bin in bins {
+        let name = bin.name.clone().unwrap_or_else(|| package.name.to_string());
+        let path = bin.path.as_ref().map_or_else(
+            || match name == *package.name {
+                true => dir.join("src/main.rs"),
+                false => dir.join("src/bin").join(format!("{}.rs", name)),
+            },
+            |p| dir.join(&p.0),
+        );
+        targets.push(target(&name, "bin", vec!["bin".into()], path));
+    }
+    let build = match &package.build {
+        Some(StringOrBool::String(path)) => Some(dir.join(path)),
+        Some(StringOrBool::Bool(false)) => None,
+        _ => Some(dir.join("build.rs")).filter(|p| p.exists()),
+    };
+    if let Some(build) = build {
+        targets.push(target(
+            "build-script-build",
+            "custom-build",
+            vec!["bin".into()],
+            build,
+        ));
+    }
+    targets
+}
+
+type Dependencies = BTreeMap<PackageName, InheritableDependency>;
+
+/// Dependency tables of a manifest, with their kind and platform
+fn dependency_tables(
+    manifest: &TomlManifest,
+) -> Vec<(Option<&'static str>, Option<&str>, &Dependencies)> {
+    let mut tables = vec![
+        (None, None, manifest.dependencies.as_ref()),
+        (Some("dev"), None, manifest.dev_dependencies()),
+        (Some("build"), None, manifest.build_dependencies()),
+    ];
+    for (platform, t) in manifest.target.iter().flatten() {
+        tables.extend([
+            (None, Some(platform.as_str()), t.dependencies.as_ref()),
+            (Some("dev"), Some(platform.as_str()), t.dev_dependencies()),
+            (
+                Some("build"),
+                Some(platform.as_str()),
+                t.build_dependencies(),
+            ),
+        ]);
+    }
+    tables
+        .into_iter()
+        .filter_map(|(kind, platform, deps)| Some((kind, platform, deps?)))
+        .collect()
+}
+
+fn dependencies(manifest: &TomlManifest, dir: &Path) -> Vec<serde_json::Value> {
+    let mut deps = vec![];
+    for (kind, platform, table) in dependency_tables(manifest) {
+        for (name, dep) in table {
+            let InheritableDependency::Value(dep) = dep else {
+                unreachable!("rejected when reading the manifest");
+            };
+            let mut json = json!({
+                "name": name, "source": CRATES_IO, "req": "*", "kind": kind, "optional": false,
+                "uses_default_features": true, "features": [], "target": platform,
+                "rename": null, "registry": null, "path": null,
+            });
+            match dep {
+                TomlDependency::Simple(req) => json["req"] = json!(req),
+                TomlDependency::Detailed(d) => {
+                    if let Some(req) = &d.version {
+                        json["req"] = json!(req);
+                    }
+                    if let Some(package) = &d.package {
+                        json["name"] = json!(package);
+                        json["rename"] = json!(name);
+                    }
+                    if let Some(git) = &d.git {
+                        json["source"] = json!(format!("git+{}", git));
+                    } else if d.path.is_some() || d.registry.is_some() || d.registry_index.is_some()
+                    {
+                        json["source"] = json!(null);
+                    }
+                    if let Some(path) = &d.path {
+                        json["path"] = json!(dir.join(path));
+                    }
+                    json["optional"] = json!(d.optional.unwrap_or(false));
+                    json["uses_default_features"] = json!(d.default_features().unwrap_or(true));
+                    json["features"] = json!(d.features.clone().unwrap_or_default());
+                }
+            }
+            deps.push(json);
+        }
+    }
+    deps
+}
+
+/// Declared features, and the implicit features of optional dependencies not referenced with
+/// `dep:`, like `cargo metadata`.
+fn features(manifest: &TomlManifest, deps: &[serde_json::Value]) -> BTreeMap<String, Vec<String>> {
+    let mut features: BTreeMap<String, Vec<String>> = manifest
+        .features
+        .iter()
+        .flatten()
+        .map(|(k, v)| (k.to_string(), v.clone()))
+        .collect();
+    let explicit = features
+        .values()
+        .flatten()
+        .filter_map(|f| f.strip_prefix("dep:"))
+        .map(String::from)
+        .collect_vec();
+    for dep in deps.iter().filter(|d| d["optional"] == json!(true)) {
+        let name = dep["rename"].as_str().or(dep["name"].as_str()).unwrap();
+        if !explicit.iter().any(|e| e == name) && !features.contains_key(name) {
+            features.insert(name.into(), vec![format!("dep:{}", name)]);
+        }
+    }
+    features
+}
+
+/// Package metadata in the format of `cargo metadata`
+fn package_metadata(manifest_path: &Path) -> anyhow::Result<serde_json::Value> {
+    let manifest = read_manifest(manifest_path)?;
+    let dir = manifest_path.parent().unwrap();
+    let Some(package) = &manifest.package else {
+        anyhow::bail!("{:?} has no [package] section", manifest_path);
+    };
+    let version = value(&package.version)
+        .cloned()
+        .unwrap_or(cargo_metadata::semver::Version::new(0, 0, 0));
+    let publish = match value(&package.publish) {
+        // Packages without a version cannot be published
+        _ if package.version.is_none() => Some(vec![]),
+        Some(VecStringOrBool::Bool(false)) => Some(vec![]),
+        Some(VecStringOrBool::VecString(registries)) => Some(registries.clone()),
+        Some(VecStringOrBool::Bool(true)) | None => None,
+    };
+    let deps = dependencies(&manifest, dir);
+    Ok(json!({
+        "name": package.name,
+        "version": version,
+        "id": format!("path+file://{}#{}@{}", dir.display(), package.name, version),
+        "source": null,
+        "description": value(&package.description),
+        "license": value(&package.license),
+        "license_file": value(&package.license_file),
+        "readme": readme(&manifest, dir),
+        "repository": value(&package.repository),
+        "homepage": value(&package.homepage),
+        "documentation": value(&package.documentation),
+        "links": package.links,
+        "publish": publish,
+        "default_run": package.default_run,
+        "authors": value(&package.authors).cloned().unwrap_or_default(),
+        "categories": value(&package.categories).cloned().unwrap_or_default(),
+        "keywords": value(&package.keywords).cloned().unwrap_or_default(),
+        "edition": value(&package.edition).cloned().unwrap_or("2015".into()),
+        "rust_version": value(&package.rust_version),
+        "metadata": package.metadata,
+        "features": features(&manifest, &deps),
+        "dependencies": deps,
+        "targets": targets(&manifest, dir),
+        "manifest_path": manifest_path,
+    }))
+}
+
+/// Equivalent of `cargo metadata --no-deps` for a workspace, from its manifests
+pub fn metadata(workspace: &Path) -> anyhow::Result<cargo_metadata::Metadata> {
+    let root = workspace.canonicalize()?;
+    let manifest = read_manifest(&root.join("Cargo.toml"))?;
+    let mut members = vec![];
+    if manifest.package.is_some() {
+        members.push(root.clone());
+    }
+    let mut default_members = members.clone();
+    let mut workspace_metadata = None;
+    if let Some(workspace) = &manifest.workspace {
+        let excluded = expand_members(&root, workspace.exclude.as_deref().unwrap_or_default())?;
+        for member in expand_members(&root, workspace.members.as_deref().unwrap_or_default())? {
+            if !excluded.contains(&member) && !members.contains(&member) {
+                members.push(member);
+            }
+        }
+        default_members = match &workspace.default_members {
+            Some(patterns) => expand_members(&root, patterns)?,
+            None if manifest.package.is_some() => default_members,
+            None => members.clone(),
+        };
+        workspace_metadata = workspace.metadata.clone();
+    }
+    let mut packages = vec![];
+    for member in &members {
+        packages.push(package_metadata(&member.join("Cargo.toml"))?);
+    }
+    let id = |dir: &PathBuf| {
+        packages
+            .iter()
+            .zip(&members)
+            .find(|(_, m)| *m == dir)
+            .map(|(p, _)| p["id"].clone())
+    };
+    let default_members = default_members.iter().filter_map(id).collect_vec();
+    let target_directory = std::env::var_os("CARGO_TARGET_DIR")
+        .map(|d| root.join(d))
+        .unwrap_or_else(|| root.join("target"));
+    Ok(serde_json::from_value(json!({
+        "workspace_members": members.iter().filter_map(id).collect_vec(),
+        "workspace_default_members": default_members,
+        "packages": packages,
+        "resolve": null,
+        "workspace_root": root,
+        "target_directory": target_directory,
+        "metadata": workspace_metadata,
+        "version": 1,
+    }))?)
+}
+
+/// Match a path relative to the package against an `include`/`exclude` pattern, which
+/// follow the gitignore syntax.
+fn matches(pattern: &str, path: &Path) -> anyhow::Result<bool> {
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    let anchored = pattern.trim_end_matches('/').contains('/');
+    let pattern = glob::Pattern::new(pattern.trim_start_matches('/').trim_end_matches('/'))?;
+    // A pattern matching a directory matches its contents
+    Ok(path
+        .ancestors()
+        .filter(|a| !a.as_os_str().is_empty())
+        .any(|a| {
+            if anchored {
+                pattern.matches_path_with(a, options)
+            } else {
+                a.file_name().map_or(false, |f| {
+                    pattern.matches_with(&f.to_string_lossy(), options)
+                })
+            }
+        }))
+}
+
+/// Files of the package, relative to its directory. Like cargo, the `target` directory, hidden
+/// directories and nested packages are skipped, as well as hidden files unless explicitly
+/// included.
+fn package_files(dir: &Path, manifest: &TomlManifest) -> anyhow::Result<Vec<PathBuf>> {
+    let package = manifest.package.as_ref().expect("checked by the caller");
+    let include = value(&package.include);
+    let exclude = value(&package.exclude).cloned().unwrap_or_default();
+    let mut files = vec![];
+    let mut stack = vec![PathBuf::new()];
+    while let Some(relative) = stack.pop() {
+        for entry in std::fs::read_dir(dir.join(&relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                let skip = path == Path::new("target")
+                    || entry.file_name().to_string_lossy().starts_with('.')
+                    || entry.path().join("Cargo.toml").exists();
+                if !skip {
+                    stack.push(path);
+                }
+                continue;
+            }
+            let selected = match include {
+                Some(include) => include
+                    .iter()
+                    .map(|p| matches(p, &path))
+                    .fold_ok(false, |a, b| a || b)?,
+                None => {
+                    !entry.file_name().to_string_lossy().starts_with('.')
+                        && !exclude
+                            .iter()
+                            .map(|p| matches(p, &path))
+                            .fold_ok(false, |a, b| a || b)?
+                }
+            };
+            if selected {
+                files.push(path);
+            }
+        }
+    }
+    files.retain(|f| {
+        !["Cargo.toml", "Cargo.toml.orig", "Cargo.lock"].contains(&&*f.to_string_lossy())
+    });
+    files.sort();
+    Ok(files)
+}
+
+/// Remove the path and git sources from dependencies, like `cargo package`
+fn normalize_dependencies(deps: &mut Option<Dependencies>, dev: bool) -> anyhow::Result<()> {
+    let Some(deps) = deps else {
+        return Ok(());
+    };
+    for (name, dep) in std::mem::take(deps) {
+        let InheritableDependency::Value(TomlDependency::Detailed(mut d)) = dep else {
+            deps.insert(name, dep);
+            continue;
+        };
+        anyhow::ensure!(
+            d.registry.is_none(),
+            "Dependencies from named registries ({}) are not supported with --no-cargo",
+            name
+        );
+        if d.path.is_some() || d.git.is_some() {
+            if d.version.is_none() {
+                anyhow::ensure!(
+                    dev,
+                    "Dependency {} has no version, which is required for publishing",
+                    name
+                );
+                // Development dependencies without a version are removed
+                continue;
+            }
+            d.path = None;
+            d.git = None;
+            d.branch = None;
+            d.tag = None;
+            d.rev = None;
+        }
+        deps.insert(
+            name,
+            InheritableDependency::Value(TomlDependency::Detailed(d)),
+        );
+    }
+    Ok(())
+}
+
+fn normalize(manifest: &TomlManifest) -> anyhow::Result<TomlManifest> {
+    let mut manifest = manifest.clone();
+    manifest.workspace = None;
+    manifest.patch = None;
+    manifest.replace = None;
+    if let Some(package) = &mut manifest.package {
+        package.workspace = None;
+    }
+    normalize_dependencies(&mut manifest.dependencies, false)?;
+    normalize_dependencies(&mut manifest.build_dependencies, false)?;
+    normalize_dependencies(&mut manifest.build_dependencies2, false)?;
+    normalize_dependencies(&mut manifest.dev_dependencies, true)?;
+    normalize_dependencies(&mut manifest.dev_dependencies2, true)?;
+    for platform in manifest.target.iter_mut().flat_map(|t| t.values_mut()) {
+        normalize_dependencies(&mut platform.dependencies, false)?;
+        normalize_dependencies(&mut platform.build_dependencies, false)?;
+        normalize_dependencies(&mut platform.build_dependencies2, false)?;
+        normalize_dependencies(&mut platform.dev_dependencies, true)?;
+        normalize_dependencies(&mut platform.dev_dependencies2, true)?;
+    }
+    Ok(manifest)
+}
+
+fn append(
+    builder: &mut tar::Builder<impl std::io::Write>,
+    path: &Path,
+    data: &[u8],
+    mode: u32,
+) -> anyhow::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(mode);
+    // Fixed timestamp, so that the archive is reproducible
+    header.set_mtime(1153704088);
+    header.set_cksum();
+    Ok(builder.append_data(&mut header, path, data)?)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> anyhow::Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(path.metadata()?.permissions().mode() & 0o111 != 0)
+}
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> anyhow::Result<bool> {
+    Ok(false)
+}
+
+/// Build the .crate archive of a package from its edited manifest, copying a README located
+/// outside of the package. Returns the path to the archive in the target directory.
+pub(crate) fn package(
+    p: &cargo_metadata::Package,
+    manifest: &TomlManifest,
+    readme: Option<&Path>,
+    target_directory: &Path,
+) -> anyhow::Result<PathBuf> {
+    info!("Building package without cargo");
+    let dir = p.manifest_path.parent().unwrap().as_std_path();
+    let files = package_files(dir, manifest)?;
+    let prefix = PathBuf::from(format!("{}-{}", p.name, p.version));
+    let output = target_directory
+        .join("package")
+        .join(format!("{}.crate", prefix.display()));
+    std::fs::create_dir_all(output.parent().unwrap())?;
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        std::fs::File::create(&output)?,
+        flate2::Compression::default(),
+    ));
+    let normalized = format!(
+        "# Generated by cargo-depot from the original manifest, Cargo.toml.orig\n\n{}",
+        toml::to_string_pretty(&normalize(manifest)?)?
+    );
+    append(
+        &mut builder,
+        &prefix.join("Cargo.toml"),
+        normalized.as_bytes(),
+        0o644,
+    )?;
+    append(
+        &mut builder,
+        &prefix.join("Cargo.toml.orig"),
+        &std::fs::read(&p.manifest_path)?,
+        0o644,
+    )?;
+    if let Some(readme) = readme {
+        let file_name = Path::new(readme.file_name().unwrap());
+        anyhow::ensure!(
+            !files.iter().any(|f| f == file_name),
+            "The README {:?} is outside of the package, and {:?} already exists",
+            readme,
+            dir.join(file_name)
+        );
+        append(
+            &mut builder,
+            &prefix.join(file_name),
+            &std::fs::read(readme)?,
+            0o644,
+        )?;
+    }
+    for file in files {
+        let path = dir.join(&file);
+        let mode = if is_executable(&path)? { 0o755 } else { 0o644 };
+        append(
+            &mut builder,
+            &prefix.join(&file),
+            &std::fs::read(path)?,
+            mode,
+        )?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(output)
+}
+
+/// Set the package name in a normalized manifest, keeping its header comments
+fn rename_manifest(manifest: &[u8], name: &str) -> anyhow::Result<Vec<u8>> {
+    let manifest = std::str::from_utf8(manifest)?;
+    let header: String = manifest
+        .lines()
+        .take_while(|l| l.starts_with('#') || l.trim().is_empty())
+        .flat_map(|l| [l, "\n"])
+        .collect();
+    let mut table: toml::Table = toml::from_str(manifest)?;
+    let Some(package) = table.get_mut("package").and_then(|p| p.as_table_mut()) else {
+        anyhow::bail!("The manifest has no [package] section");
+    };
+    package.insert("name".into(), name.into());
+    Ok(format!("{}{}", header, toml::to_string_pretty(&table)?).into_bytes())
+}
+
+/// Rewrite the archive of a package under another name: its top-level directory, and the name in
+/// its normalized manifest, `Cargo.toml.orig` being kept as is. Returns the path to the new
+/// archive, next to the original.
+pub(crate) fn rename_archive(
+    archive: &Path,
+    p: &cargo_metadata::Package,
+    name: &str,
+) -> anyhow::Result<PathBuf> {
+    use std::io::Read;
+    info!("Renaming {} to {}", p.name, name);
+    let prefix = PathBuf::from(format!("{}-{}", p.name, p.version));
+    let renamed = PathBuf::from(format!("{}-{}", name, p.version));
+    let output = archive.with_file_name(format!("{}.crate", renamed.display()));
+    let mut input = tar::Archive::new(flate2::read::GzDecoder::new(std::fs::File::open(archive)?));
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        std::fs::File::create(&output)?,
+        flate2::Compression::default(),
+    ));
+    for entry in input.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        let Ok(file) = path.strip_prefix(&prefix) else {
+            anyhow::bail!("{:?} in {:?} is outside of {:?}", path, archive, prefix);
+        };
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        if file == Path::new("Cargo.toml") {
+            data = rename_manifest(&data, name)?;
+        }
+        append(
+            &mut builder,
+            &renamed.join(file),
+            &data,
+            entry.header().mode()?,
+        )?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(output)
+}
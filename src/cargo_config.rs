@@ -0,0 +1,108 @@
+//! Configuration for using the registry from cargo, as printed by `config-snippet` or merged
+//! into an existing `.cargo/config.toml`.
+use std::path::Path;
+
+use log::*;
+
+use crate::Registry;
+
+/// Registry declaration for `.cargo/config.toml`
+pub struct CargoConfig {
+    /// Name of the registry for cargo
+    pub name: String,
+    /// Index URL, `sparse+<url>/index/` or a git repository
+    pub index: String,
+    /// Replace crates.io with the registry (source replacement), e.g. for an offline mirror,
+    /// rather than declaring an alternative registry
+    pub replace_crates_io: bool,
+}
+
+/// Subtable of `parent`, created if needed. Implicit tables have no header of their own, e.g.
+/// `registries` in `[registries.depot]`.
+fn table<'a>(
+    parent: &'a mut dyn toml_edit::TableLike,
+    key: &str,
+    implicit: bool,
+) -> anyhow::Result<&'a mut dyn toml_edit::TableLike> {
+    let mut new = toml_edit::Table::new();
+    new.set_implicit(implicit);
+    parent
+        .entry(key)
+        .or_insert(toml_edit::Item::Table(new))
+        .as_table_like_mut()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a table", key))
+}
+
+impl CargoConfig {
+    /// Add the declaration to a configuration, keeping its other keys (e.g. tokens)
+    pub fn apply(&self, config: &mut toml_edit::DocumentMut) -> anyhow::Result<()> {
+        let (section, key) = if self.replace_crates_io {
+            table(
+                table(config.as_table_mut(), "source", true)?,
+                "crates-io",
+                false,
+            )?
+            .insert("replace-with", toml_edit::value(&self.name));
+            ("source", "registry")
+        } else {
+            ("registries", "index")
+        };
+        table(
+            table(config.as_table_mut(), section, true)?,
+            &self.name,
+            false,
+        )?
+        .insert(key, toml_edit::value(&self.index));
+        Ok(())
+    }
+    /// Add the declaration to a configuration file, creating it if needed
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let mut config: toml_edit::DocumentMut = match std::fs::read_to_string(path) {
+            Ok(contents) => contents.parse()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e.into()),
+        };
+        self.apply(&mut config)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, config.to_string())?;
+        info!("Added registry {} to {:?}", self.name, path);
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for CargoConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut config = toml_edit::DocumentMut::new();
+        self.apply(&mut config).map_err(|_| std::fmt::Error)?;
+        write!(f, "{}", config)
+    }
+}
+
+/// Sparse index URL of a registry served at `url`
+pub fn sparse_index(url: &str) -> String {
+    format!("sparse+{}/index/", url.trim_end_matches('/'))
+}
+
+impl Registry {
+    /// Index URL for cargo: the git remote the index is pushed to, unless `sparse` is set,
+    /// otherwise the sparse index served at `url` (by default, the URL of `config.json`)
+    pub fn cargo_index(&self, url: Option<&str>, sparse: bool) -> anyhow::Result<String> {
+        if !sparse {
+            if let Some(remote) = self.git_remote()? {
+                info!(
+                    "Using the git remote of the index, pass --sparse to use the sparse protocol"
+                );
+                return Ok(remote);
+            }
+        }
+        let config = self.config()?;
+        let Some(url) = url.or(config.base_url()) else {
+            anyhow::bail!(
+                "Cannot infer the URL of the registry from the download URL of config.json, provide it with --url"
+            );
+        };
+        Ok(sparse_index(url))
+    }
+}
@@ -0,0 +1,121 @@
+//! Append-only feed of the changes to the registry (publications, replacements, yanks and
+//! removals), stored in `changes.jsonl` at the root of the registry, so that mirrors, caches
+//! and indexers can sync incrementally instead of rescanning the index.
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use cargo_metadata::semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::Registry;
+
+pub const CHANGES: &str = "changes.jsonl";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Publish,
+    /// New archive and checksum for an existing version, see `--force`
+    Replace,
+    Yank,
+    Unyank,
+    Remove,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Change {
+    /// Increasing sequence number, starting at 1
+    pub seq: u64,
+    /// Unix timestamp
+    pub time: u64,
+    pub kind: ChangeKind,
+    pub name: String,
+    /// `None` when every version of the crate was removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<Version>,
+}
+
+/// Parse the feed, ignoring a last line that is still being written
+fn parse(contents: &str) -> impl Iterator<Item = anyhow::Result<Change>> + '_ {
+    let complete = contents.rfind('\n').map_or("", |i| &contents[..i]);
+    complete
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| Ok(serde_json::from_str(l)?))
+}
+
+impl Registry {
+    fn changes_path(&self) -> PathBuf {
+        self.0.join(CHANGES)
+    }
+    /// Changes with a sequence number greater than `since`
+    pub fn read_changes(&self, since: u64) -> anyhow::Result<Vec<Change>> {
+        let path = self.changes_path();
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        parse(&std::fs::read_to_string(path)?)
+            .filter(|c| c.as_ref().map_or(true, |c| c.seq > since))
+            .collect()
+    }
+    pub(crate) fn record_change(
+        &self,
+        kind: ChangeKind,
+        name: &str,
+        version: Option<&Version>,
+    ) -> anyhow::Result<()> {
+        let path = self.changes_path();
+        let last = match std::fs::read_to_string(&path) {
+            Ok(contents) => match parse(&contents).last() {
+                Some(change) => change?.seq,
+                None => 0,
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let change = Change {
+            seq: last + 1,
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs(),
+            kind,
+            name: name.into(),
+            version: version.cloned(),
+        };
+        // A single write, so that readers see either nothing or the whole line
+        let line = format!("{}\n", serde_json::to_string(&change)?);
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(line.as_bytes())?;
+        Ok(())
+    }
+    #[cfg(all(feature = "http", feature = "package"))]
+    /// Record the differences between two states of the index entries of a crate, e.g. after
+    /// pulling from another registry
+    pub(crate) fn record_index_changes(
+        &self,
+        name: &str,
+        before: &[crate::IndexMeta],
+        after: &[crate::IndexMeta],
+    ) -> anyhow::Result<()> {
+        for meta in after {
+            let kind = match before.iter().find(|m| m.vers == meta.vers) {
+                None => ChangeKind::Publish,
+                Some(old) if old.cksum != meta.cksum => ChangeKind::Replace,
+                Some(old) if old.yanked != meta.yanked && meta.yanked => ChangeKind::Yank,
+                Some(old) if old.yanked != meta.yanked => ChangeKind::Unyank,
+                Some(_) => continue,
+            };
+            self.record_change(kind, name, Some(&meta.vers))?;
+        }
+        for meta in before {
+            if !after.iter().any(|m| m.vers == meta.vers) {
+                self.record_change(ChangeKind::Remove, name, Some(&meta.vers))?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+//! Comparison of the contents of two published versions of a crate.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use cargo_metadata::semver::Version;
+
+use crate::{unpack_crate, Registry};
+
+/// Files in a directory, recursively, by path relative to the directory
+fn list_files(root: &Path) -> anyhow::Result<BTreeMap<PathBuf, PathBuf>> {
+    let mut files = BTreeMap::new();
+    let mut stack = vec![root.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                stack.push(path);
+            } else {
+                files.insert(path.strip_prefix(root)?.to_path_buf(), path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Print the files added (A), deleted (D) and modified (M) between two versions, and optionally
+/// a unified diff of the modified files.
+pub fn diff_crate(
+    registry: &Registry,
+    name: &str,
+    old: &Version,
+    new: &Version,
+    lines: bool,
+) -> anyhow::Result<()> {
+    let mut unpacked = vec![];
+    for version in [old, new] {
+        let path = registry.crate_path(name, version);
+        anyhow::ensure!(
+            path.exists(),
+            "{} {} not found in the registry",
+            name,
+            version
+        );
+        let dir = unpack_crate(&path)?;
+        let files = list_files(&dir.path().join(format!("{}-{}", name, version)))?;
+        unpacked.push((dir, files));
+    }
+    let (old_files, new_files) = (&unpacked[0].1, &unpacked[1].1);
+
+    let mut modified = vec![];
+    for (rel, path) in old_files {
+        match new_files.get(rel) {
+            None => println!("D {}", rel.display()),
+            Some(new_path) => {
+                let (a, b) = (std::fs::read(path)?, std::fs::read(new_path)?);
+                if a != b {
+                    println!("M {}", rel.display());
+                    modified.push((rel, a, b));
+                }
+            }
+        }
+    }
+    for rel in new_files.keys().filter(|rel| !old_files.contains_key(*rel)) {
+        println!("A {}", rel.display());
+    }
+
+    if lines {
+        for (rel, a, b) in modified {
+            println!();
+            let (Ok(a), Ok(b)) = (std::str::from_utf8(&a), std::str::from_utf8(&b)) else {
+                println!("Binary file {} differs", rel.display());
+                continue;
+            };
+            let rel = rel.display().to_string();
+            print!(
+                "{}",
+                similar::TextDiff::from_lines(a, b)
+                    .unified_diff()
+                    .header(&format!("{}/{}", old, rel), &format!("{}/{}", new, rel))
+            );
+        }
+    }
+    Ok(())
+}
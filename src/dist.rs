@@ -0,0 +1,78 @@
+//! Prebuilt binaries stored under `dist/<crate>/<version>/<target>/`, with a
+//! `dist/<crate>/manifest.json` listing them.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use cargo_metadata::semver::Version;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{sha256_file, Registry};
+
+pub(crate) const DIST: &str = "dist";
+
+#[derive(Serialize, Deserialize)]
+pub struct DistFile {
+    pub name: String,
+    pub sha256: String,
+}
+
+/// Files per target, per version
+#[derive(Default, Serialize, Deserialize)]
+pub struct DistManifest {
+    pub versions: BTreeMap<Version, BTreeMap<String, Vec<DistFile>>>,
+}
+
+impl Registry {
+    fn dist_manifest_path(&self, name: &str) -> PathBuf {
+        self.0.join(DIST).join(name).join("manifest.json")
+    }
+    pub fn read_dist_manifest(&self, name: &str) -> anyhow::Result<DistManifest> {
+        let path = self.dist_manifest_path(name);
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+    /// Store prebuilt binaries for a crate version and target
+    pub fn add_dist(
+        &self,
+        name: &str,
+        version: &Version,
+        target: &str,
+        files: &[PathBuf],
+    ) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        let dest = self
+            .0
+            .join(DIST)
+            .join(name)
+            .join(version.to_string())
+            .join(target);
+        std::fs::create_dir_all(&dest)?;
+        let mut manifest = self.read_dist_manifest(name)?;
+        let entries = manifest
+            .versions
+            .entry(version.clone())
+            .or_default()
+            .entry(target.into())
+            .or_default();
+        for file in files {
+            let Some(filename) = file.file_name() else {
+                anyhow::bail!("Invalid file {:?}", file);
+            };
+            let filename = filename.to_string_lossy().into_owned();
+            info!("Adding {} for {} {} ({})", filename, name, version, target);
+            std::fs::copy(file, dest.join(&filename))?;
+            entries.retain(|e| e.name != filename);
+            entries.push(DistFile {
+                sha256: sha256_file(file)?,
+                name: filename,
+            });
+        }
+        Ok(std::fs::write(
+            self.dist_manifest_path(name),
+            serde_json::to_string_pretty(&manifest)?,
+        )?)
+    }
+}
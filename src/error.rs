@@ -0,0 +1,40 @@
+//! Errors of the library API that callers may want to handle, e.g. a version that is already
+//! published. Other failures are carried as `anyhow` errors, with their context.
+use cargo_metadata::semver::Version;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{name} {version} is already in the registry")]
+    AlreadyPublished { name: String, version: Version },
+    #[error("Repository not clean: {}. These files would be embedded in the package. Stash them with `git stash -u` or add them to gitignore", files.join(" "))]
+    DirtyWorkspace {
+        /// Lines of `git status --porcelain`
+        files: Vec<String>,
+    },
+    #[error("{name} is not a library, add it with --keep-bins")]
+    NotALibrary { name: String },
+    #[error("{name} {version} cannot be published during the freeze: {reason}")]
+    Frozen {
+        name: String,
+        version: Version,
+        reason: String,
+    },
+    #[error("The upload exceeds the limit of {limit} bytes")]
+    UploadTooLarge { limit: u64 },
+    #[error("Failed to build package {name}")]
+    PackageFailed { name: String },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+/// Recover the typed errors raised with `anyhow` in the implementation
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => Self::Other(e),
+        }
+    }
+}
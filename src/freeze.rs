@@ -0,0 +1,449 @@
+//! Freeze windows, configured in `depot.toml`, during which publishing is rejected, e.g. for
+//! releases in the days before a product cut. Tokens listed in a freeze can still publish
+//! through the API, and the command line can override it with `--override-freeze`.
+use std::collections::BTreeSet;
+
+use cargo_metadata::semver::Version;
+use serde::{Deserialize, Serialize};
+
+/// Versions affected by a freeze
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    #[default]
+    All,
+    /// Releases, without a pre-release suffix
+    Stable,
+    Prerelease,
+}
+impl Channel {
+    pub fn contains(self, version: &Version) -> bool {
+        match self {
+            Self::All => true,
+            Self::Stable => version.pre.is_empty(),
+            Self::Prerelease => !version.pre.is_empty(),
+        }
+    }
+}
+
+/// Days since 1970-01-01 of a date of the proleptic Gregorian calendar, see
+/// http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Inverse of `days_from_civil`, as (year, month, day)
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// UTC time, written `2026-11-02` (midnight) or `2026-11-02T18:00:00Z`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Time(pub u64);
+impl TryFrom<String> for Time {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> anyhow::Result<Self> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "Expected a UTC time like 2026-11-02 or 2026-11-02T18:00:00Z, got {:?}",
+                value
+            )
+        };
+        let (date, time) = match value.split_once('T') {
+            Some((date, time)) => (date, Some(time.strip_suffix('Z').ok_or_else(invalid)?)),
+            None => (value.as_str(), None),
+        };
+        let parse = |s: &str, range: std::ops::RangeInclusive<i64>| {
+            s.parse::<i64>()
+                .ok()
+                .filter(|v| range.contains(v))
+                .ok_or_else(invalid)
+        };
+        let [year, month, day] = date.split('-').collect::<Vec<_>>()[..] else {
+            return Err(invalid());
+        };
+        let date = (
+            parse(year, 1970..=9999)?,
+            parse(month, 1..=12)?,
+            parse(day, 1..=31)?,
+        );
+        let days = days_from_civil(date.0, date.1, date.2);
+        // Days past the end of the month, e.g. 2026-02-31, would roll over into the next one
+        if civil_from_days(days) != date {
+            return Err(invalid());
+        }
+        let seconds = match time.map(|t| t.split(':').collect::<Vec<_>>()) {
+            None => 0,
+            Some(parts) => match parts[..] {
+                [hour, minute] => parse(hour, 0..=23)? * 3600 + parse(minute, 0..=59)? * 60,
+                [hour, minute, second] => {
+                    parse(hour, 0..=23)? * 3600
+                        + parse(minute, 0..=59)? * 60
+                        + parse(second, 0..=59)?
+                }
+                _ => return Err(invalid()),
+            },
+        };
+        Ok(Self((days * 86400 + seconds) as u64))
+    }
+}
+impl From<Time> for String {
+    fn from(time: Time) -> Self {
+        let seconds = time.0 as i64;
+        let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+        let seconds = seconds.rem_euclid(86400);
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year,
+            month,
+            day,
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60
+        )
+    }
+}
+
+/// Cron-like schedule, `<minute> <hour> <day of month> <month> <day of week>` in UTC, frozen
+/// during every minute it matches. Fields accept `*`, values, ranges, lists and steps, e.g.
+/// `* * 13-14 * *` or `* 18-23 * * 5`. Days of the week start at 0 for Sunday.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    expression: String,
+    fields: [BTreeSet<u32>; 5],
+    /// Whether the days of the month and of the week are restricted, in which case either
+    /// matches, as with cron
+    restricted_days: (bool, bool),
+}
+
+fn parse_cron_field(field: &str, min: u32, max: u32) -> anyhow::Result<BTreeSet<u32>> {
+    let mut values = BTreeSet::new();
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<u32>()?)),
+            None => (part, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (start.parse()?, end.parse()?),
+            None if step.is_some() => (range.parse()?, max),
+            None => (range.parse()?, range.parse()?),
+        };
+        anyhow::ensure!(
+            min <= start && start <= end && end <= max && step != Some(0),
+            "Invalid cron field {:?}, expected values from {} to {}",
+            part,
+            min,
+            max
+        );
+        values.extend((start..=end).step_by(step.unwrap_or(1) as usize));
+    }
+    Ok(values)
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = anyhow::Error;
+    fn try_from(expression: String) -> anyhow::Result<Self> {
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            anyhow::bail!(
+                "Expected 5 fields in the cron schedule {:?}: minute, hour, day of month, month, day of week",
+                expression
+            );
+        };
+        let mut weekdays = parse_cron_field(weekday, 0, 7)?;
+        // Sunday is both 0 and 7
+        if weekdays.remove(&7) {
+            weekdays.insert(0);
+        }
+        Ok(Self {
+            fields: [
+                parse_cron_field(minute, 0, 59)?,
+                parse_cron_field(hour, 0, 23)?,
+                parse_cron_field(day, 1, 31)?,
+                parse_cron_field(month, 1, 12)?,
+                weekdays,
+            ],
+            restricted_days: (day != "*", weekday != "*"),
+            expression,
+        })
+    }
+}
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> Self {
+        schedule.expression
+    }
+}
+
+impl Schedule {
+    /// Whether the minute containing `time` matches
+    pub fn matches(&self, time: Time) -> bool {
+        let seconds = time.0 as i64;
+        let days = seconds.div_euclid(86400);
+        let (_, month, day) = civil_from_days(days);
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4).rem_euclid(7);
+        let [minutes, hours, month_days, months, weekdays] = &self.fields;
+        let day_matches = match self.restricted_days {
+            (true, true) => {
+                month_days.contains(&(day as u32)) || weekdays.contains(&(weekday as u32))
+            }
+            _ => month_days.contains(&(day as u32)) && weekdays.contains(&(weekday as u32)),
+        };
+        minutes.contains(&(seconds.rem_euclid(3600) as u32 / 60))
+            && hours.contains(&(seconds.rem_euclid(86400) as u32 / 3600))
+            && months.contains(&(month as u32))
+            && day_matches
+    }
+}
+
+/// Window during which publishing is rejected: between `start` and `end`, when `schedule`
+/// matches, or both
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Freeze {
+    /// Shown when rejecting a version
+    pub reason: Option<String>,
+    pub start: Option<Time>,
+    /// Exclusive
+    pub end: Option<Time>,
+    pub schedule: Option<Schedule>,
+    #[serde(default)]
+    pub channel: Channel,
+    /// Globs matching the names of the frozen crates, every crate if empty
+    #[serde(default)]
+    pub crates: Vec<String>,
+    /// Names of the tokens still allowed to publish through the API
+    #[serde(default)]
+    pub override_tokens: Vec<String>,
+}
+
+#[cfg(feature = "package")]
+impl Freeze {
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.start.is_some() || self.end.is_some() || self.schedule.is_some(),
+            "A freeze needs a start, an end or a schedule"
+        );
+        for pattern in &self.crates {
+            glob::Pattern::new(pattern)?;
+        }
+        Ok(())
+    }
+    fn is_active(&self, now: Time) -> bool {
+        self.start.map_or(true, |start| start <= now)
+            && self.end.map_or(true, |end| now < end)
+            && self.schedule.as_ref().map_or(true, |s| s.matches(now))
+    }
+    /// Whether the freeze rejects a version at `now`
+    pub fn applies(&self, name: &str, version: &Version, now: Time) -> bool {
+        self.is_active(now)
+            && self.channel.contains(version)
+            && (self.crates.is_empty()
+                || self.crates.iter().any(|pattern| {
+                    glob::Pattern::new(pattern).map_or(false, |p| p.matches(&name.to_lowercase()))
+                }))
+    }
+    fn describe(&self) -> String {
+        if let Some(reason) = &self.reason {
+            return reason.clone();
+        }
+        let mut parts = vec![];
+        if let Some(start) = self.start {
+            parts.push(format!("from {}", String::from(start)));
+        }
+        if let Some(end) = self.end {
+            parts.push(format!("until {}", String::from(end)));
+        }
+        if let Some(schedule) = &self.schedule {
+            parts.push(format!("on schedule {:?}", schedule.expression));
+        }
+        parts.join(" ")
+    }
+}
+
+#[cfg(feature = "package")]
+impl crate::Registry {
+    /// Fail if a freeze of `depot.toml` currently rejects the version, unless `token` (the
+    /// name of the token publishing through the API) may override it
+    pub fn check_freeze(
+        &self,
+        name: &str,
+        version: &Version,
+        token: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let now = Time(
+            std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)?
+                .as_secs(),
+        );
+        for freeze in self.settings()?.freeze {
+            if !freeze.applies(name, version, now) {
+                continue;
+            }
+            if let Some(token) = token.filter(|t| freeze.override_tokens.iter().any(|o| o == t)) {
+                log::info!(
+                    "{} {} is frozen ({}), allowed for token {}",
+                    name,
+                    version,
+                    freeze.describe(),
+                    token
+                );
+                continue;
+            }
+            return Err(crate::Error::Frozen {
+                name: name.into(),
+                version: version.clone(),
+                reason: freeze.describe(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> Time {
+        Time::try_from(s.to_string()).unwrap()
+    }
+
+    #[test]
+    fn civil_round_trip() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        // 1900 and 2100 are not leap years, 2000 is
+        for days in (-30_000..150_000).step_by(7) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        for (year, month, day) in [(2000, 2, 29), (2024, 2, 29), (2024, 3, 1), (2100, 3, 1)] {
+            assert_eq!(
+                civil_from_days(days_from_civil(year, month, day)),
+                (year, month, day)
+            );
+        }
+        assert_eq!(
+            days_from_civil(2024, 3, 1) - days_from_civil(2024, 2, 28),
+            2
+        );
+        assert_eq!(
+            days_from_civil(2100, 3, 1) - days_from_civil(2100, 2, 28),
+            1
+        );
+    }
+
+    #[test]
+    fn time_round_trip() {
+        for s in [
+            "2026-11-02T18:30:05Z",
+            "2024-02-29T00:00:00Z",
+            "1970-01-01T00:00:00Z",
+        ] {
+            assert_eq!(String::from(time(s)), s);
+        }
+        assert_eq!(String::from(time("2026-11-02")), "2026-11-02T00:00:00Z");
+        assert_eq!(
+            String::from(time("2026-11-04T18:00Z")),
+            "2026-11-04T18:00:00Z"
+        );
+        assert_eq!(time("1970-01-02").0, 86400);
+    }
+
+    #[test]
+    fn invalid_times() {
+        for s in [
+            "2026-02-29",
+            "2100-02-29",
+            "2026-02-31",
+            "2026-04-31",
+            "2026-13-01",
+            "2026-00-10",
+            "1969-12-31",
+            "2026-11",
+            "2026-11-02T24:00Z",
+            "2026-11-02T18:60Z",
+            "2026-11-02T18:00",
+            "2026-11-02T18Z",
+            "tomorrow",
+        ] {
+            assert!(Time::try_from(s.to_string()).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn cron_fields() {
+        let field = |s: &str| {
+            parse_cron_field(s, 0, 59)
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(field("5"), [5]);
+        assert_eq!(field("1-4"), [1, 2, 3, 4]);
+        assert_eq!(field("*/15"), [0, 15, 30, 45]);
+        assert_eq!(field("10/20"), [10, 30, 50]);
+        assert_eq!(field("0-10/5"), [0, 5, 10]);
+        assert_eq!(field("1,3,7-8"), [1, 3, 7, 8]);
+        assert_eq!(field("*").len(), 60);
+        for s in ["60", "5-1", "*/0", "a", "", "1-"] {
+            assert!(parse_cron_field(s, 0, 59).is_err(), "{}", s);
+        }
+        assert!(parse_cron_field("0", 1, 31).is_err());
+        assert!(Schedule::try_from("* * * *".to_string()).is_err());
+    }
+
+    #[test]
+    fn schedule_matches() {
+        let schedule = |s: &str| Schedule::try_from(s.to_string()).unwrap();
+        let evenings = schedule("30 18-23 * * *");
+        assert!(evenings.matches(time("2026-11-15T18:30:00Z")));
+        assert!(evenings.matches(time("2026-11-15T23:30:59Z")));
+        assert!(!evenings.matches(time("2026-11-15T18:31:00Z")));
+        assert!(!evenings.matches(time("2026-11-15T17:30:00Z")));
+        // 2026-11-15 is a Sunday, which is both 0 and 7
+        assert!(schedule("* * * * 0").matches(time("2026-11-15")));
+        assert!(schedule("* * * * 7").matches(time("2026-11-15")));
+        assert!(!schedule("* * * * 1-6").matches(time("2026-11-15")));
+        assert!(schedule("* * * 11 *").matches(time("2026-11-15")));
+        assert!(!schedule("* * * 12 *").matches(time("2026-11-15")));
+    }
+
+    #[test]
+    fn restricted_days() {
+        let schedule = |s: &str| Schedule::try_from(s.to_string()).unwrap();
+        // 2026-11-01 and 2026-11-15 are Sundays, 2026-11-20 is a Friday
+        let (first, sunday, friday) = (time("2026-11-01"), time("2026-11-15"), time("2026-11-20"));
+        // Either the day of the month or the day of the week when both are restricted
+        let either = schedule("* * 1 * 5");
+        assert!(either.matches(first));
+        assert!(either.matches(friday));
+        assert!(!either.matches(sunday));
+        // Only the restricted one otherwise
+        let day_of_month = schedule("* * 1 * *");
+        assert!(day_of_month.matches(first));
+        assert!(!day_of_month.matches(friday));
+        let day_of_week = schedule("* * * * 5");
+        assert!(day_of_week.matches(friday));
+        assert!(!day_of_week.matches(first));
+    }
+}
@@ -0,0 +1,139 @@
+//! Generators deriving extra artifacts from the published crates, e.g. C headers extracted with
+//! cbindgen or wasm packages, for the other toolchains of the team. They are declared in
+//! `depot.toml` and enabled per crate in `[package.metadata.depot]`, and their outputs are
+//! stored under `crates/<crate>/<crate>-<version>.artifacts/<generator>/`.
+#[cfg(feature = "package")]
+use std::path::Path;
+use std::path::PathBuf;
+
+#[cfg(feature = "package")]
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::Registry;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Generator {
+    /// Program and arguments, run in the unpacked crate with `DEPOT_CRATE`, `DEPOT_VERSION` and
+    /// `DEPOT_OUTPUT` (the directory the artifacts are written to) set
+    pub command: Vec<String>,
+    /// Only warn when the command fails, instead of rejecting the version
+    #[serde(default)]
+    pub optional: bool,
+}
+
+#[cfg(feature = "package")]
+impl Generator {
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.command.is_empty(), "The command is empty");
+        Ok(())
+    }
+    fn run(&self, name: &str, p: &cargo_metadata::Package, dir: &Path, output: &Path) -> bool {
+        info!("Running generator {} on {} {}", name, p.name, p.version);
+        let status = std::process::Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .current_dir(dir)
+            .env("DEPOT_CRATE", &p.name)
+            .env("DEPOT_VERSION", p.version.to_string())
+            .env("DEPOT_OUTPUT", output)
+            .status();
+        match status {
+            Ok(status) if status.success() => true,
+            Ok(status) => {
+                warn!("Generator {} failed with {}", name, status);
+                false
+            }
+            Err(e) => {
+                warn!("Failed to run generator {}: {}", name, e);
+                false
+            }
+        }
+    }
+}
+
+/// Artifacts generated for a version, before it is added
+#[cfg(feature = "package")]
+pub(crate) struct Artifacts(tempfile::TempDir);
+
+impl Registry {
+    /// Directory of the generated artifacts of a version
+    pub fn artifacts_path(&self, name: &str, version: &cargo_metadata::semver::Version) -> PathBuf {
+        self.crate_path(name, version).with_extension("artifacts")
+    }
+}
+
+#[cfg(feature = "package")]
+impl Registry {
+    /// Run the generators enabled for a package on its archive. Fails if one is unknown, or if
+    /// a non-optional one fails.
+    pub(crate) fn generate_artifacts(
+        &self,
+        p: &cargo_metadata::Package,
+        crate_src: &Path,
+        generators: &[String],
+    ) -> anyhow::Result<Option<Artifacts>> {
+        if generators.is_empty() {
+            return Ok(None);
+        }
+        let settings = self.settings()?;
+        let unpacked = crate::unpack_crate(crate_src)?;
+        let dir = unpacked.path().join(format!("{}-{}", p.name, p.version));
+        let artifacts = crate::temp::tempdir()?;
+        for name in generators {
+            let Some(generator) = settings.generators.get(name) else {
+                anyhow::bail!(
+                    "{} enables the generator {}, which is not declared in depot.toml",
+                    p.name,
+                    name
+                );
+            };
+            let output = artifacts.path().join(name);
+            std::fs::create_dir(&output)?;
+            if !generator.run(name, p, &dir, &output) {
+                anyhow::ensure!(
+                    generator.optional,
+                    "Generator {} failed on {} {}",
+                    name,
+                    p.name,
+                    p.version
+                );
+                std::fs::remove_dir_all(output)?;
+            }
+        }
+        Ok(Some(Artifacts(artifacts)))
+    }
+    /// Store the artifacts of a version, replacing the previous ones
+    pub(crate) fn store_artifacts(
+        &self,
+        name: &str,
+        version: &cargo_metadata::semver::Version,
+        artifacts: Option<Artifacts>,
+    ) -> anyhow::Result<()> {
+        let dest = self.artifacts_path(name, version);
+        if dest.exists() {
+            std::fs::remove_dir_all(&dest)?;
+        }
+        let Some(artifacts) = artifacts else {
+            return Ok(());
+        };
+        copy_dir(artifacts.0.path(), &dest)?;
+        info!("Stored the artifacts of {} {} in {:?}", name, version, dest);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "package")]
+fn copy_dir(src: &Path, dest: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dest)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let path = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &path)?;
+        } else {
+            std::fs::copy(entry.path(), path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,85 @@
+//! History of the index in a git repository, enabled with `init --git` or by running `git init`
+//! in the index directory. Each change is committed, and pushed when the repository has a
+//! remote, which also allows using the index with cargo's git protocol.
+use std::path::PathBuf;
+
+use log::*;
+
+use crate::{Registry, INDEX};
+
+impl Registry {
+    fn index_repository(&self) -> PathBuf {
+        self.0.join(INDEX)
+    }
+    pub(crate) fn is_git_backed(&self) -> bool {
+        self.index_repository().join(".git").exists()
+    }
+    fn git(&self, args: &[&str]) -> anyhow::Result<String> {
+        let out = std::process::Command::new("git")
+            .args(args)
+            .current_dir(self.index_repository())
+            .output()?;
+        anyhow::ensure!(
+            out.status.success(),
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+        Ok(String::from_utf8(out.stdout)?)
+    }
+    /// Create the repository with the current index, pushing to `remote` after each change
+    pub fn init_git(&self, remote: Option<&str>) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        anyhow::ensure!(
+            !self.is_git_backed(),
+            "{:?} is already a git repository",
+            self.index_repository()
+        );
+        self.git(&["init", "--quiet"])?;
+        if let Some(remote) = remote {
+            self.git(&["remote", "add", "origin", remote])?;
+        }
+        self.commit_index("Initialize the registry")
+    }
+    /// URL of the remote the index is pushed to, if any
+    pub(crate) fn git_remote(&self) -> anyhow::Result<Option<String>> {
+        if !self.is_git_backed() {
+            return Ok(None);
+        }
+        let Some(remote) = self.git(&["remote"])?.lines().next().map(String::from) else {
+            return Ok(None);
+        };
+        Ok(Some(
+            self.git(&["remote", "get-url", &remote])?.trim().into(),
+        ))
+    }
+    /// Commit the changes to the index, if it is a git repository
+    pub(crate) fn commit_index(&self, message: &str) -> anyhow::Result<()> {
+        if !self.is_git_backed() {
+            return Ok(());
+        }
+        self.git(&["add", "--all"])?;
+        if self.git(&["status", "--porcelain"])?.trim().is_empty() {
+            debug!("No index changes to commit");
+            return Ok(());
+        }
+        // Fall back to a default identity, e.g. on servers
+        let mut args = vec![];
+        if self.git(&["config", "user.email"]).is_err() {
+            args.extend([
+                "-c",
+                "user.name=cargo-depot",
+                "-c",
+                "user.email=cargo-depot@localhost",
+            ]);
+        }
+        args.extend(["commit", "--quiet", "--message", message]);
+        self.git(&args)?;
+        info!("Committed index changes: {}", message);
+        if let Some(remote) = self.git(&["remote"])?.lines().next() {
+            info!("Pushing the index to {}", remote);
+            self.git(&["push", "--quiet", remote, "HEAD"])?;
+        }
+        Ok(())
+    }
+}
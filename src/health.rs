@@ -0,0 +1,99 @@
+//! Summary of the state of the registry, for monitoring.
+use std::collections::BTreeSet;
+use std::time::SystemTime;
+
+use cargo_metadata::semver::Version;
+use serde::Serialize;
+
+use crate::{sha256_file, Registry, CRATES};
+
+#[derive(Serialize)]
+pub struct Health {
+    pub healthy: bool,
+    pub crates: usize,
+    pub versions: usize,
+    /// Index entries failing validation
+    pub invalid_entries: usize,
+    /// Index files whose hash does not match manifest.json
+    pub manifest_mismatches: usize,
+    /// Archives without an index entry
+    pub orphan_archives: usize,
+    /// Index entries without an archive
+    pub missing_archives: usize,
+    /// Modification time of the most recent archive, as a Unix timestamp
+    pub last_publish: Option<u64>,
+    /// Space available on the filesystem of the registry
+    pub free_space_bytes: u64,
+}
+
+type Versions = BTreeSet<(String, Version)>;
+
+impl Registry {
+    /// Versions of the archives in `crates/`, with the modification time of the most recent one
+    fn archives(&self) -> anyhow::Result<(Versions, Option<SystemTime>)> {
+        let mut archives = BTreeSet::new();
+        let mut last = None;
+        let crates = self.0.join(CRATES);
+        if !crates.exists() {
+            return Ok((archives, last));
+        }
+        for dir in std::fs::read_dir(crates)? {
+            let dir = dir?;
+            let name = dir.file_name().to_string_lossy().into_owned();
+            for file in std::fs::read_dir(dir.path())? {
+                let file = file?;
+                let file_name = file.file_name().to_string_lossy().into_owned();
+                let Some(version) = file_name
+                    .strip_suffix(".crate")
+                    .and_then(|f| f.strip_prefix(&format!("{}-", name)))
+                    .and_then(|v| v.parse().ok())
+                else {
+                    continue;
+                };
+                let modified = file.metadata()?.modified()?;
+                last = last.max(Some(modified));
+                archives.insert((name.clone(), version));
+            }
+        }
+        Ok((archives, last))
+    }
+    pub fn health(&self) -> anyhow::Result<Health> {
+        let names = self.package_names()?;
+        let manifest = self.read_manifest()?;
+        let mut indexed = BTreeSet::new();
+        let mut manifest_mismatches = 0;
+        for name in &names {
+            for meta in self.read_package(name)? {
+                indexed.insert((meta.name, meta.vers));
+            }
+            if manifest.index.get(name) != Some(&sha256_file(&self.package_index(name))?) {
+                manifest_mismatches += 1;
+            }
+        }
+        manifest_mismatches += manifest
+            .index
+            .keys()
+            .filter(|name| !names.contains(name))
+            .count();
+        let (archives, last_publish) = self.archives()?;
+        let invalid_entries = self.index_errors()?.len();
+        let orphan_archives = archives.difference(&indexed).count();
+        let missing_archives = indexed.difference(&archives).count();
+        Ok(Health {
+            healthy: invalid_entries == 0
+                && manifest_mismatches == 0
+                && orphan_archives == 0
+                && missing_archives == 0,
+            crates: names.len(),
+            versions: indexed.len(),
+            invalid_entries,
+            manifest_mismatches,
+            orphan_archives,
+            missing_archives,
+            last_publish: last_publish
+                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+            free_space_bytes: fs2::available_space(&self.0)?,
+        })
+    }
+}
@@ -0,0 +1,46 @@
+//! Outbound HTTP requests, sent with the same user agent and extra headers.
+use std::sync::OnceLock;
+
+pub const DEFAULT_USER_AGENT: &str = concat!("cargo-depot/", env!("CARGO_PKG_VERSION"));
+
+struct Client {
+    agent: ureq::Agent,
+    headers: Vec<(String, String)>,
+}
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Set the user agent and the headers added to every request
+pub fn init(user_agent: &str, headers: Vec<(String, String)>) {
+    let agent = ureq::AgentBuilder::new().user_agent(user_agent).build();
+    if CLIENT.set(Client { agent, headers }).is_err() {
+        log::warn!("HTTP client already initialized");
+    }
+}
+
+/// Parse a `Name: value` header
+pub fn parse_header(header: &str) -> anyhow::Result<(String, String)> {
+    let Some((name, value)) = header.split_once(':') else {
+        anyhow::bail!("Expected <name>: <value>, got {:?}", header);
+    };
+    Ok((name.trim().into(), value.trim().into()))
+}
+
+pub fn request(method: &str, url: &str) -> ureq::Request {
+    let client = CLIENT.get_or_init(|| Client {
+        agent: ureq::AgentBuilder::new()
+            .user_agent(DEFAULT_USER_AGENT)
+            .build(),
+        headers: vec![],
+    });
+    client
+        .headers
+        .iter()
+        .fold(client.agent.request(method, url), |r, (name, value)| {
+            r.set(name, value)
+        })
+}
+
+pub fn get(url: &str) -> ureq::Request {
+    request("GET", url)
+}
@@ -0,0 +1,78 @@
+//! Summary of a published version, combining the index entry with the sidecar metadata.
+use cargo_metadata::semver::Version;
+use itertools::Itertools;
+
+use crate::Registry;
+
+/// Print the details of a version of a crate, by default the latest non-yanked one.
+pub fn info(registry: &Registry, name: &str, version: Option<&Version>) -> anyhow::Result<()> {
+    let versions = registry.read_package(name)?;
+    anyhow::ensure!(!versions.is_empty(), "{} not found in the registry", name);
+    let meta = match version {
+        Some(version) => versions.iter().find(|m| &m.vers == version),
+        None => versions
+            .iter()
+            .filter(|m| !m.yanked)
+            .max_by(|a, b| a.vers.cmp(&b.vers)),
+    };
+    let Some(meta) = meta else {
+        anyhow::bail!("No matching version of {} in the registry", name);
+    };
+    let info = registry.read_info(name, &meta.vers)?;
+    println!("{} {}", meta.name, meta.vers);
+    if let Some(description) = &info.description {
+        println!("{}", description.trim());
+    }
+    println!();
+    for (key, value) in [
+        ("license", meta.license.as_deref()),
+        ("rust-version", meta.rust_version.as_deref()),
+        ("documentation", info.documentation.as_deref()),
+        ("homepage", info.homepage.as_deref()),
+        ("repository", info.repository.as_deref()),
+    ] {
+        println!("{:<14} {}", format!("{}:", key), value.unwrap_or("-"));
+    }
+    println!("{:<14} {}", "checksum:", meta.cksum);
+    if let Some(env) = &info.environment {
+        println!(
+            "{:<14} cargo-depot {}, {}, {}",
+            "packaged with:",
+            env.depot_version,
+            env.cargo_version.as_deref().unwrap_or("without cargo"),
+            env.platform
+        );
+    }
+    if let Some(upstream) = &info.upstream {
+        println!(
+            "{:<14} {} ({} for this version), metadata from {}",
+            "downloads:",
+            upstream.downloads.map_or("-".into(), |d| d.to_string()),
+            upstream
+                .version_downloads
+                .map_or("-".into(), |d| d.to_string()),
+            upstream.api
+        );
+    }
+    println!("{:<14} {}", "yanked:", meta.yanked);
+    println!(
+        "{:<14} {}",
+        "pinned:",
+        registry.is_pinned(name, &meta.vers)?
+    );
+    println!(
+        "{:<14} {}",
+        "versions:",
+        versions.iter().map(|m| &m.vers).join(", ")
+    );
+    println!(
+        "{:<14} {}",
+        "aliases:",
+        registry
+            .read_aliases(name)?
+            .iter()
+            .map(|(alias, version)| format!("{} ({})", alias, version))
+            .join(", ")
+    );
+    Ok(())
+}
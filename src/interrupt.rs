@@ -0,0 +1,92 @@
+//! Rollback of in-progress operations when the process is interrupted (Ctrl-C, SIGTERM), or
+//! when they fail.
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use log::*;
+
+#[derive(Clone)]
+pub(crate) enum Undo {
+    /// Move a file back to its original location
+    Rename { from: PathBuf, to: PathBuf },
+    /// Remove a partially written file
+    Remove(PathBuf),
+}
+
+impl Undo {
+    fn run(&self) -> std::io::Result<()> {
+        match self {
+            Undo::Rename { from, to } if from.exists() => std::fs::rename(from, to),
+            Undo::Remove(path) if path.exists() => std::fs::remove_file(path),
+            _ => Ok(()),
+        }
+    }
+}
+
+static PENDING: Mutex<(usize, Vec<(usize, Undo)>)> = Mutex::new((0, vec![]));
+
+/// Registration of an [`Undo`] action, which is run if the process is interrupted while this
+/// guard is alive.
+pub(crate) struct Guard(usize);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        PENDING.lock().unwrap().1.retain(|(id, _)| *id != self.0);
+    }
+}
+
+pub(crate) fn on_interrupt(undo: Undo) -> Guard {
+    let mut pending = PENDING.lock().unwrap();
+    pending.0 += 1;
+    let id = pending.0;
+    pending.1.push((id, undo));
+    Guard(id)
+}
+
+/// An [`Undo`] action run when dropped, i.e. also after errors and panics, and if the process is
+/// interrupted while it is alive.
+pub(crate) struct Restore {
+    undo: Option<Undo>,
+    _guard: Guard,
+}
+
+impl Restore {
+    /// Run the action now, reporting its failure
+    pub(crate) fn restore(mut self) -> std::io::Result<()> {
+        self.undo.take().map_or(Ok(()), |undo| undo.run())
+    }
+}
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Some(undo) = self.undo.take() {
+            if let Err(e) = undo.run() {
+                error!("Rollback failed: {}", e);
+            }
+        }
+    }
+}
+
+pub(crate) fn restore_on_drop(undo: Undo) -> Restore {
+    Restore {
+        _guard: on_interrupt(undo.clone()),
+        undo: Some(undo),
+    }
+}
+
+/// Install a handler rolling back the pending operations, most recent first, before exiting.
+pub fn install() -> anyhow::Result<()> {
+    ctrlc::set_handler(|| {
+        warn!("Interrupted, rolling back");
+        // Keep the lock, so that no new operation starts
+        let pending = PENDING.lock().unwrap();
+        for (_, undo) in pending.1.iter().rev() {
+            if let Err(e) = undo.run() {
+                error!("Rollback failed: {}", e);
+            }
+        }
+        crate::temp::finish(false);
+        std::process::exit(130);
+    })?;
+    Ok(())
+}
@@ -0,0 +1,104 @@
+//! Extraction of the licence, notice and README files of every archived version, for
+//! compliance archives. The output has one `<crate>/<version>/` directory per version, with the
+//! files and a `legal.json` summary, and versions already extracted are skipped.
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use log::*;
+use serde::Serialize;
+
+use crate::{IndexMeta, Registry};
+
+const PREFIXES: [&str; 5] = ["license", "licence", "copying", "notice", "readme"];
+
+#[derive(Serialize)]
+struct Summary<'a> {
+    name: &'a str,
+    version: String,
+    license: Option<&'a str>,
+    checksum: &'a str,
+    files: Vec<String>,
+}
+
+/// Whether a path of the archive (without the `<crate>-<version>/` prefix) is extracted
+fn is_legal(path: &Path, license_file: Option<&Path>) -> bool {
+    if Some(path) == license_file {
+        return true;
+    }
+    let top_level = path.parent() == Some(Path::new(""));
+    let name = path.to_string_lossy().to_lowercase();
+    top_level && PREFIXES.iter().any(|p| name.starts_with(p))
+}
+
+/// Extract the files of a version into `dest`, returning their paths
+fn extract(archive: &Path, meta: &IndexMeta, dest: &Path) -> anyhow::Result<Vec<String>> {
+    let root = PathBuf::from(format!("{}-{}", meta.name, meta.vers));
+    let license_file = meta.license_file.as_ref().map(|f| f.as_std_path());
+    let tar = flate2::read::GzDecoder::new(std::fs::File::open(archive)?);
+    let mut files = vec![];
+    for entry in tar::Archive::new(tar).entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let Ok(path) = path.strip_prefix(&root) else {
+            continue;
+        };
+        if !entry.header().entry_type().is_file() || !is_legal(path, license_file) {
+            continue;
+        }
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        let output = dest.join(path);
+        std::fs::create_dir_all(output.parent().unwrap())?;
+        std::fs::write(output, data)?;
+        files.push(path.to_string_lossy().into_owned());
+    }
+    Ok(files)
+}
+
+/// Extract the legal files of the versions not yet in `outdir`
+pub fn extract_legal(registry: &Registry, outdir: &Path) -> anyhow::Result<()> {
+    let (mut extracted, mut skipped) = (0, 0);
+    for name in registry.package_names()? {
+        for meta in registry.read_package(&name)? {
+            let dest = outdir.join(&meta.name).join(meta.vers.to_string());
+            if dest.exists() {
+                skipped += 1;
+                continue;
+            }
+            let archive = registry.crate_path(&meta.name, &meta.vers);
+            if !archive.exists() {
+                warn!("Missing archive for {} {}", meta.name, meta.vers);
+                continue;
+            }
+            // Extract into a temporary directory first, so that interrupted extractions are
+            // retried on the next run
+            let tmp = outdir.join(&meta.name).join(format!(".{}.tmp", meta.vers));
+            if tmp.exists() {
+                std::fs::remove_dir_all(&tmp)?;
+            }
+            std::fs::create_dir_all(&tmp)?;
+            let files = extract(&archive, &meta, &tmp)?;
+            if files.is_empty() {
+                warn!("No licence or README files in {} {}", meta.name, meta.vers);
+            }
+            let summary = Summary {
+                name: &meta.name,
+                version: meta.vers.to_string(),
+                license: meta.license.as_deref(),
+                checksum: &meta.cksum,
+                files,
+            };
+            std::fs::write(
+                tmp.join("legal.json"),
+                serde_json::to_string_pretty(&summary)?,
+            )?;
+            std::fs::rename(tmp, dest)?;
+            extracted += 1;
+        }
+    }
+    info!(
+        "Extracted {} versions, {} already extracted",
+        extracted, skipped
+    );
+    Ok(())
+}
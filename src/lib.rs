@@ -1,14 +1,76 @@
 use std::collections::{BTreeMap, HashSet};
-use std::io::Write;
 use std::path::{Path, PathBuf};
 
+use anyhow::Context;
+#[cfg(feature = "package")]
 use itertools::Itertools;
 use log::*;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
 
-const INDEX: &str = "index";
-const CRATES: &str = "crates";
+pub use error::Error;
+
+pub mod add_dep;
+pub mod aliases;
+#[cfg(feature = "package")]
+pub mod api;
+#[cfg(feature = "s3")]
+pub mod backend;
+#[cfg(feature = "package")]
+pub mod builtin;
+pub mod cargo_config;
+pub mod changes;
+#[cfg(feature = "package")]
+pub mod diff;
+pub mod dist;
+pub mod error;
+pub mod freeze;
+pub mod generators;
+mod git;
+pub mod health;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod info;
+#[cfg(feature = "package")]
+pub mod interrupt;
+#[cfg(feature = "package")]
+pub mod legal;
+pub mod list;
+pub mod lock;
+pub mod migrate;
+#[cfg(all(feature = "http", feature = "package"))]
+pub mod mirror;
+pub mod pins;
+#[cfg(feature = "policy")]
+mod policy;
+#[cfg(feature = "package")]
+mod preflight;
+#[cfg(feature = "package")]
+pub mod publish;
+#[cfg(all(feature = "http", feature = "package"))]
+pub mod pull;
+#[cfg(feature = "package")]
+pub mod quotas;
+pub mod remove;
+pub mod report;
+#[cfg(feature = "serve")]
+pub mod resolve;
+pub mod restrictions;
+pub mod sbom;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod settings;
+pub mod signing;
+pub mod temp;
+pub mod tokens;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(feature = "serve")]
+mod verify;
+
+pub(crate) const INDEX: &str = "index";
+pub(crate) const CRATES: &str = "crates";
+pub(crate) const MANIFEST: &str = "manifest.json";
 
 #[derive(clap::Parser)]
 pub struct FeaturesFlags {
@@ -18,6 +80,7 @@ pub struct FeaturesFlags {
     features: Option<String>,
 }
 impl FeaturesFlags {
+    #[cfg(feature = "package")]
     fn flags(&self) -> String {
         if self.all_features {
             "--all-features".into()
@@ -27,10 +90,188 @@ impl FeaturesFlags {
     }
 }
 
+/// Options forwarded to cargo invocations, e.g. for vendored or offline workspaces
+#[derive(clap::Parser)]
+pub struct CargoFlags {
+    /// Run cargo without accessing the network
+    #[clap(long)]
+    offline: bool,
+    /// Require Cargo.lock and the cache to be up to date
+    #[clap(long)]
+    frozen: bool,
+    /// Require Cargo.lock to be up to date
+    #[clap(long)]
+    locked: bool,
+    /// Override a cargo configuration value, forwarded as `cargo --config`
+    #[clap(long = "config", value_name = "KEY=VALUE|PATH")]
+    config: Vec<String>,
+}
+impl CargoFlags {
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if self.offline {
+            args.push("--offline".into());
+        }
+        if self.frozen {
+            args.push("--frozen".into());
+        }
+        if self.locked {
+            args.push("--locked".into());
+        }
+        for c in &self.config {
+            args.push(format!("--config={}", c));
+        }
+        args
+    }
+}
+
+/// Format of the results printed on stdout, the logs being printed on stderr
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable table
+    #[default]
+    Text,
+    /// One JSON object per line and per package
+    Json,
+}
+
+#[derive(clap::Parser)]
+pub struct PackageFlags {
+    #[clap(flatten)]
+    pub cargo: CargoFlags,
+    #[clap(flatten)]
+    features: FeaturesFlags,
+    /// Verify that packages build with their declared `rust-version` before adding them
+    #[clap(long)]
+    verify_msrv: bool,
+    /// Keep the binary targets, e.g. to distribute tools installable with `cargo install`.
+    /// Packages without a library are then added too.
+    #[clap(long)]
+    keep_bins: bool,
+    /// Keep the examples
+    #[clap(long)]
+    keep_examples: bool,
+    /// Build the packaged crate before adding it, resolving the dependencies that point to this
+    /// registry from it, rather than from crates.io as `cargo package` would
+    #[cfg(feature = "serve")]
+    #[clap(long)]
+    verify: bool,
+    /// Rhai script deciding whether packages are allowed to be published
+    #[cfg(feature = "policy")]
+    #[clap(long)]
+    policy: Option<PathBuf>,
+    /// Run `cargo check` on the packaged crate with this feature set before adding it:
+    /// `default`, `no-default-features`, `all-features`, or a comma-separated list of features.
+    /// Can be repeated.
+    #[clap(long, value_name = "FEATURES")]
+    check_features: Vec<String>,
+    /// Run `cargo check` on the packaged crate for this target before adding it. Can be repeated.
+    #[clap(long, value_name = "TRIPLE")]
+    check_target: Vec<String>,
+    /// Fail if the version of a package does not match this requirement, e.g. `1.4.*`
+    #[clap(long, value_name = "REQ")]
+    expect_version: Option<cargo_metadata::semver::VersionReq>,
+    /// Read the manifests and build the archives without invoking cargo. Workspace inheritance,
+    /// named registries and `.gitignore` files are not supported.
+    #[clap(long)]
+    pub no_cargo: bool,
+    /// Replace versions already in the registry, with a new archive and checksum. Lockfiles
+    /// referencing the old checksum will fail to build.
+    #[clap(long)]
+    force: bool,
+    /// Package and validate the crates, and print the index entries that would be added,
+    /// without modifying the registry
+    #[clap(long)]
+    pub dry_run: bool,
+    /// Publish even during a freeze window of `depot.toml`
+    #[clap(long)]
+    override_freeze: bool,
+    /// Format of the results of each package on stdout
+    #[clap(long, value_enum, default_value_t)]
+    pub output: OutputFormat,
+}
+
+impl PackageFlags {
+    /// Fill in the defaults of `depot.toml`
+    pub fn apply_defaults(&mut self, defaults: &settings::PackageDefaults) {
+        self.cargo.offline |= defaults.offline;
+        self.cargo.frozen |= defaults.frozen;
+        self.cargo.locked |= defaults.locked;
+        if self.cargo.config.is_empty() {
+            self.cargo.config.clone_from(&defaults.config);
+        }
+        if !self.features.all_features && self.features.features.is_none() {
+            self.features.all_features = defaults.all_features;
+            self.features.features.clone_from(&defaults.features);
+        }
+        self.verify_msrv |= defaults.verify_msrv;
+        self.keep_bins |= defaults.keep_bins;
+        self.keep_examples |= defaults.keep_examples;
+        #[cfg(feature = "serve")]
+        {
+            self.verify |= defaults.verify;
+        }
+        #[cfg(feature = "policy")]
+        if self.policy.is_none() {
+            self.policy.clone_from(&defaults.policy);
+        }
+        if self.check_features.is_empty() {
+            self.check_features.clone_from(&defaults.check_features);
+        }
+        if self.check_target.is_empty() {
+            self.check_target.clone_from(&defaults.check_target);
+        }
+        self.no_cargo |= defaults.no_cargo;
+    }
+    /// Fail if a freeze window rejects the version, unless overridden with `--override-freeze`
+    #[cfg(feature = "package")]
+    fn check_freeze(
+        &self,
+        registry: &Registry,
+        name: &str,
+        version: &cargo_metadata::semver::Version,
+    ) -> anyhow::Result<()> {
+        match registry.check_freeze(name, version, None) {
+            Err(e) if self.override_freeze => {
+                warn!("{}, overriding it", e);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+    /// Check the version of a package against `--expect-version`
+    pub fn check_expected_version(&self, p: &cargo_metadata::Package) -> anyhow::Result<()> {
+        if let Some(req) = &self.expect_version {
+            anyhow::ensure!(
+                req.matches(&p.version),
+                "{} has version {}, which does not match --expect-version {}",
+                p.name,
+                p.version,
+                req
+            );
+        }
+        Ok(())
+    }
+}
+
 /// config.json at the root of the index
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct IndexConfig {
     dl: String,
+    /// Base URL of the web API, also advertised by `serve --allow-publish`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api: Option<String>,
+    /// Whether cargo must authenticate for all requests, not only publishing and yanking
+    #[serde(
+        rename = "auth-required",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub auth_required: bool,
+    /// Version of the registry layout, 0 for registries created before it was recorded. Ignored
+    /// by cargo.
+    #[serde(rename = "depot-format", default)]
+    pub format: u32,
 }
 impl IndexConfig {
     pub fn from_url(url: &str) -> Self {
@@ -40,14 +281,44 @@ impl IndexConfig {
                 url.trim_end_matches('/'),
                 CRATES,
             ),
+            api: None,
+            auth_required: false,
+            format: migrate::FORMAT_VERSION,
+        }
+    }
+    /// URL the registry was initialized with, when the download URL has the default layout
+    pub fn base_url(&self) -> Option<&str> {
+        self.dl
+            .strip_suffix("/{crate}/{crate}-{version}.crate")?
+            .strip_suffix(CRATES)?
+            .strip_suffix('/')
+    }
+    // See https://doc.rust-lang.org/cargo/reference/registry-index.html#index-configuration
+    pub fn download_url(&self, name: &str, version: &str, checksum: &str) -> String {
+        let markers = [
+            "{crate}",
+            "{version}",
+            "{prefix}",
+            "{lowerprefix}",
+            "{sha256-checksum}",
+        ];
+        if !markers.iter().any(|m| self.dl.contains(m)) {
+            return format!("{}/{}/{}/download", self.dl, name, version);
         }
+        let prefix = pkg_path(name).to_string_lossy().into_owned();
+        self.dl
+            .replace("{crate}", name)
+            .replace("{version}", version)
+            .replace("{prefix}", &prefix)
+            .replace("{lowerprefix}", &prefix.to_lowercase())
+            .replace("{sha256-checksum}", checksum)
     }
     pub fn write(&self, index: &Path) -> anyhow::Result<()> {
         std::fs::create_dir_all(index)?;
-        Ok(std::fs::write(
-            index.join("config.json"),
+        write_atomic(
+            &index.join("config.json"),
             serde_json::to_string_pretty(&self)?,
-        )?)
+        )
     }
 }
 
@@ -91,6 +362,119 @@ impl From<cargo_metadata::Dependency> for Dependency {
     }
 }
 
+#[cfg(feature = "package")]
+/// What adding a version did, see `Registry::add_packages`
+#[derive(Debug, Serialize)]
+#[serde(tag = "outcome", rename_all = "kebab-case")]
+pub enum PublishOutcome {
+    Added {
+        checksum: String,
+        path: PathBuf,
+    },
+    /// Replaced a version already in the registry, with `--force`
+    Replaced {
+        checksum: String,
+        path: PathBuf,
+    },
+    /// Validated without modifying the registry, with `--dry-run`
+    DryRun {
+        checksum: String,
+    },
+    /// Already in the registry
+    SkippedExisting,
+    /// Not a library, and binaries are not kept
+    SkippedNonLibrary,
+}
+
+#[cfg(feature = "package")]
+#[derive(Debug, Serialize)]
+pub struct PackageOutcome {
+    pub name: String,
+    pub version: cargo_metadata::semver::Version,
+    #[serde(flatten)]
+    pub outcome: PublishOutcome,
+    /// Time spent packaging, validating and adding the version
+    #[serde(rename = "duration_secs", serialize_with = "serialize_secs")]
+    pub duration: std::time::Duration,
+}
+#[cfg(feature = "package")]
+impl PackageOutcome {
+    fn new(
+        p: &cargo_metadata::Package,
+        outcome: PublishOutcome,
+        duration: std::time::Duration,
+    ) -> Self {
+        Self {
+            name: p.name.clone(),
+            version: p.version.clone(),
+            outcome,
+            duration,
+        }
+    }
+}
+#[cfg(feature = "package")]
+fn serialize_secs<S: serde::Serializer>(
+    duration: &std::time::Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+#[cfg(feature = "package")]
+/// Describe the index entry that `--dry-run` would have added
+fn print_dry_run(
+    p: &cargo_metadata::Package,
+    metadata: &IndexMeta,
+    crate_src: &Path,
+    replace: bool,
+    settings: &settings::Settings,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    use std::io::Write;
+    // Keep stdout for the results
+    let mut out: Box<dyn Write> = match output {
+        OutputFormat::Text => Box::new(std::io::stdout()),
+        OutputFormat::Json => Box::new(std::io::stderr()),
+    };
+    writeln!(
+        out,
+        "Would {} {} {} ({})",
+        if replace { "replace" } else { "add" },
+        metadata.name,
+        metadata.vers,
+        metadata.cksum
+    )?;
+    writeln!(out, "  {} files", crate_files(crate_src)?.len())?;
+    for dep in &p.dependencies {
+        let from = match (&dep.path, &dep.source) {
+            (Some(path), _) => format!("path {}", path),
+            (None, Some(source)) => source.clone(),
+            (None, None) => continue,
+        };
+        match settings.dependency_registry(dep) {
+            None => writeln!(out, "  {}: {} -> this registry", dep.name, from)?,
+            Some(to) if to != from => writeln!(out, "  {}: {} -> {}", dep.name, from, to)?,
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "package")]
+/// Path to the package's README, if it is located outside of the package directory.
+fn readme_outside_package(p: &cargo_metadata::Package) -> anyhow::Result<Option<PathBuf>> {
+    let Some(readme) = &p.readme else {
+        return Ok(None);
+    };
+    let package_dir = p.manifest_path.parent().unwrap().canonicalize()?;
+    let readme = package_dir
+        .join(readme)
+        .canonicalize()
+        .with_context(|| format!("README {} not found", readme))?;
+    Ok((!readme.starts_with(&package_dir)).then_some(readme))
+}
+
+#[cfg(feature = "package")]
 fn check_dirty(repository: &Path) -> anyhow::Result<()> {
     let out = std::process::Command::new("git")
         .args(["status", "--porcelain"])
@@ -108,7 +492,534 @@ fn check_dirty(repository: &Path) -> anyhow::Result<()> {
         // This gets filtered by cargo package anyway
         .filter(|l| !l.contains("Cargo.lock"))
         .collect_vec();
-    anyhow::ensure!(out.is_empty(), "Repository not clean: {}. These files would be embedded in the package. Stash them with `git stash -u` or add them to gitignore", out.join(" "));
+    anyhow::ensure!(
+        out.is_empty(),
+        Error::DirtyWorkspace {
+            files: out.into_iter().map(String::from).collect()
+        }
+    );
+    Ok(())
+}
+
+#[cfg(feature = "package")]
+/// Fail if manifests were left edited by a run that could not restore them (e.g. killed with
+/// SIGKILL), as packaging again would overwrite the original.
+fn check_leftover_edits<'a>(
+    packages: impl IntoIterator<Item = &'a cargo_metadata::Package>,
+) -> anyhow::Result<()> {
+    let leftovers = packages
+        .into_iter()
+        .filter_map(|p| {
+            let orig = p.manifest_path.with_extension("toml.pre-edit");
+            orig.exists()
+                .then(|| format!("mv {} {}", orig, p.manifest_path))
+        })
+        .collect_vec();
+    anyhow::ensure!(
+        leftovers.is_empty(),
+        "Manifests were left edited by an interrupted run, restore them with:\n  {}",
+        leftovers.join("\n  ")
+    );
+    Ok(())
+}
+
+/// Write to a temporary file and rename it, so that concurrent readers (e.g. `serve`) see
+/// either the previous or the new contents, never a partial file
+pub(crate) fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    let tmp = path.with_file_name(format!(
+        ".{}.tmp",
+        path.file_name().unwrap().to_string_lossy()
+    ));
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+pub fn sha256_file(path: &Path) -> anyhow::Result<String> {
+    let mut hasher = sha2::Sha256::new();
+    let mut file = std::fs::File::open(path)?;
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(feature = "package")]
+/// List the files in a .crate archive
+pub fn crate_files(path: &Path) -> anyhow::Result<Vec<String>> {
+    let tar = flate2::read::GzDecoder::new(std::fs::File::open(path)?);
+    let mut archive = tar::Archive::new(tar);
+    let mut files = vec![];
+    for entry in archive.entries()? {
+        files.push(entry?.path()?.to_string_lossy().into_owned());
+    }
+    Ok(files)
+}
+
+/// Only libraries and procedural macros can be added to the registry, unless binaries are kept
+pub fn is_library(p: &cargo_metadata::Package) -> bool {
+    p.targets
+        .iter()
+        .any(|t| t.is_lib() || t.kind.contains(&"proc-macro".into()))
+}
+
+#[cfg(feature = "package")]
+/// Whether a package can be added: libraries, and binaries when they are kept
+pub fn can_be_added(
+    p: &cargo_metadata::Package,
+    workspace_metadata: &cargo_metadata::Metadata,
+    flags: &PackageFlags,
+) -> anyhow::Result<bool> {
+    Ok(is_library(p) || PackagingOverrides::new(p, workspace_metadata)?.keep_bins(flags))
+}
+
+#[cfg(feature = "package")]
+/// `include`/`exclude` replacing the fields of the manifest when packaging, `keep-bins`/
+/// `keep-examples` replacing the flags, `rename-to` setting the name under which the package
+/// is added, and `generators` listing the generators of `depot.toml` run on its archive, set in
+/// `[package.metadata.depot]` or, to leave vendored manifests untouched, in
+/// `[workspace.metadata.depot.packages.<name>]` (which takes precedence)
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct PackagingOverrides {
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    keep_bins: Option<bool>,
+    keep_examples: Option<bool>,
+    rename_to: Option<String>,
+    generators: Option<Vec<String>>,
+}
+
+#[cfg(feature = "package")]
+impl PackagingOverrides {
+    fn new(
+        p: &cargo_metadata::Package,
+        workspace_metadata: &cargo_metadata::Metadata,
+    ) -> anyhow::Result<Self> {
+        let parse = |value: &serde_json::Value, location: &str| -> anyhow::Result<Self> {
+            if value.is_null() {
+                return Ok(Self::default());
+            }
+            serde_json::from_value(value.clone()).with_context(|| format!("Invalid {}", location))
+        };
+        let package = parse(&p.metadata["depot"], "package.metadata.depot")?;
+        let workspace = parse(
+            &workspace_metadata.workspace_metadata["depot"]["packages"][p.name.as_str()],
+            &format!("workspace.metadata.depot.packages.{}", p.name),
+        )?;
+        Ok(Self {
+            include: workspace.include.or(package.include),
+            exclude: workspace.exclude.or(package.exclude),
+            keep_bins: workspace.keep_bins.or(package.keep_bins),
+            keep_examples: workspace.keep_examples.or(package.keep_examples),
+            rename_to: workspace.rename_to.or(package.rename_to),
+            generators: workspace.generators.or(package.generators),
+        })
+    }
+    fn keep_bins(&self, flags: &PackageFlags) -> bool {
+        self.keep_bins.unwrap_or(flags.keep_bins)
+    }
+    fn keep_examples(&self, flags: &PackageFlags) -> bool {
+        self.keep_examples.unwrap_or(flags.keep_examples)
+    }
+}
+
+#[cfg(feature = "package")]
+/// The package as it is added to the registry, under the name set with `rename-to`
+pub fn renamed(
+    p: &cargo_metadata::Package,
+    workspace_metadata: &cargo_metadata::Metadata,
+) -> anyhow::Result<cargo_metadata::Package> {
+    let mut p = p.clone();
+    if let Some(name) = PackagingOverrides::new(&p, workspace_metadata)?.rename_to {
+        p.name = name;
+    }
+    Ok(p)
+}
+
+#[cfg(feature = "package")]
+/// Build the .crate archive of a package with `cargo package` (or without cargo with
+/// `--no-cargo`), after temporarily editing its manifest, and rewrite it under the name set with
+/// `rename-to`. Returns the path to the archive, in `target_directory`, which takes precedence
+/// over the `build.target-dir` configuration.
+pub fn package(
+    p: &cargo_metadata::Package,
+    workspace_metadata: &cargo_metadata::Metadata,
+    flags: &PackageFlags,
+    target_directory: &Path,
+) -> Result<PathBuf, Error> {
+    let archive = build_package(p, workspace_metadata, flags, target_directory)?;
+    match PackagingOverrides::new(p, workspace_metadata)?.rename_to {
+        Some(name) => Ok(builtin::rename_archive(&archive, p, &name)?),
+        None => Ok(archive),
+    }
+}
+
+#[cfg(feature = "package")]
+fn build_package(
+    p: &cargo_metadata::Package,
+    workspace_metadata: &cargo_metadata::Metadata,
+    flags: &PackageFlags,
+    target_directory: &Path,
+) -> anyhow::Result<PathBuf> {
+    // Edit manifest
+    info!("Editing manifest");
+    let manifest = std::fs::read_to_string(&p.manifest_path)?;
+    let mut manifest: cargo_util_schemas::manifest::TomlManifest = toml::from_str(&manifest)?;
+    let overrides = PackagingOverrides::new(p, workspace_metadata)?;
+    if let Some(package) = &mut manifest.package {
+        // The version might have been overridden
+        package.version = Some(cargo_util_schemas::manifest::InheritableField::Value(
+            p.version.clone(),
+        ));
+    }
+    if !overrides.keep_bins(flags) {
+        manifest.bin = None;
+    }
+    if !overrides.keep_examples(flags) {
+        if let Some(package) = &mut manifest.package {
+            package.autoexamples = Some(false);
+        }
+        manifest.example = None;
+    }
+    if let Some(package) = &mut manifest.package {
+        if let Some(include) = overrides.include {
+            package.include = Some(cargo_util_schemas::manifest::InheritableField::Value(
+                include,
+            ));
+        }
+        if let Some(exclude) = overrides.exclude {
+            package.exclude = Some(cargo_util_schemas::manifest::InheritableField::Value(
+                exclude,
+            ));
+        }
+    }
+    // Copy a README located outside of the package directory, so that the package is
+    // self-contained
+    let readme = readme_outside_package(p)?;
+    if let (Some(readme), Some(package)) = (&readme, &mut manifest.package) {
+        package.readme = Some(cargo_util_schemas::manifest::InheritableField::Value(
+            cargo_util_schemas::manifest::StringOrBool::String(
+                readme.file_name().unwrap().to_string_lossy().into(),
+            ),
+        ));
+    }
+    if flags.no_cargo {
+        return builtin::package(p, &manifest, readme.as_deref(), target_directory);
+    }
+    let readme_copy = match readme {
+        Some(readme) => {
+            let dest = p
+                .manifest_path
+                .parent()
+                .unwrap()
+                .as_std_path()
+                .join(readme.file_name().unwrap());
+            anyhow::ensure!(
+                !dest.exists(),
+                "The README {:?} is outside of the package, and {:?} already exists",
+                readme,
+                dest
+            );
+            info!("Copying README from {:?}", readme);
+            std::fs::copy(&readme, &dest)?;
+            Some(dest)
+        }
+        None => None,
+    };
+    // Restored even if packaging fails or panics
+    let readme_guard =
+        readme_copy.map(|path| interrupt::restore_on_drop(interrupt::Undo::Remove(path)));
+    check_leftover_edits([p])?;
+    let manifest_orig = p.manifest_path.with_extension("toml.pre-edit");
+    std::fs::copy(&p.manifest_path, &manifest_orig)?;
+    let manifest_guard = interrupt::restore_on_drop(interrupt::Undo::Rename {
+        from: manifest_orig.into(),
+        to: p.manifest_path.clone().into(),
+    });
+    // Replace the manifest atomically, as cargo might be reading it to package another member
+    let manifest_edited = p.manifest_path.with_extension("toml.edited");
+    std::fs::write(&manifest_edited, toml::to_string_pretty(&manifest)?)?;
+    std::fs::rename(manifest_edited, &p.manifest_path)?;
+
+    info!("Building package");
+
+    // Do not mistake the archive of a previous run for the output of this one
+    let expected = target_directory
+        .join("package")
+        .join(format!("{}-{}.crate", p.name, p.version));
+    match std::fs::remove_file(&expected) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let out = std::process::Command::new("cargo")
+        .args([
+            "package",
+            "-p",
+            &p.name,
+            "--no-verify",
+            &flags.features.flags(),
+            "--allow-dirty",
+        ])
+        .arg("--target-dir")
+        .arg(target_directory)
+        .args(flags.cargo.args())
+        .current_dir(p.manifest_path.parent().unwrap())
+        .spawn()?
+        .wait()?;
+    manifest_guard.restore()?;
+    if let Some(readme_guard) = readme_guard {
+        readme_guard.restore()?;
+    }
+    anyhow::ensure!(
+        out.success(),
+        Error::PackageFailed {
+            name: p.name.clone()
+        }
+    );
+    if expected.is_file() {
+        return Ok(expected);
+    }
+    find_package_archive(p, target_directory)
+}
+
+#[cfg(feature = "package")]
+/// Locate the archive produced by `cargo package` outside of `target_directory/package`, where
+/// current cargo versions write it, in case another version or configuration writes it elsewhere
+/// in the target directory. The most recent archive is picked.
+fn find_package_archive(
+    p: &cargo_metadata::Package,
+    target_directory: &Path,
+) -> anyhow::Result<PathBuf> {
+    let file = format!("{}-{}.crate", p.name, p.version);
+    let pattern = format!(
+        "{}/**/{}",
+        glob::Pattern::escape(&target_directory.to_string_lossy()),
+        glob::Pattern::escape(&file)
+    );
+    let mut archives = vec![];
+    for path in glob::glob(&pattern)? {
+        let path = path?;
+        archives.push((std::fs::metadata(&path)?.modified()?, path));
+    }
+    let Some((_, archive)) = archives.into_iter().max() else {
+        anyhow::bail!(
+            "cargo package did not produce {} in {:?}",
+            file,
+            target_directory
+        );
+    };
+    warn!(
+        "Found the archive of {} at an unexpected location: {:?}",
+        p.name, archive
+    );
+    Ok(archive)
+}
+
+#[cfg(feature = "package")]
+/// Order packages so that the packages they depend on come first, keeping the original order
+/// otherwise, so that the index never references versions it does not contain yet.
+/// Dev-dependencies are ignored, as they may form cycles.
+fn topological_order(
+    packages: Vec<&cargo_metadata::Package>,
+) -> anyhow::Result<Vec<&cargo_metadata::Package>> {
+    let names: HashSet<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+    let mut added = HashSet::new();
+    let mut remaining = packages;
+    let mut ordered = vec![];
+    while !remaining.is_empty() {
+        let Some(i) = remaining.iter().position(|p| {
+            p.dependencies.iter().all(|d| {
+                d.kind == cargo_metadata::DependencyKind::Development
+                    || d.name == p.name
+                    || !names.contains(d.name.as_str())
+                    || added.contains(d.name.as_str())
+            })
+        }) else {
+            anyhow::bail!(
+                "Dependency cycle between {}",
+                remaining.iter().map(|p| &p.name).join(", ")
+            );
+        };
+        let p = remaining.remove(i);
+        added.insert(p.name.as_str());
+        ordered.push(p);
+    }
+    Ok(ordered)
+}
+
+#[cfg(feature = "package")]
+/// Build the archives of packages, up to `jobs` at a time, in the same order. Concurrent builds
+/// use separate target directories, so that cargo does not wait on the lock of the shared one.
+fn package_all(
+    packages: &[&cargo_metadata::Package],
+    workspace_metadata: &cargo_metadata::Metadata,
+    flags: &PackageFlags,
+    jobs: usize,
+) -> Result<Vec<(PathBuf, std::time::Duration)>, Error> {
+    let target_directory = workspace_metadata.target_directory.as_std_path();
+    let package = |p, target_directory: &Path| {
+        let start = std::time::Instant::now();
+        package(p, workspace_metadata, flags, target_directory).map(|path| (path, start.elapsed()))
+    };
+    if jobs <= 1 || packages.len() <= 1 {
+        return packages
+            .iter()
+            .map(|p| package(p, target_directory))
+            .collect();
+    }
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let results = std::sync::Mutex::new(packages.iter().map(|_| None).collect_vec());
+    std::thread::scope(|s| {
+        for _ in 0..jobs.min(packages.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let Some(p) = packages.get(i) else {
+                    break;
+                };
+                info!("Packaging {}", p.name);
+                let res = package(p, &target_directory.join("depot-jobs").join(&p.name));
+                let failed = res.is_err();
+                results.lock().unwrap()[i] = Some(res);
+                if failed {
+                    // Do not start the remaining packages
+                    next.store(packages.len(), std::sync::atomic::Ordering::SeqCst);
+                }
+            });
+        }
+    });
+    // Packages after a failure were not started
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+#[cfg(feature = "package")]
+/// Hash of the contents of a .crate archive, ignoring `.cargo_vcs_info.json`, which changes
+/// with every commit.
+pub fn content_hash(path: &Path) -> anyhow::Result<String> {
+    let tar = flate2::read::GzDecoder::new(std::fs::File::open(path)?);
+    let mut entries = BTreeMap::new();
+    for entry in tar::Archive::new(tar).entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if path.ends_with("/.cargo_vcs_info.json") {
+            continue;
+        }
+        let mut hasher = sha2::Sha256::new();
+        std::io::copy(&mut entry, &mut hasher)?;
+        entries.insert(path, hasher.finalize());
+    }
+    let mut hasher = sha2::Sha256::new();
+    for (path, digest) in entries {
+        hasher.update(path.as_bytes());
+        hasher.update(digest);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(feature = "package")]
+/// Extract a .crate archive into a temporary directory
+pub fn unpack_crate(path: &Path) -> anyhow::Result<tempfile::TempDir> {
+    let output = temp::tempdir()?;
+    let tar = flate2::read::GzDecoder::new(std::fs::File::open(path)?);
+    tar::Archive::new(tar).unpack(&output)?;
+    Ok(output)
+}
+
+#[cfg(feature = "package")]
+fn verify_msrv(
+    p: &cargo_metadata::Package,
+    crate_file: &Path,
+    features: &FeaturesFlags,
+) -> anyhow::Result<()> {
+    let Some(msrv) = &p.rust_version else {
+        warn!("No rust-version declared, skipping MSRV verification");
+        return Ok(());
+    };
+    info!("Verifying that the package builds with Rust {}", msrv);
+    let out = std::process::Command::new("rustup")
+        .args([
+            "toolchain",
+            "install",
+            "--profile",
+            "minimal",
+            &msrv.to_string(),
+        ])
+        .status()?;
+    anyhow::ensure!(out.success(), "Failed to install Rust {}", msrv);
+    // Build from the packaged sources, in a copy outside of the workspace
+    let unpacked = unpack_crate(crate_file)?;
+    let out = std::process::Command::new("cargo")
+        .arg(format!("+{}", msrv))
+        .args(["check", &features.flags()])
+        .current_dir(unpacked.path().join(format!("{}-{}", p.name, p.version)))
+        .status()?;
+    anyhow::ensure!(
+        out.success(),
+        "Package does not build with its declared rust-version {}",
+        msrv
+    );
+    Ok(())
+}
+
+#[cfg(feature = "package")]
+fn verify_matrix(
+    p: &cargo_metadata::Package,
+    crate_file: &Path,
+    flags: &PackageFlags,
+) -> anyhow::Result<()> {
+    let features = if flags.check_features.is_empty() {
+        vec!["default".into()]
+    } else {
+        flags.check_features.clone()
+    };
+    let targets = if flags.check_target.is_empty() {
+        vec![None]
+    } else {
+        flags.check_target.iter().map(Some).collect()
+    };
+    for target in targets.iter().flatten() {
+        let out = std::process::Command::new("rustup")
+            .args(["target", "add", target])
+            .status()?;
+        anyhow::ensure!(out.success(), "Failed to install target {}", target);
+    }
+    let unpacked = unpack_crate(crate_file)?;
+    let dir = unpacked.path().join(format!("{}-{}", p.name, p.version));
+    let mut failures = vec![];
+    for target in &targets {
+        for feature_set in &features {
+            let mut command = std::process::Command::new("cargo");
+            command.arg("check").current_dir(&dir);
+            match feature_set.as_str() {
+                "default" => {}
+                "no-default-features" => {
+                    command.arg("--no-default-features");
+                }
+                "all-features" => {
+                    command.arg("--all-features");
+                }
+                features => {
+                    command.arg(format!("--features={}", features));
+                }
+            }
+            if let Some(target) = target {
+                command.arg(format!("--target={}", target));
+            }
+            let target = target.map_or("host", |t| t.as_str());
+            info!("Checking {} with features {}", target, feature_set);
+            if !command.status()?.success() {
+                failures.push(format!("{} ({})", target, feature_set));
+            }
+        }
+    }
+    anyhow::ensure!(
+        failures.is_empty(),
+        "Package does not build for: {}",
+        failures.join(", ")
+    );
     Ok(())
 }
 
@@ -119,25 +1030,34 @@ pub struct IndexMeta {
     vers: cargo_metadata::semver::Version,
     deps: Vec<Dependency>,
     features: BTreeMap<String, Vec<String>>,
+    /// Features using the `dep:` or `pkg?/feat` syntax, which older cargo versions cannot parse
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    features2: BTreeMap<String, Vec<String>>,
     license: Option<String>,
     license_file: Option<cargo_metadata::camino::Utf8PathBuf>,
     cksum: String,
+    /// Name of the native library the package links to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    links: Option<String>,
+    /// Minimum supported Rust version, used by cargo's MSRV-aware resolver
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rust_version: Option<String>,
+    /// 2 when `features2` is present, 1 otherwise
     v: u8,
     yanked: bool,
 }
 impl IndexMeta {
-    pub fn from_package(p: &cargo_metadata::Package, checksum: String) -> Self {
+    /// Index entry of a package, pointing its dependencies to registries according to the
+    /// settings of the registry
+    pub fn from_package(
+        p: &cargo_metadata::Package,
+        checksum: String,
+        settings: &settings::Settings,
+    ) -> Self {
         let mut deps: Vec<Dependency> = vec![];
         for dep_meta in &p.dependencies {
             let mut dep = Dependency::from(dep_meta.clone());
-            if dep.registry.as_ref().map_or(false, |s| {
-                s != "registry+https://github.com/rust-lang/crates.io-index"
-            }) || dep_meta.path.is_some()
-            {
-                // Use our registry when the package is a path, a git repository, or another
-                // registry.
-                dep.registry = None;
-            }
+            dep.registry = settings.dependency_registry(dep_meta);
             // Renames
             if let Some(original) = dep_meta.rename.clone() {
                 dep.package = Some(dep.name);
@@ -161,16 +1081,226 @@ impl IndexMeta {
                 }
             })
         });
-        Self {
+        let mut meta = Self {
             deps,
             name: p.name.clone(),
             vers: p.version.clone(),
             features,
+            features2: Default::default(),
             license: p.license.clone(),
             license_file: p.license_file.clone(),
             cksum: checksum,
-            v: 2,
+            links: p.links.clone(),
+            rust_version: p.rust_version.as_ref().map(|v| v.to_string()),
+            v: 1,
             yanked: false,
+        };
+        meta.split_features();
+        meta
+    }
+    /// Move the features using the `dep:` or `pkg?/feat` syntax to `features2`, and set the
+    /// schema version accordingly.
+    pub(crate) fn split_features(&mut self) {
+        let features = std::mem::take(&mut self.features)
+            .into_iter()
+            .chain(std::mem::take(&mut self.features2));
+        (self.features2, self.features) = features.partition(|(_, values)| {
+            values
+                .iter()
+                .any(|v| v.starts_with("dep:") || v.contains("?/"))
+        });
+        self.v = if self.features2.is_empty() { 1 } else { 2 };
+    }
+    /// Features from both `features` and `features2`
+    pub fn all_features(&self) -> impl Iterator<Item = (&String, &Vec<String>)> {
+        self.features.iter().chain(&self.features2)
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn version(&self) -> &cargo_metadata::semver::Version {
+        &self.vers
+    }
+    pub fn checksum(&self) -> &str {
+        &self.cksum
+    }
+    pub fn is_yanked(&self) -> bool {
+        self.yanked
+    }
+}
+
+impl IndexMeta {
+    /// Check the entry against cargo's rules for package and feature names, and the references
+    /// between features and dependencies.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        use cargo_util_schemas::manifest::{FeatureName, PackageName};
+        let context = || format!("Invalid index entry for {} {}", self.name, self.vers);
+        PackageName::new(&self.name).with_context(context)?;
+        anyhow::ensure!(
+            self.cksum.len() == 64 && self.cksum.chars().all(|c| c.is_ascii_hexdigit()),
+            "{}: invalid checksum {:?}",
+            context(),
+            self.cksum
+        );
+        for dep in &self.deps {
+            PackageName::new(dep.package.as_ref().unwrap_or(&dep.name)).with_context(context)?;
+            if let Some(registry) = &dep.registry {
+                anyhow::ensure!(
+                    registry.contains("://"),
+                    "{}: registry of {} should be an index URL, got {:?}",
+                    context(),
+                    dep.name,
+                    registry
+                );
+            }
+        }
+        for (feature, values) in self.all_features() {
+            FeatureName::new(feature).with_context(context)?;
+            for value in values {
+                self.validate_feature_value(value)
+                    .with_context(|| format!("{}: feature {}", context(), feature))?;
+            }
+        }
+        Ok(())
+    }
+    fn validate_feature_value(&self, value: &str) -> anyhow::Result<()> {
+        let dep = |name: &str| {
+            self.deps
+                .iter()
+                .find(|d| d.name == name && d.kind != cargo_metadata::DependencyKind::Development)
+        };
+        if let Some(name) = value.strip_prefix("dep:") {
+            anyhow::ensure!(
+                dep(name).map_or(false, |d| d.optional),
+                "{:?} does not refer to an optional dependency",
+                value
+            );
+        } else if let Some((name, feature)) = value.split_once('/') {
+            let name = name.trim_end_matches('?');
+            anyhow::ensure!(
+                dep(name).is_some(),
+                "{:?} does not refer to a dependency",
+                value
+            );
+            cargo_util_schemas::manifest::FeatureName::new(feature)?;
+        } else {
+            anyhow::ensure!(
+                self.features.contains_key(value)
+                    || self.features2.contains_key(value)
+                    || dep(value).map_or(false, |d| d.optional),
+                "{:?} is neither a feature nor an optional dependency",
+                value
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Cargo.lock
+#[cfg(feature = "package")]
+#[derive(Deserialize)]
+pub struct Lockfile {
+    #[serde(default)]
+    pub package: Vec<LockedPackage>,
+}
+
+#[cfg(feature = "package")]
+#[derive(Deserialize)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: cargo_metadata::semver::Version,
+    pub source: Option<String>,
+    pub checksum: Option<String>,
+}
+
+/// manifest.json at the root of the registry, listing the index files with their hashes, used
+/// for replication.
+#[derive(Default, Serialize, Deserialize)]
+pub struct RegistryManifest {
+    pub index: BTreeMap<String, String>,
+}
+
+/// Package metadata omitted by the index schema, stored next to the archive.
+#[derive(Default, Serialize, Deserialize)]
+pub struct PackageInfo {
+    pub description: Option<String>,
+    pub documentation: Option<String>,
+    pub homepage: Option<String>,
+    pub repository: Option<String>,
+    /// Absent for versions published through the API, mirrored, or added before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<PackagingEnvironment>,
+    /// Set for mirrored versions whose metadata was fetched from the upstream registry rather
+    /// than read from the manifest, see `mirror --upstream-metadata`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<UpstreamInfo>,
+}
+impl PackageInfo {
+    #[cfg(feature = "package")]
+    fn from_package(p: &cargo_metadata::Package, flags: &PackageFlags) -> Self {
+        Self {
+            description: p.description.clone(),
+            documentation: p.documentation.clone(),
+            homepage: p.homepage.clone(),
+            repository: p.repository.clone(),
+            environment: Some(PackagingEnvironment::capture(flags)),
+            upstream: None,
+        }
+    }
+}
+
+/// Origin of upstream-sourced metadata, and the statistics only known upstream
+#[derive(Serialize, Deserialize)]
+pub struct UpstreamInfo {
+    /// Base URL of the web API the metadata was fetched from, e.g. `https://crates.io`
+    pub api: String,
+    /// Unix timestamp of the fetch, as the download counts keep changing
+    pub fetched: u64,
+    /// Downloads of all the versions of the crate
+    pub downloads: Option<u64>,
+    /// Downloads of this version
+    pub version_downloads: Option<u64>,
+}
+
+/// Environment in which a version was packaged, to investigate unexpected archives.
+#[derive(Serialize, Deserialize)]
+pub struct PackagingEnvironment {
+    pub depot_version: String,
+    /// `None` with `--no-cargo`
+    pub cargo_version: Option<String>,
+    pub rustc_version: Option<String>,
+    /// Operating system and architecture
+    pub platform: String,
+    /// `CARGO_*` and `RUST*` variables, except those holding credentials
+    pub variables: BTreeMap<String, String>,
+}
+impl PackagingEnvironment {
+    #[cfg(feature = "package")]
+    fn capture(flags: &PackageFlags) -> Self {
+        let version = |program: String| {
+            let out = std::process::Command::new(program)
+                .arg("--version")
+                .output()
+                .ok()?;
+            out.status
+                .success()
+                .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        };
+        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+        Self {
+            depot_version: env!("CARGO_PKG_VERSION").into(),
+            cargo_version: if flags.no_cargo { None } else { version(cargo) },
+            rustc_version: version(rustc),
+            platform: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+            variables: std::env::vars()
+                .filter(|(name, _)| name.starts_with("CARGO_") || name.starts_with("RUST"))
+                .filter(|(name, _)| {
+                    !["TOKEN", "SECRET", "PASSWORD", "CREDENTIAL"]
+                        .iter()
+                        .any(|s| name.contains(s))
+                })
+                .collect(),
         }
     }
 }
@@ -180,114 +1310,607 @@ impl Registry {
     pub fn package_index(&self, name: &str) -> PathBuf {
         self.0.join(INDEX).join(pkg_path(name)).join(name)
     }
+    pub fn crate_path(&self, name: &str, version: &cargo_metadata::semver::Version) -> PathBuf {
+        // Do not use .with_extension due to the . in the name.
+        self.0
+            .join(CRATES)
+            .join(name)
+            .join(format!("{}-{}.crate", name, version))
+    }
+    pub fn config(&self) -> anyhow::Result<IndexConfig> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(
+            self.0.join(INDEX).join("config.json"),
+        )?)?)
+    }
+    /// URL from which cargo downloads a version
+    pub fn download_url(
+        &self,
+        name: &str,
+        version: &cargo_metadata::semver::Version,
+    ) -> anyhow::Result<String> {
+        let Some(meta) = self
+            .read_package(name)?
+            .into_iter()
+            .find(|m| &m.vers == version)
+        else {
+            anyhow::bail!("{} {} not found in the index", name, version);
+        };
+        Ok(self
+            .config()?
+            .download_url(name, &version.to_string(), &meta.cksum))
+    }
+    pub fn write_config(&self, config: &IndexConfig) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        config.write(&self.0.join(INDEX))?;
+        self.commit_index("Update the index configuration")
+    }
+    /// Path where the API diff of a release against its predecessor is stored
+    pub fn api_diff_path(&self, name: &str, version: &cargo_metadata::semver::Version) -> PathBuf {
+        self.crate_path(name, version)
+            .with_extension("api-diff.txt")
+    }
+    pub fn info_path(&self, name: &str, version: &cargo_metadata::semver::Version) -> PathBuf {
+        self.crate_path(name, version).with_extension("info.json")
+    }
+    /// Sidecar metadata of a version, empty for versions published without it.
+    pub fn read_info(
+        &self,
+        name: &str,
+        version: &cargo_metadata::semver::Version,
+    ) -> anyhow::Result<PackageInfo> {
+        let path = self.info_path(name, version);
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
     pub fn read_package(&self, name: &str) -> anyhow::Result<Vec<IndexMeta>> {
         let filename = self.package_index(name);
-        if !filename.exists() {
-            return Ok(vec![]);
-        }
+        let contents = match std::fs::read_to_string(&filename) {
+            Ok(contents) => contents,
+            // Also when removed after a concurrent check
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
         let mut res = vec![];
-        for line in std::fs::read_to_string(&filename)?
-            .lines()
-            .filter(|l| !l.trim().is_empty())
-        {
-            res.push(serde_json::from_str(line)?);
+        let mut lines = contents.lines().filter(|l| !l.trim().is_empty()).peekable();
+        while let Some(line) = lines.next() {
+            match serde_json::from_str(line) {
+                Ok(meta) => res.push(meta),
+                // Line being appended by a writer that does not replace the file, e.g. an older
+                // version of cargo-depot
+                Err(e) if lines.peek().is_none() && !contents.ends_with('\n') => {
+                    warn!(
+                        "Ignoring the incomplete last entry of {:?}: {}",
+                        filename, e
+                    );
+                }
+                Err(e) => return Err(e.into()),
+            }
         }
         Ok(res)
     }
+    fn write_package(&self, name: &str, versions: &[IndexMeta]) -> anyhow::Result<()> {
+        let mut contents = String::new();
+        for v in versions {
+            v.validate()?;
+            contents += &serde_json::to_string(v)?;
+            contents.push('\n');
+        }
+        write_atomic(&self.package_index(name), contents)?;
+        self.update_latest(name)
+    }
+    /// Parse and validate every entry in the index
+    pub fn validate_index(&self) -> anyhow::Result<()> {
+        let errors = self.index_errors()?;
+        for e in &errors {
+            error!("{}", e);
+        }
+        anyhow::ensure!(
+            errors.is_empty(),
+            "Found {} invalid index entries",
+            errors.len()
+        );
+        Ok(())
+    }
+    /// Invalid entries in the index, with their location
+    pub fn index_errors(&self) -> anyhow::Result<Vec<String>> {
+        let mut errors = vec![];
+        for name in self.package_names()? {
+            let filename = self.package_index(&name);
+            for (i, line) in std::fs::read_to_string(&filename)?.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let res = serde_json::from_str::<IndexMeta>(line)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|meta| {
+                        anyhow::ensure!(
+                            meta.name.to_lowercase() == name.to_lowercase(),
+                            "Entry for {} in the index file of {}",
+                            meta.name,
+                            name
+                        );
+                        meta.validate()
+                    });
+                if let Err(e) = res {
+                    errors.push(format!("{:?}:{}: {:#}", filename, i + 1, e));
+                }
+            }
+        }
+        Ok(errors)
+    }
+    /// Names of all the packages in the index, sorted
+    pub fn package_names(&self) -> anyhow::Result<Vec<String>> {
+        let index = self.0.join(INDEX);
+        let mut names = vec![];
+        let mut stack = vec![index.clone()];
+        while let Some(dir) = stack.pop() {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                // Skip temporary files and the git repository
+                if path.file_name().unwrap().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                if path.is_dir() {
+                    stack.push(path);
+                } else if path.parent() != Some(&index) {
+                    names.push(path.file_name().unwrap().to_string_lossy().into_owned());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+    pub fn yank(
+        &self,
+        name: &str,
+        version: &cargo_metadata::semver::Version,
+    ) -> anyhow::Result<()> {
+        self.set_yanked(name, version, true)
+    }
+    /// Make a yanked version resolvable again
+    pub fn unyank(
+        &self,
+        name: &str,
+        version: &cargo_metadata::semver::Version,
+    ) -> anyhow::Result<()> {
+        self.set_yanked(name, version, false)
+    }
+    fn set_yanked(
+        &self,
+        name: &str,
+        version: &cargo_metadata::semver::Version,
+        yanked: bool,
+    ) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        let mut versions = self.read_package(name)?;
+        let Some(meta) = versions.iter_mut().find(|m| &m.vers == version) else {
+            anyhow::bail!("{} {} not found in the index", name, version);
+        };
+        if meta.yanked == yanked {
+            warn!(
+                "{} {} {}",
+                name,
+                version,
+                if yanked {
+                    "is already yanked"
+                } else {
+                    "is not yanked"
+                }
+            );
+            return Ok(());
+        }
+        meta.yanked = yanked;
+        self.write_package(name, &versions)?;
+        self.update_manifest()?;
+        let kind = if yanked {
+            changes::ChangeKind::Yank
+        } else {
+            changes::ChangeKind::Unyank
+        };
+        self.record_change(kind, name, Some(version))?;
+        self.commit_index(&format!(
+            "{} {} {}",
+            if yanked { "Yank" } else { "Unyank" },
+            name,
+            version
+        ))
+    }
+    /// Non-yanked versions of other crates declaring the same `links` value, which cannot be
+    /// part of the same dependency graph.
+    pub fn links_conflicts(&self, name: &str, links: &str) -> anyhow::Result<Vec<IndexMeta>> {
+        let mut conflicts = vec![];
+        for other in self.package_names()? {
+            if other == name {
+                continue;
+            }
+            conflicts.extend(
+                self.read_package(&other)?
+                    .into_iter()
+                    .filter(|m| !m.yanked && m.links.as_deref() == Some(links)),
+            );
+        }
+        Ok(conflicts)
+    }
+    pub fn read_manifest(&self) -> anyhow::Result<RegistryManifest> {
+        let path = self.0.join(MANIFEST);
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+    /// Regenerate manifest.json from the index
+    pub fn update_manifest(&self) -> anyhow::Result<()> {
+        let mut manifest = RegistryManifest::default();
+        for name in self.package_names()? {
+            let hash = sha256_file(&self.package_index(&name))?;
+            manifest.index.insert(name, hash);
+        }
+        write_atomic(
+            &self.0.join(MANIFEST),
+            serde_json::to_string_pretty(&manifest)?,
+        )
+    }
+    #[cfg(feature = "package")]
     pub fn add_package(
         &self,
         p: &cargo_metadata::Package,
         workspace_metadata: &cargo_metadata::Metadata,
-        features: &FeaturesFlags,
-    ) -> anyhow::Result<()> {
-        if !p
-            .targets
-            .iter()
-            .any(|t| t.is_lib() || t.kind.contains(&"proc-macro".into()))
-        {
-            warn!("Skipping non-library package");
-            return Ok(());
+        flags: &PackageFlags,
+    ) -> Result<PublishOutcome, Error> {
+        let mut outcomes =
+            self.add_packages(std::slice::from_ref(p), workspace_metadata, flags, 1)?;
+        // One outcome per package
+        Ok(outcomes.remove(0).outcome)
+    }
+    #[cfg(feature = "package")]
+    /// Add packages of a workspace, building up to `jobs` archives concurrently. The archives
+    /// are then added one at a time, in order. Returns an outcome per package, the skipped
+    /// packages first.
+    pub fn add_packages(
+        &self,
+        packages: &[cargo_metadata::Package],
+        workspace_metadata: &cargo_metadata::Metadata,
+        flags: &PackageFlags,
+        jobs: usize,
+    ) -> Result<Vec<PackageOutcome>, Error> {
+        let mut outcomes = vec![];
+        let mut pending = vec![];
+        for p in packages {
+            flags.check_expected_version(p)?;
+            let added = renamed(p, workspace_metadata)?;
+            if !can_be_added(p, workspace_metadata, flags)? {
+                warn!(
+                    "Skipping non-library package {}, add it with --keep-bins",
+                    p.name
+                );
+                outcomes.push(PackageOutcome::new(
+                    &added,
+                    PublishOutcome::SkippedNonLibrary,
+                    Default::default(),
+                ));
+                continue;
+            }
+            // Check if already in the index
+            if self
+                .read_package(&added.name)?
+                .into_iter()
+                .any(|p_index| p_index.vers == added.version)
+            {
+                if !flags.force {
+                    warn!(
+                        "{} {} already in the index, skipping",
+                        added.name, added.version
+                    );
+                    outcomes.push(PackageOutcome::new(
+                        &added,
+                        PublishOutcome::SkippedExisting,
+                        Default::default(),
+                    ));
+                    continue;
+                }
+                warn!(
+                    "{} {} already in the index, replacing it",
+                    added.name, added.version
+                );
+            }
+            flags.check_freeze(self, &added.name, &added.version)?;
+            pending.push(p);
+        }
+        if pending.is_empty() {
+            return Ok(outcomes);
+        }
+        let pending = topological_order(pending)?;
+
+        // Before any manifest is edited
+        check_leftover_edits(workspace_metadata.workspace_packages())?;
+        check_dirty(workspace_metadata.workspace_root.as_std_path())?;
+        let archives = package_all(&pending, workspace_metadata, flags, jobs)?;
+        for (p, (crate_src, packaging)) in pending.into_iter().zip(archives) {
+            let generators = PackagingOverrides::new(p, workspace_metadata)?.generators;
+            let p = renamed(p, workspace_metadata)?;
+            info!("Adding {}", p.name);
+            let start = std::time::Instant::now();
+            let outcome =
+                self.add_archive(&p, &crate_src, flags, &generators.unwrap_or_default())?;
+            outcomes.push(PackageOutcome::new(
+                &p,
+                outcome,
+                packaging + start.elapsed(),
+            ));
+        }
+        Ok(outcomes)
+    }
+    #[cfg(feature = "package")]
+    /// Add an existing .crate archive, with the metadata of its manifest, without invoking cargo
+    pub fn add_crate_file(
+        &self,
+        path: &Path,
+        flags: &PackageFlags,
+    ) -> Result<PackageOutcome, Error> {
+        info!("Adding {:?}", path);
+        let start = std::time::Instant::now();
+        let unpacked = unpack_crate(path)?;
+        let Some(dir) = std::fs::read_dir(&unpacked)?.next() else {
+            return Err(anyhow::anyhow!("{:?} is empty", path).into());
+        };
+        let metadata = builtin::metadata(&dir?.path())?;
+        let Some(p) = metadata.packages.first() else {
+            return Err(anyhow::anyhow!("No package in {:?}", path).into());
+        };
+        flags.check_expected_version(p)?;
+        if !crate_files(path)?.contains(&format!("{}-{}/Cargo.toml", p.name, p.version)) {
+            return Err(anyhow::anyhow!(
+                "{:?} is not a .crate archive of {} {}",
+                path,
+                p.name,
+                p.version
+            )
+            .into());
+        }
+        if !can_be_added(p, &metadata, flags)? {
+            return Err(Error::NotALibrary {
+                name: p.name.clone(),
+            });
         }
-        // Check if already in the index
         if self
             .read_package(&p.name)?
             .into_iter()
             .any(|p_index| p_index.vers == p.version)
         {
-            warn!("Package already in the index, skipping");
-            return Ok(());
+            if !flags.force {
+                warn!("Package already in the index, skipping");
+                return Ok(PackageOutcome::new(
+                    p,
+                    PublishOutcome::SkippedExisting,
+                    start.elapsed(),
+                ));
+            }
+            warn!("Package already in the index, replacing it");
+        }
+        flags.check_freeze(self, &p.name, &p.version)?;
+        let generators = PackagingOverrides::new(p, &metadata)?.generators;
+        let outcome = self.add_archive(p, path, flags, &generators.unwrap_or_default())?;
+        Ok(PackageOutcome::new(p, outcome, start.elapsed()))
+    }
+    #[cfg(feature = "package")]
+    /// Verify a .crate archive and add it with the metadata of its package, and the artifacts
+    /// of `generators`
+    fn add_archive(
+        &self,
+        p: &cargo_metadata::Package,
+        crate_src: &Path,
+        flags: &PackageFlags,
+        generators: &[String],
+    ) -> anyhow::Result<PublishOutcome> {
+        // Hash .crate
+        let hash = sha256_file(crate_src)?;
+        if flags.verify_msrv {
+            verify_msrv(p, crate_src, &flags.features)?;
+        }
+        #[cfg(feature = "serve")]
+        if flags.verify {
+            verify::verify_build(self, p, crate_src, flags)?;
+        }
+        if !flags.check_features.is_empty() || !flags.check_target.is_empty() {
+            verify_matrix(p, crate_src, flags)?;
         }
+        // Compute metadata
+        let settings = self.settings()?;
+        let metadata = IndexMeta::from_package(p, hash.clone(), &settings);
+        metadata.validate()?;
+        self.warn_links(&metadata)?;
+        #[cfg(feature = "policy")]
+        if let Some(script) = &flags.policy {
+            policy::check(script, &metadata, crate_files(crate_src)?)?;
+        }
+        let artifacts = self.generate_artifacts(p, crate_src, generators)?;
 
-        check_dirty(workspace_metadata.workspace_root.as_std_path())?;
-        // Edit manifest
-        info!("Editing manifest");
-        let manifest = std::fs::read_to_string(&p.manifest_path)?;
-        let mut manifest: cargo_util_schemas::manifest::TomlManifest = toml::from_str(&manifest)?;
-        if let Some(package) = &mut manifest.package {
-            package.autoexamples = Some(false);
+        let info = PackageInfo::from_package(p, flags);
+        // Until the version is added, so that concurrent runs cannot both add it
+        let _lock = self.lock()?;
+        let exists = self
+            .read_package(&p.name)?
+            .iter()
+            .any(|m| m.vers == p.version);
+        if flags.dry_run {
+            print_dry_run(p, &metadata, crate_src, exists, &settings, flags.output)?;
+            return Ok(PublishOutcome::DryRun { checksum: hash });
         }
-        manifest.bin = None;
-        manifest.example = None;
-        let manifest_orig = p.manifest_path.with_extension("toml.pre-edit");
-        std::fs::rename(&p.manifest_path, &manifest_orig)?;
-        std::fs::write(&p.manifest_path, toml::to_string_pretty(&manifest)?)?;
+        if exists && !flags.force {
+            // Checked before packaging, without the lock
+            warn!(
+                "{} {} was added by another run in the meantime, skipping",
+                p.name, p.version
+            );
+            return Ok(PublishOutcome::SkippedExisting);
+        }
+        let path = self.crate_path(&p.name, &p.version);
+        if exists {
+            self.replace_package(metadata, &info, crate_src)?;
+            self.store_artifacts(&p.name, &p.version, artifacts)?;
+            return Ok(PublishOutcome::Replaced {
+                checksum: hash,
+                path,
+            });
+        }
+        self.insert(&metadata, &info, crate_src)?;
+        self.store_artifacts(&p.name, &p.version, artifacts)?;
+        Ok(PublishOutcome::Added {
+            checksum: hash,
+            path,
+        })
+    }
+    #[cfg(feature = "package")]
+    fn warn_links(&self, metadata: &IndexMeta) -> anyhow::Result<()> {
+        let Some(links) = &metadata.links else {
+            return Ok(());
+        };
+        let conflicts = self.links_conflicts(&metadata.name, links)?;
+        if !conflicts.is_empty() {
+            warn!(
+                "{} links to {:?} like {}, which cargo does not allow in the same dependency graph",
+                metadata.name,
+                links,
+                conflicts
+                    .iter()
+                    .map(|m| format!("{} {}", m.name, m.vers))
+                    .join(", ")
+            );
+        }
+        Ok(())
+    }
+    /// Replace the archive, sidecar metadata and index entry of a version already in the
+    /// registry, keeping its yanked status
+    #[cfg(feature = "package")]
+    pub fn replace_package(
+        &self,
+        mut metadata: IndexMeta,
+        info: &PackageInfo,
+        crate_src: &Path,
+    ) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        let (name, version) = (metadata.name.clone(), metadata.vers.clone());
+        anyhow::ensure!(
+            !self.is_pinned(&name, &version)?,
+            "{} {} is pinned, unpin it before replacing it",
+            name,
+            version
+        );
+        let mut versions = self.read_package(&name)?;
+        let Some(position) = versions.iter().position(|m| m.vers == version) else {
+            anyhow::bail!("{} {} not found in the index", name, version);
+        };
+        metadata.yanked = versions[position].yanked;
+        versions[position] = metadata;
 
-        info!("Building package");
-        let parent = self.0.join(CRATES).join(&p.name);
-        std::fs::create_dir_all(&parent)?;
-        // Do not use .with_extension due to the . in the name.
-        let crate_dest = parent.join(format!("{}-{}.crate", p.name, p.version));
-
-        let out = std::process::Command::new("cargo")
-            .args([
-                "package",
-                "-p",
-                &p.name,
-                "--no-verify",
-                &features.flags(),
-                "--allow-dirty",
-            ])
-            .current_dir(p.manifest_path.parent().unwrap())
-            .spawn()?
-            .wait()?;
-        std::fs::rename(manifest_orig, &p.manifest_path)?;
-        anyhow::ensure!(out.success(), "Failed to build package");
-        // Hash .crate
-        let crate_src = workspace_metadata
-            .target_directory
-            .as_std_path()
-            .join("package")
-            .join(crate_dest.file_name().unwrap());
-        let mut hasher = sha2::Sha256::new();
-        let mut file = std::fs::File::open(&crate_src)?;
-        std::io::copy(&mut file, &mut hasher)?;
-        let hash = format!("{:x}", hasher.finalize());
-        // Copy .crate
-        anyhow::ensure!(!crate_dest.exists(), "{:?} already exists", crate_dest);
+        let crate_dest = self.crate_path(&name, &version);
+        let (size, previous) = (
+            std::fs::metadata(crate_src)?.len(),
+            std::fs::metadata(&crate_dest).map_or(0, |m| m.len()),
+        );
+        if size > previous {
+            self.check_quota(&name, size - previous)?;
+        }
+        // Replace the archive atomically, as it might be served
+        let crate_tmp = crate_dest.with_extension("crate.tmp");
+        std::fs::copy(crate_src, &crate_tmp)?;
+        self.sign_archive(&name, &version, crate_src)?;
+        std::fs::rename(crate_tmp, crate_dest)?;
+        std::fs::write(
+            self.info_path(&name, &version),
+            serde_json::to_string_pretty(info)?,
+        )?;
+        // Computed from the previous archive
+        let api_diff = self.api_diff_path(&name, &version);
+        if api_diff.exists() {
+            std::fs::remove_file(api_diff)?;
+        }
+        self.write_package(&name, &versions)?;
+        self.update_manifest()?;
+        self.record_change(changes::ChangeKind::Replace, &name, Some(&version))?;
+        self.commit_index(&format!("Replace {} {}", name, version))
+    }
+    /// Copy the archive and its sidecar metadata into the registry, and add the index entry
+    #[cfg(feature = "package")]
+    fn insert(
+        &self,
+        metadata: &IndexMeta,
+        info: &PackageInfo,
+        crate_src: &Path,
+    ) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        let crate_dest = self.crate_path(&metadata.name, &metadata.vers);
+        let info_dest = self.info_path(&metadata.name, &metadata.vers);
+        anyhow::ensure!(
+            !crate_dest.exists(),
+            Error::AlreadyPublished {
+                name: metadata.name.clone(),
+                version: metadata.vers.clone()
+            }
+        );
+        self.check_quota(&metadata.name, std::fs::metadata(crate_src)?.len())?;
+        std::fs::create_dir_all(crate_dest.parent().unwrap())?;
+        // Remove the files if interrupted before the index is updated
+        let guards = [
+            interrupt::on_interrupt(interrupt::Undo::Remove(crate_dest.clone())),
+            interrupt::on_interrupt(interrupt::Undo::Remove(info_dest.clone())),
+            interrupt::on_interrupt(interrupt::Undo::Remove(
+                self.signature_path(&metadata.name, &metadata.vers),
+            )),
+        ];
+        // First, as it fails if the signing key is invalid
+        self.sign_archive(&metadata.name, &metadata.vers, crate_src)?;
         std::fs::copy(crate_src, crate_dest)?;
+        std::fs::write(info_dest, serde_json::to_string_pretty(info)?)?;
 
-        // Compute metadata
-        let metadata = IndexMeta::from_package(p, hash);
-
-        // Write to index
-        let index = self.package_index(&p.name);
+        // Write to index, replacing the file rather than appending to it
+        let index = self.package_index(&metadata.name);
         std::fs::create_dir_all(index.parent().unwrap())?;
-        let mut f = std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(index)?;
-        writeln!(f, "{}", serde_json::to_string(&metadata)?)?;
-        Ok(())
+        let mut contents = match std::fs::read_to_string(&index) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        contents += &serde_json::to_string(metadata)?;
+        contents.push('\n');
+        write_atomic(&index, contents)?;
+        // The version is published, the files must stay if interrupted from now on
+        drop(guards);
+        self.update_latest(&metadata.name)?;
+        self.update_manifest()?;
+        self.record_change(
+            changes::ChangeKind::Publish,
+            &metadata.name,
+            Some(&metadata.vers),
+        )?;
+        self.commit_index(&format!("Add {} {}", metadata.name, metadata.vers))
     }
+    /// Open the registry at `root`, initializing it at `url` (by default, the URL of
+    /// `depot.toml`) if needed
     pub fn open(root: &Path, url: Option<&str>) -> anyhow::Result<Self> {
         std::fs::create_dir_all(root)?;
+        if let Some(format) = migrate::check_format(root)?.filter(|f| *f < migrate::FORMAT_VERSION)
+        {
+            warn!(
+                "The registry has format {}, upgrade it to format {} with `cargo depot upgrade-registry`",
+                format,
+                migrate::FORMAT_VERSION
+            );
+        }
+        let settings = settings::read(root)?;
+        let url = url.or(settings.url.as_deref());
 
         let index = root.join(INDEX);
         if !index.join("config.json").exists() {
             info!("Initializing registry at {:?}", root);
             let Some(url) = &url else {
                 anyhow::bail!(
-                    "Provide the URL where the registry will be hosted with the --url flag"
+                    "Provide the URL where the registry will be hosted with the --url flag, or in depot.toml"
                 );
             };
             IndexConfig::from_url(url).write(&index)?;
@@ -295,3 +1918,183 @@ impl Registry {
         Ok(Self(root.into()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Index entry of `foo` with optional `serde`, and `bar` and a dev-dependency on `baz`
+    fn entry(features: serde_json::Value) -> IndexMeta {
+        let dep = |name: &str, optional: bool, kind: &str| {
+            serde_json::json!({
+                "name": name,
+                "req": "^1",
+                "features": [],
+                "optional": optional,
+                "default_features": true,
+                "target": null,
+                "kind": kind,
+                "registry": null,
+                "package": null,
+            })
+        };
+        serde_json::from_value(serde_json::json!({
+            "name": "foo",
+            "vers": "1.0.0",
+            "deps": [
+                dep("serde", true, "normal"),
+                dep("bar", false, "normal"),
+                dep("baz", true, "dev"),
+            ],
+            "features": features,
+            "license": null,
+            "license_file": null,
+            "cksum": "0".repeat(64),
+            "v": 1,
+            "yanked": false,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn split_features() {
+        let mut meta = entry(serde_json::json!({
+            "default": ["std"],
+            "std": ["bar/std"],
+            "serde": ["dep:serde"],
+            "serde-std": ["serde?/std"],
+        }));
+        meta.split_features();
+        assert_eq!(meta.features.keys().collect::<Vec<_>>(), ["default", "std"]);
+        assert_eq!(
+            meta.features2.keys().collect::<Vec<_>>(),
+            ["serde", "serde-std"]
+        );
+        assert_eq!(meta.v, 2);
+        meta.validate().unwrap();
+
+        // Entries with every feature in `features2` are split again
+        meta.features2.append(&mut meta.features);
+        meta.split_features();
+        assert_eq!(meta.features.keys().collect::<Vec<_>>(), ["default", "std"]);
+        assert_eq!(
+            meta.features2.keys().collect::<Vec<_>>(),
+            ["serde", "serde-std"]
+        );
+    }
+
+    #[test]
+    fn split_features_without_new_syntax() {
+        let mut meta = entry(serde_json::json!({ "default": ["serde"], "std": [] }));
+        meta.v = 2;
+        meta.split_features();
+        assert_eq!(meta.features.len(), 2);
+        assert!(meta.features2.is_empty());
+        assert_eq!(meta.v, 1);
+        let json = serde_json::to_value(&meta).unwrap();
+        assert!(json.get("features2").is_none());
+        assert_eq!(json["v"], 1);
+        meta.validate().unwrap();
+    }
+
+    #[test]
+    fn invalid_entries() {
+        for features in [
+            // Not optional
+            serde_json::json!({ "a": ["dep:bar"] }),
+            // Dev-dependencies cannot be enabled by features
+            serde_json::json!({ "a": ["dep:baz"] }),
+            serde_json::json!({ "a": ["baz/std"] }),
+            serde_json::json!({ "a": ["missing/std"] }),
+            serde_json::json!({ "a": ["missing"] }),
+            serde_json::json!({ "a b": [] }),
+        ] {
+            let mut meta = entry(features.clone());
+            meta.split_features();
+            assert!(meta.validate().is_err(), "{}", features);
+        }
+        let mut meta = entry(serde_json::json!({}));
+        meta.cksum = "xyz".into();
+        assert!(meta.validate().is_err());
+        let mut meta = entry(serde_json::json!({}));
+        meta.deps[1].registry = Some("crates-io".into());
+        assert!(meta.validate().is_err());
+    }
+
+    #[cfg(feature = "package")]
+    fn package(name: &str, deps: &[(&str, &str)]) -> cargo_metadata::Package {
+        let deps = deps
+            .iter()
+            .map(|(name, kind)| {
+                serde_json::json!({
+                    "name": name,
+                    "req": "*",
+                    "kind": kind,
+                    "optional": false,
+                    "uses_default_features": true,
+                    "features": [],
+                    "target": null,
+                    "rename": null,
+                    "registry": null,
+                    "source": null,
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "version": "0.1.0",
+            "id": format!("path+file:///ws/{}#0.1.0", name),
+            "dependencies": deps,
+            "targets": [],
+            "features": {},
+            "manifest_path": format!("/ws/{}/Cargo.toml", name),
+        }))
+        .unwrap()
+    }
+
+    #[cfg(feature = "package")]
+    fn order(packages: &[cargo_metadata::Package]) -> anyhow::Result<Vec<&str>> {
+        Ok(topological_order(packages.iter().collect())?
+            .into_iter()
+            .map(|p| p.name.as_str())
+            .collect())
+    }
+
+    #[cfg(feature = "package")]
+    #[test]
+    fn topological_order_dependencies_first() {
+        let packages = [
+            package("a", &[("b", "normal"), ("serde", "normal")]),
+            package("b", &[("c", "build")]),
+            package("c", &[]),
+            package("d", &[("a", "normal")]),
+            package("e", &[]),
+        ];
+        assert_eq!(order(&packages).unwrap(), ["c", "b", "a", "d", "e"]);
+        // The original order is kept otherwise
+        let packages = [package("x", &[]), package("y", &[]), package("z", &[])];
+        assert_eq!(order(&packages).unwrap(), ["x", "y", "z"]);
+    }
+
+    #[cfg(feature = "package")]
+    #[test]
+    fn topological_order_ignores_dev_and_self_dependencies() {
+        let packages = [
+            package("a", &[("b", "dev"), ("a", "normal")]),
+            package("b", &[("a", "normal")]),
+        ];
+        assert_eq!(order(&packages).unwrap(), ["a", "b"]);
+    }
+
+    #[cfg(feature = "package")]
+    #[test]
+    fn topological_order_cycle() {
+        let packages = [
+            package("a", &[]),
+            package("b", &[("c", "normal")]),
+            package("c", &[("b", "normal")]),
+        ];
+        let error = order(&packages).unwrap_err().to_string();
+        assert_eq!(error, "Dependency cycle between b, c");
+    }
+}
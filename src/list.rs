@@ -0,0 +1,60 @@
+//! Listing of the crates in the index, with their versions.
+use serde::Serialize;
+
+use crate::{IndexMeta, Registry};
+
+#[derive(Serialize)]
+pub struct ListedVersion<'a> {
+    pub name: &'a str,
+    pub version: String,
+    pub yanked: bool,
+    pub checksum: &'a str,
+}
+
+impl Registry {
+    /// Crates of the index, sorted by name, with their versions
+    pub fn iter_packages(
+        &self,
+    ) -> anyhow::Result<impl Iterator<Item = anyhow::Result<(String, Vec<IndexMeta>)>> + '_> {
+        Ok(self.package_names()?.into_iter().map(|name| {
+            let versions = self.read_package(&name)?;
+            Ok((name, versions))
+        }))
+    }
+}
+
+/// Print every version in the index, or only those of `name`, one per line or as JSON
+pub fn list(registry: &Registry, name: Option<&str>, json: bool) -> anyhow::Result<()> {
+    let packages: Vec<(String, Vec<IndexMeta>)> = match name {
+        Some(name) => {
+            let versions = registry.read_package(name)?;
+            anyhow::ensure!(!versions.is_empty(), "{} not found in the registry", name);
+            vec![(name.into(), versions)]
+        }
+        None => registry.iter_packages()?.collect::<anyhow::Result<_>>()?,
+    };
+    let listed: Vec<_> = packages
+        .iter()
+        .flat_map(|(_, versions)| versions)
+        .map(|m| ListedVersion {
+            name: &m.name,
+            version: m.vers.to_string(),
+            yanked: m.yanked,
+            checksum: &m.cksum,
+        })
+        .collect();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&listed)?);
+        return Ok(());
+    }
+    for l in listed {
+        println!(
+            "{} {} {}{}",
+            l.name,
+            l.version,
+            l.checksum,
+            if l.yanked { " (yanked)" } else { "" }
+        );
+    }
+    Ok(())
+}
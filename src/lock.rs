@@ -0,0 +1,62 @@
+//! Advisory lock on the registry, held during mutations so that concurrent processes (e.g. CI
+//! jobs publishing at the same time) do not interleave their changes. The lock is reentrant
+//! within a thread, so that mutations can be composed.
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use fs2::FileExt;
+use log::*;
+
+use crate::Registry;
+
+pub(crate) const LOCK: &str = ".lock";
+
+thread_local! {
+    /// Locks held by the current thread, with their number of guards
+    static HELD: RefCell<BTreeMap<PathBuf, (std::fs::File, usize)>> = RefCell::default();
+}
+
+/// Releases the lock when the last guard of the thread is dropped
+pub struct RegistryLock(PathBuf);
+
+impl Drop for RegistryLock {
+    fn drop(&mut self) {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some((_, count)) = held.get_mut(&self.0) {
+                *count -= 1;
+                if *count == 0 {
+                    // Closing the file releases the lock
+                    held.remove(&self.0);
+                }
+            }
+        });
+    }
+}
+
+impl Registry {
+    /// Take the lock on the registry, waiting for other processes and threads to release it
+    pub fn lock(&self) -> anyhow::Result<RegistryLock> {
+        let path = self.0.join(LOCK);
+        let reentered = HELD.with(|held| {
+            held.borrow_mut()
+                .get_mut(&path)
+                .map(|(_, count)| *count += 1)
+                .is_some()
+        });
+        if !reentered {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            if file.try_lock_exclusive().is_err() {
+                info!("Waiting for another process to release the registry");
+                file.lock_exclusive()?;
+            }
+            HELD.with(|held| held.borrow_mut().insert(path.clone(), (file, 1)));
+        }
+        Ok(RegistryLock(path))
+    }
+}
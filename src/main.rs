@@ -1,11 +1,17 @@
 use std::path::Path;
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use itertools::Itertools;
 use log::*;
 
-use cargo_depot::{FeaturesFlags, Registry};
+use cargo_depot::add_dep::DependencyKind;
+use cargo_depot::{
+    IndexConfig, OutputFormat, PackageFlags, PackageOutcome, PublishOutcome, Registry,
+};
+use cargo_metadata::semver::{Version, VersionReq};
+
+mod sources;
 
 #[derive(Parser)]
 #[command(name = "cargo")]
@@ -17,6 +23,15 @@ pub enum MainFlags {
 /// Create an maintain a simple cargo alternative registry.
 #[derive(Parser)]
 #[clap(about, version)]
+#[command(after_long_help = "Examples:
+  Initialize a registry:
+    cargo depot --registry /srv/registry --url https://depot.example.com init
+  Add all the packages of a workspace, at its current commit:
+    cargo depot --registry /srv/registry path/to/workspace
+  Add a release candidate of a single package:
+    cargo depot --registry /srv/registry publish -p foo --version 1.4.0-rc.1 path/to/workspace
+  Serve the registry locally:
+    cargo depot --registry /srv/registry serve --port 8000")]
 pub struct Flags {
     /// Local path to the registry
     #[clap(long)]
@@ -24,75 +39,1561 @@ pub struct Flags {
     /// URL of the registry, only needed for initialization
     #[clap(long)]
     url: Option<String>,
-    /// Paths to crates (local workspaces or HTTP links to tarballs).
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Paths to crates (local workspaces, .crate archives or directories of them, HTTP links to tarballs, git
+    /// repository URLs, oci://<image>#<path in image>, or file://<git repository>#<ref>).
     crates: Vec<String>,
+    /// Container engine used to extract oci:// sources
+    #[clap(long, default_value = "docker")]
+    container_engine: String,
+    /// Number of sources downloaded, and of workspace members packaged, concurrently. Packages
+    /// are still added one at a time, in order.
+    #[clap(long, short, default_value_t = 4)]
+    jobs: usize,
+    /// Directory for temporary files such as downloads and unpacked crates (by default, the
+    /// system temporary directory)
+    #[clap(long)]
+    tmpdir: Option<PathBuf>,
+    /// Keep the temporary files when the run fails, for debugging
+    #[clap(long)]
+    keep_tmp_on_failure: bool,
+    /// User agent of the HTTP requests (downloads, mirroring, object storage)
+    #[clap(long, default_value = cargo_depot::http::DEFAULT_USER_AGENT)]
+    user_agent: String,
+    /// Header added to the HTTP requests, as `Name: value`. Can be repeated.
+    #[clap(long = "header", value_name = "HEADER", value_parser = cargo_depot::http::parse_header)]
+    headers: Vec<(String, String)>,
     #[clap(flatten)]
-    features: FeaturesFlags,
+    package: PackageFlags,
+    #[clap(flatten)]
+    selection: Selection,
+    /// Print a JSON description of the subcommands and their arguments, e.g. to generate forms
+    /// invoking them, and exit. Does not require `--registry`.
+    #[clap(long, exclusive = true)]
+    dump_command_schema: bool,
 }
 
-fn process_workspace(
-    workspace: impl AsRef<Path>,
-    registry: &Registry,
-    features: &FeaturesFlags,
-) -> anyhow::Result<()> {
-    let workspace = workspace.as_ref();
-    info!("Processing workspace {:?}", workspace);
-    let metadata = cargo_metadata::MetadataCommand::new()
+#[derive(clap::Parser)]
+struct InitFlags {
+    /// Ask for the settings instead of taking them from the flags
+    #[clap(long)]
+    interactive: bool,
+    /// Name of the registry in the printed cargo configuration [default: depot]
+    #[clap(long)]
+    name: Option<String>,
+    /// Record the history of the index in a git repository
+    #[clap(long)]
+    git: bool,
+    /// Push the index repository to this remote after each change
+    #[clap(long, requires = "git", value_name = "URL")]
+    git_remote: Option<String>,
+    #[clap(flatten)]
+    settings: IndexSettings,
+}
+
+impl InitFlags {
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("depot")
+    }
+}
+
+/// Optional fields of config.json
+#[derive(clap::Parser)]
+struct IndexSettings {
+    /// URL of the web API used by `cargo publish` and `cargo yank`
+    #[clap(long, value_name = "URL")]
+    api_url: Option<String>,
+    /// Require cargo to send a token with every request, including downloads
+    #[clap(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+    auth_required: Option<bool>,
+}
+impl IndexSettings {
+    fn is_empty(&self) -> bool {
+        self.api_url.is_none() && self.auth_required.is_none()
+    }
+    fn apply(&self, config: &mut IndexConfig) {
+        if let Some(api) = &self.api_url {
+            config.api = Some(api.trim_end_matches('/').into());
+        }
+        if let Some(auth_required) = self.auth_required {
+            config.auth_required = auth_required;
+        }
+    }
+}
+
+/// Which workspace members to publish
+#[derive(clap::Parser)]
+struct Selection {
+    /// Only publish the workspace's `default-members`
+    #[clap(long)]
+    default_members_only: bool,
+    /// Only publish these packages (by default, all publishable packages). Accepts globs, e.g.
+    /// `'serde_*'`. Can be repeated.
+    #[clap(short, long, value_name = "SPEC")]
+    package: Vec<String>,
+    /// Do not publish these packages. Accepts globs. Can be repeated.
+    #[clap(long, value_name = "SPEC")]
+    exclude: Vec<String>,
+}
+impl Selection {
+    fn patterns(specs: &[String]) -> anyhow::Result<Vec<glob::Pattern>> {
+        Ok(specs
+            .iter()
+            .map(|s| glob::Pattern::new(s))
+            .collect::<Result<_, _>>()?)
+    }
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Initialize the registry and print the configuration to use it from cargo
+    Init(InitFlags),
+    /// Update the index configuration (config.json), or print it when no setting is given
+    Config {
+        #[clap(flatten)]
+        settings: IndexSettings,
+        /// Stop advertising an API
+        #[clap(long, conflicts_with = "api_url")]
+        no_api: bool,
+    },
+    /// Print the configuration to use the registry from cargo, or add it to a configuration file
+    ConfigSnippet {
+        /// Name of the registry for cargo [default: the name in depot.toml, or depot]
+        #[clap(long)]
+        name: Option<String>,
+        /// Use the sparse protocol even if the index is pushed to a git remote
+        #[clap(long)]
+        sparse: bool,
+        /// Replace crates.io with the registry, e.g. for an offline mirror, instead of declaring
+        /// an alternative registry
+        #[clap(long)]
+        replace_crates_io: bool,
+        /// Add the configuration to this file, e.g. `.cargo/config.toml`, keeping its other
+        /// settings
+        #[clap(long, value_name = "PATH")]
+        write: Option<PathBuf>,
+    },
+    /// Browse the registry in an interactive terminal interface
+    Tui,
+    /// Serve the registry over HTTP
+    Serve {
+        #[clap(long, default_value = "127.0.0.1")]
+        address: String,
+        #[clap(long, default_value_t = 8000)]
+        port: u16,
+        /// Accept `cargo publish` and `cargo yank` requests, authorized with tokens created with
+        /// `token create`
+        #[clap(long)]
+        allow_publish: bool,
+        /// Maximum size of a published crate, in MiB. Larger uploads are rejected with a 413.
+        #[clap(long, value_name = "MIB", default_value_t = 10)]
+        max_upload_size: u64,
+    },
+    /// Publish packages from a local workspace
+    Publish {
+        /// Path to the workspace
+        #[clap(default_value = ".")]
+        workspace: PathBuf,
+        /// Publish under this version instead of the one in the manifest, without modifying the
+        /// sources
+        #[clap(long, requires = "package")]
+        version: Option<Version>,
+        /// Publish under this name instead of the one in the manifest, e.g. for a fork of a
+        /// crates.io crate. Requires selecting a single package.
+        #[clap(long, value_name = "NAME", requires = "package")]
+        rename_to: Option<String>,
+        #[clap(flatten)]
+        flags: PackageFlags,
+        #[clap(flatten)]
+        selection: Selection,
+    },
+    /// Show which members of a workspace are published, unpublished, or published with
+    /// different contents
+    Status {
+        /// Path to the workspace
+        #[clap(default_value = ".")]
+        workspace: PathBuf,
+        #[clap(flatten)]
+        flags: PackageFlags,
+        #[clap(flatten)]
+        selection: Selection,
+    },
+    /// Store prebuilt binaries for a crate, under dist/<crate>/<version>/<target>/
+    Dist {
+        name: String,
+        version: Version,
+        /// Target triple, e.g. x86_64-unknown-linux-gnu
+        target: String,
+        /// Binaries or archives to store
+        #[clap(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Show the files that changed between two versions of a crate
+    DiffCrate {
+        name: String,
+        old: Version,
+        new: Version,
+        /// Also print a line-level diff of the modified files
+        #[clap(long)]
+        lines: bool,
+    },
+    /// Show the public API changes between two versions of a crate, using rustdoc's JSON output
+    /// (requires a nightly toolchain)
+    ApiDiff {
+        name: String,
+        old: Version,
+        new: Version,
+        /// Store the report next to the archive of the new version
+        #[clap(long)]
+        store: bool,
+    },
+    /// List the crates in the index, with their versions, yanked status and checksums
+    List {
+        /// Only list the versions of this crate
+        #[clap(long = "crate")]
+        name: Option<String>,
+        #[clap(long)]
+        json: bool,
+    },
+    /// Check whether the [dependencies] of a manifest-like file resolve from the registry, and
+    /// print the chosen versions
+    Resolve {
+        spec: PathBuf,
+        /// Resolve the crates.io dependencies of the registry packages, and the dependencies with
+        /// `registry = "crates-io"`, from crates.io instead of the registry
+        #[clap(long)]
+        crates_io: bool,
+    },
+    /// Show the details of a crate, by default of its latest version
+    Info {
+        name: String,
+        version: Option<Version>,
+    },
+    /// Mark a version as yanked, so that it is not used for new lockfiles
+    Yank {
+        /// <crate>@<version>
+        #[clap(value_parser = parse_crate_version)]
+        spec: (String, Version),
+    },
+    /// Undo a yank
+    Unyank {
+        /// <crate>@<version>
+        #[clap(value_parser = parse_crate_version)]
+        spec: (String, Version),
+    },
+    /// Delete a version (archive and index entry), e.g. after accidentally publishing secrets.
+    /// Prefer `yank`, as builds locked to the version will break.
+    Remove {
+        /// <crate>@<version>, or <crate> with --purge-crate
+        spec: String,
+        /// Delete every version of the crate
+        #[clap(long)]
+        purge_crate: bool,
+    },
+    /// Exempt a version from cleanup, e.g. pruning
+    Pin {
+        /// <crate>@<version>
+        #[clap(value_parser = parse_crate_version)]
+        spec: (String, Version),
+    },
+    /// Remove a pin added with `pin`
+    Unpin {
+        /// <crate>@<version>
+        #[clap(value_parser = parse_crate_version)]
+        spec: (String, Version),
+    },
+    /// Only allow the given tokens to download a crate when serving
+    Restrict {
+        name: String,
+        /// Names of the tokens, created with `token create`
+        #[clap(required = true)]
+        tokens: Vec<String>,
+    },
+    /// Remove the restrictions added with `restrict`
+    Unrestrict { name: String },
+    /// Report the storage used by the teams with a quota, or manage the quotas
+    Quota {
+        #[command(subcommand)]
+        action: Option<Quota>,
+        /// Print the usage as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Manage the tokens authorizing publishing and yanking through `serve`
+    #[command(subcommand)]
+    Token(Token),
+    /// Manage named versions of a crate, e.g. `stable`
+    #[command(subcommand)]
+    Alias(Alias),
+    /// Print the version a crate alias points to, by default the latest one
+    Latest {
+        name: String,
+        #[clap(long, default_value = cargo_depot::aliases::LATEST)]
+        alias: String,
+        /// Print the download URL instead of the version
+        #[clap(long)]
+        download_url: bool,
+    },
+    /// Add a dependency on a crate of the registry to a manifest, with its `registry` key and the
+    /// latest compatible version
+    AddDep {
+        /// <crate>, or <crate>@<requirement> to pick the latest version matching it
+        #[clap(value_parser = parse_crate_req)]
+        spec: (String, Option<VersionReq>),
+        #[clap(long, default_value = "Cargo.toml")]
+        manifest_path: PathBuf,
+        /// Name of the registry in the cargo configuration [default: the name in depot.toml, or
+        /// depot]
+        #[clap(long)]
+        registry_name: Option<String>,
+        /// Add as a development dependency
+        #[clap(long, conflicts_with = "build")]
+        dev: bool,
+        /// Add as a build dependency
+        #[clap(long)]
+        build: bool,
+        /// Features to enable, comma-separated
+        #[clap(short = 'F', long, value_delimiter = ',')]
+        features: Vec<String>,
+        /// Mark the dependency as optional
+        #[clap(long, conflicts_with = "dev")]
+        optional: bool,
+    },
+    /// Check every index entry against cargo's rules
+    Validate,
+    /// Generate a minisign key to sign the archives, encrypted with the password of the
+    /// CARGO_DEPOT_SIGNING_PASSWORD environment variable if set
+    GenerateSigningKey {
+        /// Where to write the secret key, to be set as `signing-key` in depot.toml
+        path: PathBuf,
+    },
+    /// Check the archives against the checksums of the index
+    Verify {
+        /// Only verify this crate
+        name: Option<String>,
+        /// Also verify the signatures of the archives with the public key of the registry
+        #[clap(long)]
+        signatures: bool,
+        /// Print the report as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Summarize the consistency of the registry, failing if it is not healthy
+    Health {
+        /// Print the summary as JSON, for monitoring
+        #[clap(long)]
+        json: bool,
+    },
+    /// Extract the licence, notice and README files of every version into a directory, for
+    /// compliance archives. Versions already extracted are skipped.
+    ExtractLegal { outdir: PathBuf },
+    /// Rewrite the index in the current format and check every archive, e.g. after upgrading
+    /// cargo-depot, and point the registry to --url if given. Fails if problems are found.
+    Migrate {
+        /// Only report what would change
+        #[clap(long)]
+        dry_run: bool,
+        /// Print the report as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Upgrade a registry created by an older version of cargo-depot to the current format
+    UpgradeRegistry {
+        /// Only report what would change
+        #[clap(long)]
+        dry_run: bool,
+        /// Print the report as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Generate reports over the whole registry
+    #[command(subcommand)]
+    Report(Report),
+    /// Print a software bill of materials of the registry: every version with its checksum,
+    /// license and declared dependencies
+    Sbom {
+        #[clap(long, value_enum, default_value = "cyclonedx")]
+        format: cargo_depot::sbom::SbomFormat,
+    },
+    /// Replicate a remote registry, fetching only what is missing
+    Pull {
+        /// URL where the remote registry is served
+        remote: String,
+        /// What to do with versions present on both sides with different checksums
+        #[clap(long, value_enum, default_value = "fail")]
+        on_conflict: cargo_depot::pull::ConflictPolicy,
+        /// Pre-release suffix used by --on-conflict rename-with-suffix
+        #[clap(long, default_value = "theirs")]
+        conflict_suffix: String,
+    },
+    /// Add the crates.io packages locked in a Cargo.lock, e.g. for air-gapped builds
+    Mirror {
+        #[clap(long)]
+        lockfile: PathBuf,
+        /// Sparse index to download the packages from, e.g. a crates.io mirror
+        /// [default: https://index.crates.io/]
+        #[clap(long)]
+        upstream: Option<String>,
+        /// Also fetch the description, links and download counts of the crates from the web API
+        /// of the upstream registry, at most one request per second
+        #[clap(long)]
+        upstream_metadata: bool,
+    },
+    /// Add a single version of a crate from crates.io, verifying its published checksum
+    MirrorCrate {
+        /// <crate>@<version>
+        #[clap(value_parser = parse_crate_version)]
+        spec: (String, Version),
+        /// Sparse index to download the crate from, e.g. a crates.io mirror
+        /// [default: https://index.crates.io/]
+        #[clap(long)]
+        upstream: Option<String>,
+        /// Also fetch the description, links and download counts of the crate from the web API
+        /// of the upstream registry
+        #[clap(long)]
+        upstream_metadata: bool,
+    },
+    /// Download again the versions that exist upstream, and check that they are identical to
+    /// the archives of the registry. Fails if any differs.
+    VerifyUpstream {
+        /// Only verify this crate
+        #[clap(long = "crate")]
+        name: Option<String>,
+        /// Sparse index to compare with [default: https://index.crates.io/]
+        #[clap(long)]
+        upstream: Option<String>,
+        /// Print the report as JSON
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+impl Flags {
+    /// Fill in the defaults of `depot.toml` for the flags not given on the command line
+    fn apply_settings(&mut self, settings: &cargo_depot::settings::Settings) {
+        if self.url.is_none() {
+            self.url.clone_from(&settings.url);
+        }
+        self.package.apply_defaults(&settings.package);
+        match &mut self.command {
+            Some(Command::Init(flags)) if flags.name.is_none() => {
+                flags.name.clone_from(&settings.name);
+            }
+            Some(
+                Command::AddDep {
+                    registry_name: name,
+                    ..
+                }
+                | Command::ConfigSnippet { name, .. },
+            ) if name.is_none() => {
+                name.clone_from(&settings.name);
+            }
+            Some(Command::Publish { flags, .. } | Command::Status { flags, .. }) => {
+                flags.apply_defaults(&settings.package);
+            }
+            Some(
+                Command::Mirror {
+                    upstream,
+                    upstream_metadata,
+                    ..
+                }
+                | Command::MirrorCrate {
+                    upstream,
+                    upstream_metadata,
+                    ..
+                },
+            ) => {
+                if upstream.is_none() {
+                    upstream.clone_from(&settings.upstream);
+                }
+                *upstream_metadata |= settings.upstream_metadata;
+            }
+            Some(Command::VerifyUpstream { upstream, .. }) if upstream.is_none() => {
+                upstream.clone_from(&settings.upstream);
+            }
+            _ => {}
+        }
+    }
+}
+
+const CRATES_IO_INDEX: &str = "https://index.crates.io/";
+
+fn upstream_or_default(upstream: &Option<String>) -> &str {
+    upstream.as_deref().unwrap_or(CRATES_IO_INDEX)
+}
+
+/// Ask a question on the terminal, returning the default on an empty answer
+fn prompt(question: &str, default: Option<&str>) -> anyhow::Result<String> {
+    loop {
+        match default {
+            Some(default) => eprint!("{} [{}]: ", question, default),
+            None => eprint!("{}: ", question),
+        }
+        let mut answer = String::new();
+        anyhow::ensure!(
+            std::io::stdin().read_line(&mut answer)? > 0,
+            "No answer to {:?}",
+            question
+        );
+        match (answer.trim(), default) {
+            ("", Some(default)) => return Ok(default.into()),
+            ("", None) => continue,
+            (answer, _) => return Ok(answer.into()),
+        }
+    }
+}
+
+fn init(registry: &Path, url: Option<&str>, flags: &InitFlags) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !registry.join("index").join("config.json").exists(),
+        "{:?} is already initialized",
+        registry
+    );
+    let (url, name) = if flags.interactive {
+        (
+            prompt("URL where the registry will be served", url)?,
+            prompt("Name of the registry for cargo", Some(flags.name()))?,
+        )
+    } else {
+        let Some(url) = url else {
+            anyhow::bail!("Provide the URL where the registry will be hosted with the --url flag, or in depot.toml");
+        };
+        (url.to_string(), flags.name().to_string())
+    };
+    let registry = Registry::open(registry, Some(&url))?;
+    if !flags.settings.is_empty() {
+        let mut config = registry.config()?;
+        flags.settings.apply(&mut config);
+        registry.write_config(&config)?;
+    }
+    if flags.git {
+        registry.init_git(flags.git_remote.as_deref())?;
+    }
+    println!("Add the following to .cargo/config.toml to use the registry:\n");
+    let config = cargo_depot::cargo_config::CargoConfig {
+        name,
+        index: cargo_depot::cargo_config::sparse_index(&url),
+        replace_crates_io: false,
+    };
+    print!("{}", config);
+    if let Some(remote) = &flags.git_remote {
+        println!("# or, with the git protocol:\n# index = \"{}\"", remote);
+    }
+    Ok(())
+}
+
+/// Parse `<crate>` or `<crate>@<requirement>`
+fn parse_crate_req(spec: &str) -> anyhow::Result<(String, Option<VersionReq>)> {
+    Ok(match spec.split_once('@') {
+        Some((name, req)) => (name.into(), Some(req.parse()?)),
+        None => (spec.into(), None),
+    })
+}
+
+/// Parse `<crate>@<version>`
+fn parse_crate_version(spec: &str) -> anyhow::Result<(String, Version)> {
+    let Some((name, version)) = spec.split_once('@') else {
+        anyhow::bail!("Expected <crate>@<version>");
+    };
+    Ok((name.into(), version.parse()?))
+}
+
+#[derive(clap::Subcommand)]
+enum Token {
+    /// Create a token and print it
+    Create { name: String },
+    /// Revoke a token
+    Revoke { name: String },
+    /// List the names of the tokens
+    List,
+}
+
+#[derive(clap::Subcommand)]
+enum Quota {
+    /// Create or replace the quota of a team, enforced when adding versions of its crates
+    Set {
+        team: String,
+        /// Name or glob of the crates of the team, e.g. `infra-*`. Can be repeated.
+        #[clap(long = "crate", value_name = "GLOB", required = true)]
+        crates: Vec<String>,
+        /// Maximum size of the files of these crates, in MiB
+        #[clap(long, value_name = "MIB")]
+        limit: u64,
+        /// Only warn when a version exceeds the quota, instead of rejecting it
+        #[clap(long)]
+        warn_only: bool,
+    },
+    /// Remove the quota of a team
+    Remove { team: String },
+}
+
+#[derive(clap::Subcommand)]
+enum Alias {
+    /// Point an alias to a version
+    Set {
+        /// <crate>@<version>
+        #[clap(value_parser = parse_crate_version)]
+        spec: (String, Version),
+        alias: String,
+    },
+    /// Remove an alias
+    Remove { name: String, alias: String },
+    /// List the aliases of a crate
+    List { name: String },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum Format {
+    Csv,
+    Json,
+}
+
+#[derive(clap::Subcommand)]
+enum Report {
+    /// Licenses of all the versions in the registry, flagging missing and non-SPDX ones
+    Licenses {
+        #[clap(long, value_enum, default_value = "csv")]
+        format: Format,
+        /// Include the registry dependencies of each version, recursively
+        #[clap(long)]
+        dependencies: bool,
+    },
+}
+
+/// Member names or globs to skip, from `.depotignore` at the root of the workspace and
+/// `[workspace.metadata.depot] exclude`.
+fn ignored_members(metadata: &cargo_metadata::Metadata) -> anyhow::Result<Vec<glob::Pattern>> {
+    let mut patterns = vec![];
+    let ignore_file = metadata.workspace_root.join(".depotignore");
+    if ignore_file.exists() {
+        for line in std::fs::read_to_string(ignore_file)?.lines() {
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                patterns.push(line.to_string());
+            }
+        }
+    }
+    if let Some(exclude) = metadata.workspace_metadata["depot"]["exclude"].as_array() {
+        for pattern in exclude {
+            let Some(pattern) = pattern.as_str() else {
+                anyhow::bail!("workspace.metadata.depot.exclude should be a list of strings");
+            };
+            patterns.push(pattern.into());
+        }
+    }
+    Ok(patterns
+        .iter()
+        .map(|p| glob::Pattern::new(p))
+        .collect::<Result<_, _>>()?)
+}
+
+fn workspace_metadata(
+    workspace: &Path,
+    flags: &PackageFlags,
+) -> anyhow::Result<cargo_metadata::Metadata> {
+    if flags.no_cargo {
+        return cargo_depot::builtin::metadata(workspace);
+    }
+    Ok(cargo_metadata::MetadataCommand::new()
         .manifest_path("Cargo.toml")
         .current_dir(workspace)
-        .exec()?;
-    let packages = metadata
-        .workspace_packages()
+        .other_options(flags.cargo.args())
+        .exec()?)
+}
+
+/// Publishable members of the workspace, restricted to `packages` if non-empty
+fn select_packages<'a>(
+    metadata: &'a cargo_metadata::Metadata,
+    selection: &Selection,
+) -> anyhow::Result<Vec<&'a cargo_metadata::Package>> {
+    let candidates = if selection.default_members_only {
+        anyhow::ensure!(
+            !cargo_metadata::workspace_default_members_is_missing(
+                &metadata.workspace_default_members
+            ),
+            "--default-members-only requires cargo 1.71 or newer"
+        );
+        metadata.workspace_default_packages()
+    } else {
+        metadata.workspace_packages()
+    };
+    let ignored = ignored_members(metadata)?;
+    let packages = Selection::patterns(&selection.package)?;
+    let excluded = Selection::patterns(&selection.exclude)?;
+    let publishable = candidates
         .into_iter()
         .filter(|p| p.publish.as_ref().map_or(true, |v| !v.is_empty()))
         .collect_vec();
+    // Like cargo, fail on patterns matching no package
+    for pattern in packages.iter().chain(&excluded) {
+        anyhow::ensure!(
+            publishable.iter().any(|p| pattern.matches(&p.name)),
+            "Package {} not found in the workspace, or not publishable",
+            pattern
+        );
+    }
+    let selected = publishable
+        .into_iter()
+        .filter(|p| packages.is_empty() || packages.iter().any(|g| g.matches(&p.name)))
+        .filter(|p| !excluded.iter().any(|g| g.matches(&p.name)))
+        .filter(|p| {
+            // Explicitly selected packages are never ignored
+            let skip = packages.is_empty() && ignored.iter().any(|g| g.matches(&p.name));
+            if skip {
+                info!(
+                    "Skipping {}, excluded by the workspace configuration",
+                    p.name
+                );
+            }
+            !skip
+        })
+        .collect_vec();
+    Ok(selected)
+}
+
+/// Print, for each member of the workspace, whether its current version is already in the
+/// registry, and if so whether the contents match.
+fn status(
+    workspace: &Path,
+    registry: &Registry,
+    flags: &PackageFlags,
+    selection: &Selection,
+) -> anyhow::Result<()> {
+    let metadata = workspace_metadata(workspace, flags)?;
+    let mut rows = vec![];
+    for p in select_packages(&metadata, selection)? {
+        let added = cargo_depot::renamed(p, &metadata)?;
+        let status = if !cargo_depot::can_be_added(p, &metadata, flags)? {
+            "not a library"
+        } else if !registry.crate_path(&added.name, &added.version).exists() {
+            "unpublished"
+        } else {
+            let local = cargo_depot::content_hash(&cargo_depot::package(
+                p,
+                &metadata,
+                flags,
+                metadata.target_directory.as_std_path(),
+            )?)?;
+            let published =
+                cargo_depot::content_hash(&registry.crate_path(&added.name, &added.version))?;
+            if local == published {
+                "published"
+            } else {
+                "published with different content"
+            }
+        };
+        rows.push((format!("{} {}", added.name, added.version), status));
+    }
+    let width = rows.iter().map(|(p, _)| p.len()).max().unwrap_or(0);
+    for (package, status) in rows {
+        println!("{:<width$}  {}", package, status);
+    }
+    Ok(())
+}
+
+/// Summarize what was done with each package, after the logs
+fn print_outcomes(outcomes: &[PackageOutcome], output: OutputFormat) -> anyhow::Result<()> {
+    if output == OutputFormat::Json {
+        for o in outcomes {
+            println!("{}", serde_json::to_string(o)?);
+        }
+        return Ok(());
+    }
+    let rows = outcomes
+        .iter()
+        .map(|o| {
+            let outcome = match o.outcome {
+                PublishOutcome::Added { .. } => "added",
+                PublishOutcome::Replaced { .. } => "replaced",
+                PublishOutcome::DryRun { .. } => "validated (dry run)",
+                PublishOutcome::SkippedExisting => "skipped, already in the registry",
+                PublishOutcome::SkippedNonLibrary => "skipped, not a library",
+            };
+            (format!("{} {}", o.name, o.version), outcome)
+        })
+        .collect_vec();
+    let width = rows.iter().map(|(p, _)| p.len()).max().unwrap_or(0);
+    for (package, outcome) in rows {
+        println!("{:<width$}  {}", package, outcome);
+    }
+    Ok(())
+}
+
+/// Exit if packaging or selection flags are given before a subcommand, which only reads the
+/// flags given after it, e.g. `--dry-run publish` would publish
+fn reject_misplaced_flags(matches: &clap::ArgMatches) {
+    let Some((_, depot)) = matches.subcommand() else {
+        return;
+    };
+    let Some((subcommand, _)) = depot.subcommand() else {
+        return;
+    };
+    let flags = <PackageFlags as clap::Args>::augment_args(clap::Command::new("package"));
+    let selection = <Selection as clap::Args>::augment_args(clap::Command::new("selection"));
+    let misplaced = flags
+        .get_arguments()
+        .chain(selection.get_arguments())
+        .filter(|a| {
+            depot.value_source(a.get_id().as_str()) == Some(clap::parser::ValueSource::CommandLine)
+        })
+        .map(|a| format!("--{}", a.get_long().unwrap_or(a.get_id().as_str())))
+        .collect_vec();
+    if !misplaced.is_empty() {
+        Flags::command()
+            .name("depot")
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!(
+                    "{} must be given after the {} subcommand",
+                    misplaced.join(", "),
+                    subcommand
+                ),
+            )
+            .exit();
+    }
+}
+
+fn print_migration(report: &cargo_depot::migrate::MigrationReport) {
+    println!("Crates: {}", report.crates);
+    println!("Versions: {}", report.versions);
+    println!("Configuration updated: {}", report.config_updated);
+    println!(
+        "Rewritten index files: {}",
+        report.rewritten_index_files.len()
+    );
+    for (title, problems) in [
+        ("Invalid index entries", &report.invalid_entries),
+        ("Missing archives", &report.missing_archives),
+        ("Checksum mismatches", &report.checksum_mismatches),
+    ] {
+        println!("{}: {}", title, problems.len());
+        for problem in problems {
+            println!("  {}", problem);
+        }
+    }
+}
+
+fn process_workspace(
+    workspace: impl AsRef<Path>,
+    registry: &Registry,
+    flags: &PackageFlags,
+    selection: &Selection,
+    version: Option<&Version>,
+    rename_to: Option<&str>,
+    jobs: usize,
+) -> anyhow::Result<Vec<PackageOutcome>> {
+    let workspace = workspace.as_ref();
+    info!("Processing workspace {:?}", workspace);
+    registry.preflight(workspace, flags)?;
+    let mut metadata = workspace_metadata(workspace, flags)?;
+    if let Some(name) = rename_to {
+        let [p] = select_packages(&metadata, selection)?[..] else {
+            anyhow::bail!("--rename-to requires selecting a single package");
+        };
+        let p = p.name.clone();
+        // As a workspace override, which takes precedence over the package metadata
+        metadata.workspace_metadata["depot"]["packages"][p.as_str()]["rename-to"] = name.into();
+    }
+    let selected = select_packages(&metadata, selection)?;
     info!(
         "Found {} packages: {}",
-        packages.len(),
-        packages.iter().map(|p| &p.name).join(", ")
+        selected.len(),
+        selected.iter().map(|p| &p.name).join(", ")
     );
-    for p in packages {
-        info!("Processing {}", p.name);
-        registry.add_package(p, &metadata, features)?;
+    let selected = selected
+        .into_iter()
+        .map(|p| {
+            let mut p = p.clone();
+            if let Some(version) = version {
+                info!(
+                    "Overriding version {} of {} with {}",
+                    p.version, p.name, version
+                );
+                p.version = version.clone();
+            }
+            p
+        })
+        .collect_vec();
+    // Check all the versions before publishing anything
+    for p in &selected {
+        flags.check_expected_version(p)?;
     }
-    Ok(())
+    Ok(registry.add_packages(&selected, &metadata, flags, jobs)?)
 }
 
-fn main_impl() -> anyhow::Result<()> {
-    let MainFlags::Depot(args) = MainFlags::parse();
+/// Type of the values of an argument, for `--dump-command-schema`
+fn value_type(arg: &clap::Arg) -> &'static str {
+    use std::any::TypeId;
+    match arg.get_action() {
+        clap::ArgAction::SetTrue | clap::ArgAction::SetFalse => return "boolean",
+        clap::ArgAction::Count => return "count",
+        _ => {}
+    }
+    if !arg.get_possible_values().is_empty() {
+        return "enum";
+    }
+    let id = arg.get_value_parser().type_id();
+    if [
+        TypeId::of::<u16>(),
+        TypeId::of::<u64>(),
+        TypeId::of::<usize>(),
+    ]
+    .iter()
+    .any(|t| id == *t)
+    {
+        "integer"
+    } else if id == TypeId::of::<PathBuf>() {
+        "path"
+    } else if id == TypeId::of::<Version>() {
+        "version"
+    } else if id == TypeId::of::<(String, Version)>() {
+        "crate@version"
+    } else if id == TypeId::of::<(String, Option<VersionReq>)>() {
+        "crate[@requirement]"
+    } else {
+        "string"
+    }
+}
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+/// Description of a command, its arguments and its subcommands, for `--dump-command-schema`
+fn command_schema(command: &clap::Command) -> serde_json::Value {
+    let arguments = command
+        .get_arguments()
+        .filter(|a| {
+            !a.is_hide_set()
+                && !matches!(
+                    a.get_action(),
+                    clap::ArgAction::Help | clap::ArgAction::Version
+                )
+        })
+        .map(|a| {
+            serde_json::json!({
+                "name": a.get_id().as_str(),
+                "long": a.get_long(),
+                "short": a.get_short(),
+                "help": a.get_help().map(|h| h.to_string()),
+                "type": value_type(a),
+                "value_name": a
+                    .get_value_names()
+                    .and_then(|n| n.first())
+                    .filter(|_| a.get_action().takes_values())
+                    .map(|n| n.as_str()),
+                "possible_values": a
+                    .get_possible_values()
+                    .iter()
+                    .map(|v| v.get_name())
+                    .collect_vec(),
+                "default": a
+                    .get_default_values()
+                    .iter()
+                    .map(|v| v.to_string_lossy())
+                    .collect_vec(),
+                "required": a.is_required_set(),
+                "multiple": matches!(a.get_action(), clap::ArgAction::Append),
+                "positional": a.is_positional(),
+            })
+        })
+        .collect_vec();
+    serde_json::json!({
+        "name": command.get_name(),
+        "about": command.get_about().map(|a| a.to_string()),
+        "arguments": arguments,
+        "subcommands": command
+            .get_subcommands()
+            .filter(|c| c.get_name() != "help")
+            .map(command_schema)
+            .collect_vec(),
+    })
+}
 
-    let registry = Registry::open(&args.registry, args.url.as_deref())?;
-
-    for c in &args.crates {
-        if c.starts_with("https://") || c.starts_with("http://") {
-            info!("Downloading from {}", c);
-            let tar = flate2::read::GzDecoder::new(ureq::get(c).call()?.into_reader());
-            let mut archive = tar::Archive::new(tar);
-            let output = tempfile::tempdir()?;
-            archive.unpack(&output)?;
-            // Find the workspace
-            let Some(workspace) = std::fs::read_dir(&output)?
-                .filter_map(|d| d.ok())
-                .filter(|d| d.path().join("Cargo.toml").exists())
-                .map(|d| d.path())
-                .next()
-            else {
-                anyhow::bail!("Failed to find cargo workspace at the first level of the tarball");
-            };
-            process_workspace(workspace, &registry, &args.features)?;
+/// Look for an external `cargo-depot-<cmd>` subcommand, in the same way as cargo does for
+/// `cargo-<cmd>`. Returns the position of `<cmd>` in the arguments and the path to the binary.
+fn find_plugin(args: &[String]) -> Option<(usize, PathBuf)> {
+    let command = Flags::command();
+    let mut i = 2;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg == "--" {
+            return None;
+        }
+        if let Some(long) = arg.strip_prefix("--") {
+            // Skip the value of options that take one
+            if !long.contains('=')
+                && command
+                    .get_arguments()
+                    .any(|a| a.get_long() == Some(long) && a.get_action().takes_values())
+            {
+                i += 1;
+            }
+        } else if let Some(shorts) = arg.strip_prefix('-') {
+            // Skip the value of a short option ending the group, e.g. `-j 4` but not `-j4`
+            for (j, c) in shorts.char_indices() {
+                if command
+                    .get_arguments()
+                    .any(|a| a.get_short() == Some(c) && a.get_action().takes_values())
+                {
+                    if j + c.len_utf8() == shorts.len() {
+                        i += 1;
+                    }
+                    break;
+                }
+            }
         } else {
-            process_workspace(c, &registry, &args.features)?;
+            // Built-in subcommands and crate sources take precedence over plugins
+            let builtin = arg == "help"
+                || command
+                    .get_subcommands()
+                    .any(|s| s.get_name() == arg || s.get_all_aliases().any(|a| a == arg));
+            if builtin || arg.contains("://") || Path::new(arg).exists() {
+                return None;
+            }
+            let binary = format!("cargo-depot-{}", arg);
+            let path = std::env::var_os("PATH")?;
+            return std::env::split_paths(&path)
+                .map(|dir| dir.join(&binary))
+                .find(|p| p.is_file())
+                .map(|p| (i, p));
         }
+        i += 1;
     }
+    None
+}
 
-    info!("Done");
+/// Run an external subcommand, passing the registry configuration via environment variables.
+fn run_plugin(args: &[String], position: usize, plugin: &Path) -> anyhow::Result<()> {
+    let matches = MainFlags::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&args[..position])?;
+    let mut command = std::process::Command::new(plugin);
+    command
+        .args(&args[position + 1..])
+        .env("CARGO_DEPOT", std::env::current_exe()?);
+    if let Some(matches) = matches.subcommand_matches("depot") {
+        if let Some(registry) = matches.get_one::<PathBuf>("registry") {
+            // Remote registries, e.g. s3://, are passed as is
+            match registry.to_str().filter(|r| r.contains("://")) {
+                Some(url) => command.env("CARGO_DEPOT_REGISTRY", url),
+                None => command.env("CARGO_DEPOT_REGISTRY", std::path::absolute(registry)?),
+            };
+        }
+        if let Some(url) = matches.get_one::<String>("url") {
+            command.env("CARGO_DEPOT_URL", url);
+        }
+    }
+    let status = command.status()?;
+    std::process::exit(status.code().unwrap_or(2));
+}
+
+fn main_impl() -> anyhow::Result<()> {
+    let raw_args = std::env::args().collect_vec();
+    if let Some((position, plugin)) = find_plugin(&raw_args) {
+        return run_plugin(&raw_args, position, &plugin);
+    }
+
+    let matches = MainFlags::command().get_matches();
+    // Before building the flags, which require --registry
+    if matches
+        .subcommand_matches("depot")
+        .is_some_and(|m| m.get_flag("dump_command_schema"))
+    {
+        let mut command = Flags::command().name("depot");
+        command.build();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&command_schema(&command))?
+        );
+        return Ok(());
+    }
+    let MainFlags::Depot(mut args) =
+        MainFlags::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    reject_misplaced_flags(&matches);
+
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
+    cargo_depot::temp::init(args.tmpdir.as_deref(), args.keep_tmp_on_failure)?;
+    cargo_depot::interrupt::install()?;
+    cargo_depot::http::init(&args.user_agent, args.headers.clone());
+
+    // Remote registries are checked out locally, and the changes uploaded on success
+    let mut checkout = match args.registry.to_str().filter(|r| r.starts_with("s3://")) {
+        Some(url) => Some(cargo_depot::backend::Checkout::new(Box::new(
+            cargo_depot::backend::S3::from_url(url)?,
+        ))?),
+        None => None,
+    };
+    if let Some(checkout) = &mut checkout {
+        if reads_archives(&args.command, checkout.path())? {
+            checkout.fetch_archives()?;
+        }
+    }
+    let root = checkout
+        .as_ref()
+        .map_or(args.registry.clone(), |c| c.path().into());
+    // Before reading depot.toml, which newer formats may extend
+    cargo_depot::migrate::check_format(&root)?;
+    args.apply_settings(&cargo_depot::settings::read(&root)?);
+    run(&args, &root)?;
+    if let Some(checkout) = checkout {
+        checkout.commit()?;
+    }
+    info!("Done");
     Ok(())
 }
+
+/// Whether the command reads the archives, which checkouts of remote registries only download
+/// when needed. Publishing reads them to compute the usage against the quotas.
+fn reads_archives(command: &Option<Command>, root: &Path) -> anyhow::Result<bool> {
+    Ok(match command {
+        Some(
+            Command::Serve { .. }
+            | Command::Status { .. }
+            | Command::DiffCrate { .. }
+            | Command::ApiDiff { .. }
+            | Command::Quota { .. }
+            | Command::Verify { .. }
+            | Command::Health { .. }
+            | Command::ExtractLegal { .. }
+            | Command::Migrate { .. }
+            | Command::UpgradeRegistry { .. }
+            | Command::VerifyUpstream { .. },
+        ) => true,
+        None
+        | Some(
+            Command::Publish { .. }
+            | Command::Pull { .. }
+            | Command::Mirror { .. }
+            | Command::MirrorCrate { .. },
+        ) => !Registry(root.into()).read_quotas()?.is_empty(),
+        Some(_) => false,
+    })
+}
+
+fn run(args: &Flags, root: &Path) -> anyhow::Result<()> {
+    if let Some(Command::Init(flags)) = &args.command {
+        return init(root, args.url.as_deref(), flags);
+    }
+    let registry = Registry::open(root, args.url.as_deref())?;
+
+    if let Some(command) = &args.command {
+        return match command {
+            Command::Init(_) => unreachable!("handled before opening the registry"),
+            Command::Config { settings, no_api } => {
+                let mut config = registry.config()?;
+                if settings.is_empty() && !no_api {
+                    println!("{}", serde_json::to_string_pretty(&config)?);
+                    return Ok(());
+                }
+                settings.apply(&mut config);
+                if *no_api {
+                    config.api = None;
+                }
+                registry.write_config(&config)
+            }
+            Command::ConfigSnippet {
+                name,
+                sparse,
+                replace_crates_io,
+                write,
+            } => {
+                let config = cargo_depot::cargo_config::CargoConfig {
+                    name: name.as_deref().unwrap_or("depot").into(),
+                    index: registry.cargo_index(args.url.as_deref(), *sparse)?,
+                    replace_crates_io: *replace_crates_io,
+                };
+                match write {
+                    Some(path) => config.write(path),
+                    None => {
+                        print!("{}", config);
+                        Ok(())
+                    }
+                }
+            }
+            Command::Tui => cargo_depot::tui::run(&registry),
+            Command::Serve {
+                address,
+                port,
+                allow_publish,
+                max_upload_size,
+            } => cargo_depot::serve::serve(
+                &registry,
+                address,
+                *port,
+                *allow_publish,
+                max_upload_size.checked_mul(1 << 20).ok_or_else(|| {
+                    anyhow::anyhow!("--max-upload-size {} MiB is too large", max_upload_size)
+                })?,
+            ),
+            Command::Publish {
+                workspace,
+                version,
+                rename_to,
+                flags,
+                selection,
+            } => {
+                let outcomes = process_workspace(
+                    workspace,
+                    &registry,
+                    flags,
+                    selection,
+                    version.as_ref(),
+                    rename_to.as_deref(),
+                    args.jobs,
+                )?;
+                print_outcomes(&outcomes, flags.output)
+            }
+            Command::Status {
+                workspace,
+                flags,
+                selection,
+            } => status(workspace, &registry, flags, selection),
+            Command::Dist {
+                name,
+                version,
+                target,
+                files,
+            } => registry.add_dist(name, version, target, files),
+            Command::DiffCrate {
+                name,
+                old,
+                new,
+                lines,
+            } => cargo_depot::diff::diff_crate(&registry, name, old, new, *lines),
+            Command::ApiDiff {
+                name,
+                old,
+                new,
+                store,
+            } => {
+                let report = cargo_depot::api::api_diff(&registry, name, old, new)?;
+                print!("{}", report);
+                if *store {
+                    std::fs::write(registry.api_diff_path(name, new), report)?;
+                }
+                Ok(())
+            }
+            Command::List { name, json } => {
+                cargo_depot::list::list(&registry, name.as_deref(), *json)
+            }
+            Command::Resolve { spec, crates_io } => {
+                cargo_depot::resolve::resolve(&registry, spec, *crates_io)
+            }
+            Command::Info { name, version } => {
+                cargo_depot::info::info(&registry, name, version.as_ref())
+            }
+            Command::Yank {
+                spec: (name, version),
+            } => registry.yank(name, version),
+            Command::Unyank {
+                spec: (name, version),
+            } => registry.unyank(name, version),
+            Command::Remove {
+                spec,
+                purge_crate: true,
+            } => registry.purge_crate(spec),
+            Command::Remove {
+                spec,
+                purge_crate: false,
+            } => {
+                let (name, version) = parse_crate_version(spec)?;
+                registry.remove(&name, &version)
+            }
+            Command::Pin {
+                spec: (name, version),
+            } => registry.pin(name, version),
+            Command::Unpin {
+                spec: (name, version),
+            } => registry.unpin(name, version),
+            Command::Restrict { name, tokens } => registry.restrict(name, tokens),
+            Command::Unrestrict { name } => registry.unrestrict(name),
+            Command::Quota {
+                action:
+                    Some(Quota::Set {
+                        team,
+                        crates,
+                        limit,
+                        warn_only,
+                    }),
+                ..
+            } => registry.set_quota(
+                team,
+                cargo_depot::quotas::Quota {
+                    crates: crates.clone(),
+                    limit_bytes: limit
+                        .checked_mul(1 << 20)
+                        .ok_or_else(|| anyhow::anyhow!("--limit {} MiB is too large", limit))?,
+                    warn_only: *warn_only,
+                },
+            ),
+            Command::Quota {
+                action: Some(Quota::Remove { team }),
+                ..
+            } => registry.remove_quota(team),
+            Command::Quota { action: None, json } => {
+                let usage = registry.quota_usage()?;
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&usage)?);
+                    return Ok(());
+                }
+                let width = usage.iter().map(|u| u.team.len()).max().unwrap_or(0);
+                for u in usage {
+                    println!(
+                        "{:<width$}  {:.1} / {} MiB, {} crates{}",
+                        u.team,
+                        u.used_bytes as f64 / (1 << 20) as f64,
+                        u.limit_bytes / (1 << 20),
+                        u.crates.len(),
+                        if u.warn_only { " (warn only)" } else { "" }
+                    );
+                }
+                Ok(())
+            }
+            Command::Token(Token::Create { name }) => {
+                println!("{}", registry.create_token(name)?);
+                Ok(())
+            }
+            Command::Token(Token::Revoke { name }) => registry.revoke_token(name),
+            Command::Token(Token::List) => {
+                for name in registry.read_tokens()?.keys() {
+                    println!("{}", name);
+                }
+                Ok(())
+            }
+            Command::Alias(Alias::Set {
+                spec: (name, version),
+                alias,
+            }) => registry.set_alias(name, alias, version),
+            Command::Alias(Alias::Remove { name, alias }) => registry.remove_alias(name, alias),
+            Command::Alias(Alias::List { name }) => {
+                for (alias, version) in registry.read_aliases(name)? {
+                    println!("{} {}", alias, version);
+                }
+                Ok(())
+            }
+            Command::Latest {
+                name,
+                alias,
+                download_url,
+            } => {
+                let version = registry.resolve_alias(name, alias)?;
+                if *download_url {
+                    println!("{}", registry.download_url(name, &version)?);
+                } else {
+                    println!("{}", version);
+                }
+                Ok(())
+            }
+            Command::AddDep {
+                spec: (name, req),
+                manifest_path,
+                registry_name,
+                dev,
+                build,
+                features,
+                optional,
+            } => {
+                let options = cargo_depot::add_dep::AddOptions {
+                    registry_name: registry_name.clone().unwrap_or_else(|| "depot".into()),
+                    kind: if *dev {
+                        DependencyKind::Dev
+                    } else if *build {
+                        DependencyKind::Build
+                    } else {
+                        DependencyKind::Normal
+                    },
+                    features: features.clone(),
+                    optional: *optional,
+                };
+                let version = cargo_depot::add_dep::add_dependency(
+                    &registry,
+                    manifest_path,
+                    name,
+                    req.as_ref(),
+                    &options,
+                )?;
+                println!("{} {}", name, version);
+                Ok(())
+            }
+            Command::Validate => registry.validate_index(),
+            Command::GenerateSigningKey { path } => cargo_depot::signing::generate_key(path),
+            Command::Verify {
+                name,
+                signatures,
+                json,
+            } => {
+                let report = registry.verify_archives(name.as_deref(), *signatures)?;
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    println!("Verified versions: {}", report.versions);
+                    for (kind, ids) in [
+                        ("Missing archive", &report.missing_archives),
+                        ("Checksum mismatch", &report.checksum_mismatches),
+                        ("Missing signature", &report.missing_signatures),
+                        ("Invalid signature", &report.invalid_signatures),
+                    ] {
+                        for id in ids {
+                            println!("{}: {}", kind, id);
+                        }
+                    }
+                }
+                anyhow::ensure!(report.is_clean(), "Some archives failed verification");
+                Ok(())
+            }
+            Command::Health { json } => {
+                let health = registry.health()?;
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&health)?);
+                } else {
+                    println!("Crates: {}", health.crates);
+                    println!("Versions: {}", health.versions);
+                    println!("Invalid index entries: {}", health.invalid_entries);
+                    println!(
+                        "Index files not matching manifest.json: {}",
+                        health.manifest_mismatches
+                    );
+                    println!("Archives without index entry: {}", health.orphan_archives);
+                    println!("Index entries without archive: {}", health.missing_archives);
+                    if let Some(last) = health.last_publish {
+                        println!("Last publish: {} (Unix time)", last);
+                    }
+                    println!("Free space: {} MiB", health.free_space_bytes / (1 << 20));
+                }
+                anyhow::ensure!(health.healthy, "The registry is not healthy");
+                Ok(())
+            }
+            Command::ExtractLegal { outdir } => {
+                cargo_depot::legal::extract_legal(&registry, outdir)
+            }
+            Command::Migrate { dry_run, json } => {
+                let report =
+                    cargo_depot::migrate::migrate(&registry, args.url.as_deref(), *dry_run)?;
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    print_migration(&report);
+                }
+                anyhow::ensure!(
+                    report.is_clean(),
+                    "The registry has problems to fix manually"
+                );
+                Ok(())
+            }
+            Command::UpgradeRegistry { dry_run, json } => {
+                let report = cargo_depot::migrate::upgrade_registry(&registry, *dry_run)?;
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    println!("Format: {} -> {}", report.from, report.to);
+                    if let Some(migration) = &report.migration {
+                        print_migration(migration);
+                    }
+                }
+                if report.migration.as_ref().is_some_and(|m| !m.is_clean()) {
+                    warn!("The registry has problems to fix manually, see `migrate`");
+                }
+                Ok(())
+            }
+            Command::Report(Report::Licenses {
+                format,
+                dependencies,
+            }) => {
+                let entries = cargo_depot::report::licenses(&registry, *dependencies)?;
+                match format {
+                    Format::Csv => print!("{}", cargo_depot::report::licenses_csv(&entries)?),
+                    Format::Json => println!("{}", serde_json::to_string_pretty(&entries)?),
+                }
+                Ok(())
+            }
+            Command::Sbom { format } => {
+                let sbom = cargo_depot::sbom::sbom(&registry, *format)?;
+                println!("{}", serde_json::to_string_pretty(&sbom)?);
+                Ok(())
+            }
+            Command::Pull {
+                remote,
+                on_conflict,
+                conflict_suffix,
+            } => cargo_depot::pull::pull(&registry, remote, *on_conflict, conflict_suffix),
+            Command::Mirror {
+                lockfile,
+                upstream,
+                upstream_metadata,
+            } => cargo_depot::mirror::mirror(
+                &registry,
+                lockfile,
+                upstream_or_default(upstream),
+                *upstream_metadata,
+            ),
+            Command::MirrorCrate {
+                spec: (name, version),
+                upstream,
+                upstream_metadata,
+            } => cargo_depot::mirror::mirror_crate(
+                &registry,
+                name,
+                version,
+                upstream_or_default(upstream),
+                *upstream_metadata,
+            ),
+            Command::VerifyUpstream {
+                name,
+                upstream,
+                json,
+            } => {
+                let report = cargo_depot::mirror::verify_upstream(
+                    &registry,
+                    upstream_or_default(upstream),
+                    name.as_deref(),
+                )?;
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    println!("Identical to upstream: {}", report.verified);
+                    println!("Not found upstream: {}", report.not_upstream);
+                    for d in &report.divergent {
+                        println!(
+                            "Divergent: {} {} (registry {}, upstream {})",
+                            d.name, d.version, d.local, d.upstream
+                        );
+                    }
+                }
+                anyhow::ensure!(
+                    report.divergent.is_empty(),
+                    "{} versions differ from upstream",
+                    report.divergent.len()
+                );
+                Ok(())
+            }
+        };
+    }
+
+    let mut outcomes = vec![];
+    sources::fetch_all(&args.crates, &args.container_engine, args.jobs, |source| {
+        let archives = source.archives()?;
+        if !archives.is_empty() {
+            for archive in archives {
+                outcomes.push(registry.add_crate_file(&archive, &args.package)?);
+            }
+            return Ok(());
+        }
+        outcomes.extend(process_workspace(
+            &source.path,
+            &registry,
+            &args.package,
+            &args.selection,
+            None,
+            None,
+            args.jobs,
+        )?);
+        Ok(())
+    })?;
+    print_outcomes(&outcomes, args.package.output)
+}
 fn main() {
-    if let Err(e) = main_impl() {
+    let res = main_impl();
+    cargo_depot::temp::finish(res.is_ok());
+    if let Err(e) = res {
         error!("{}", e);
         std::process::exit(2);
     }
@@ -0,0 +1,197 @@
+//! Migration of an existing registry, after moving it to a new URL or upgrading cargo-depot:
+//! index entries are rewritten in the current format, archives are checked against their
+//! checksums, and the configuration points to the new URL.
+//!
+//! The version of the layout is recorded as `depot-format` in `index/config.json`. Registries
+//! with a newer format are rejected, and older ones are brought forward by `upgrade-registry`.
+use std::path::Path;
+
+use log::*;
+use serde::Serialize;
+
+use crate::{sha256_file, IndexConfig, IndexMeta, Registry};
+
+/// Version of the registry layout written by this version of cargo-depot. Increment it when
+/// older versions can no longer operate safely on the registry, and bring older registries
+/// forward in `upgrade_registry`.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Format of the registry at `root`, `None` if it is not initialized yet. Fails if it was
+/// created or upgraded by a newer, incompatible version of cargo-depot.
+pub fn check_format(root: &Path) -> anyhow::Result<Option<u32>> {
+    let registry = Registry(root.into());
+    if !registry.0.join(crate::INDEX).join("config.json").exists() {
+        return Ok(None);
+    }
+    let format = registry.config()?.format;
+    anyhow::ensure!(
+        format <= FORMAT_VERSION,
+        "The registry at {:?} has format {}, but cargo-depot {} only supports formats up to {}. \
+         It was created or upgraded by a newer version: upgrade cargo-depot, e.g. with \
+         `cargo install cargo-depot`.",
+        root,
+        format,
+        env!("CARGO_PKG_VERSION"),
+        FORMAT_VERSION
+    );
+    Ok(Some(format))
+}
+
+#[derive(Default, Serialize)]
+pub struct MigrationReport {
+    pub crates: usize,
+    pub versions: usize,
+    /// Whether config.json was updated for the new URL
+    pub config_updated: bool,
+    /// Index files whose entries were rewritten in the current format
+    pub rewritten_index_files: Vec<String>,
+    /// Entries failing to parse or validate, whose index files were left untouched
+    pub invalid_entries: Vec<String>,
+    pub missing_archives: Vec<String>,
+    pub checksum_mismatches: Vec<String>,
+}
+
+impl MigrationReport {
+    pub fn is_clean(&self) -> bool {
+        self.invalid_entries.is_empty()
+            && self.missing_archives.is_empty()
+            && self.checksum_mismatches.is_empty()
+    }
+}
+
+/// Migrate every crate of the registry, and the configuration to `url` if given. With
+/// `dry_run`, nothing is written.
+pub fn migrate(
+    registry: &Registry,
+    url: Option<&str>,
+    dry_run: bool,
+) -> anyhow::Result<MigrationReport> {
+    let _lock = registry.lock()?;
+    let mut report = MigrationReport::default();
+    if let Some(url) = url {
+        let mut config = registry.config()?;
+        let new = IndexConfig::from_url(url);
+        // The API advertised by `serve --allow-publish` is at the base URL
+        if config.api.is_some() && config.api.as_deref() == config.base_url() {
+            config.api = new.base_url().map(Into::into);
+        }
+        if config.dl != new.dl {
+            info!("Changing the download URL from {} to {}", config.dl, new.dl);
+            config.dl = new.dl;
+            report.config_updated = true;
+            if !dry_run {
+                config.write(&registry.0.join(crate::INDEX))?;
+            }
+        }
+    }
+    for name in registry.package_names()? {
+        report.crates += 1;
+        let path = registry.package_index(&name);
+        let contents = std::fs::read_to_string(&path)?;
+        let mut versions = vec![];
+        let mut valid = true;
+        for (i, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<IndexMeta>(line)
+                .map_err(anyhow::Error::from)
+                .and_then(|meta| meta.validate().map(|_| meta))
+            {
+                Ok(mut meta) => {
+                    // Entries added before `features2` have all the features in `features`
+                    meta.split_features();
+                    versions.push(meta)
+                }
+                Err(e) => {
+                    report
+                        .invalid_entries
+                        .push(format!("{:?}:{}: {:#}", path, i + 1, e));
+                    valid = false;
+                }
+            }
+        }
+        report.versions += versions.len();
+        for meta in &versions {
+            let archive = registry.crate_path(&meta.name, &meta.vers);
+            let id = format!("{} {}", meta.name, meta.vers);
+            if !archive.exists() {
+                report.missing_archives.push(id);
+            } else if sha256_file(&archive)? != meta.cksum {
+                report.checksum_mismatches.push(id);
+            }
+        }
+        if !valid {
+            continue;
+        }
+        let mut rewritten = String::new();
+        for meta in &versions {
+            rewritten += &serde_json::to_string(meta)?;
+            rewritten.push('\n');
+        }
+        if rewritten != contents {
+            report.rewritten_index_files.push(name.clone());
+        }
+        if dry_run {
+            continue;
+        }
+        if rewritten != contents {
+            registry.write_package(&name, &versions)?;
+        } else {
+            // Registries created before aliases have no aliases.json
+            registry.update_latest(&name)?;
+        }
+    }
+    if !dry_run {
+        registry.update_manifest()?;
+        registry.commit_index("Migrate the registry")?;
+    }
+    Ok(report)
+}
+
+#[derive(Serialize)]
+pub struct UpgradeReport {
+    pub from: u32,
+    pub to: u32,
+    /// Migration of the index, `None` if the registry was already up to date
+    pub migration: Option<MigrationReport>,
+}
+
+/// Bring the registry forward to `FORMAT_VERSION`, migrating the index entries in place. The
+/// format is only recorded once every entry was migrated, so that an interrupted or failed
+/// upgrade can be run again. With `dry_run`, nothing is written.
+pub fn upgrade_registry(registry: &Registry, dry_run: bool) -> anyhow::Result<UpgradeReport> {
+    let from = registry.config()?.format;
+    let mut report = UpgradeReport {
+        from,
+        to: FORMAT_VERSION,
+        migration: None,
+    };
+    if from == FORMAT_VERSION {
+        info!("The registry already has format {}", FORMAT_VERSION);
+        return Ok(report);
+    }
+    // Format 0 to 1: index entries in the current format, aliases and manifest
+    let migration = migrate(registry, None, dry_run)?;
+    anyhow::ensure!(
+        migration.invalid_entries.is_empty(),
+        "Invalid index entries, fix them and upgrade again:\n{}",
+        migration.invalid_entries.join("\n")
+    );
+    report.migration = Some(migration);
+    if !dry_run {
+        let mut config = registry.config()?;
+        config.format = FORMAT_VERSION;
+        let _lock = registry.lock()?;
+        config.write(&registry.0.join(crate::INDEX))?;
+        registry.commit_index(&format!(
+            "Upgrade the registry to format {}",
+            FORMAT_VERSION
+        ))?;
+        info!(
+            "Upgraded the registry from format {} to {}",
+            from, FORMAT_VERSION
+        );
+    }
+    Ok(report)
+}
@@ -0,0 +1,345 @@
+//! Mirroring of the crates.io packages locked in a Cargo.lock, for air-gapped builds.
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+use cargo_metadata::semver::Version;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::pull::{download, fetch, get_string};
+use crate::{
+    pkg_path, sha256_file, IndexConfig, IndexMeta, LockedPackage, Lockfile, PackageInfo, Registry,
+    UpstreamInfo,
+};
+
+const CRATES_IO_SOURCES: [&str; 2] = [
+    "registry+https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+
+/// Parse an entry of the upstream index, kept as is, including its `features2`
+fn parse_entry(line: &str) -> anyhow::Result<IndexMeta> {
+    let mut entry: serde_json::Map<String, serde_json::Value> = serde_json::from_str(line)?;
+    // Older entries have no schema version
+    entry.entry("v").or_insert(1.into());
+    Ok(serde_json::from_value(entry.into())?)
+}
+
+/// Interval between requests to the web API, as required by the crates.io crawler policy
+const API_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Response of the web API for a crate, `GET /api/v1/crates/<name>`
+#[derive(Deserialize)]
+struct ApiCrateResponse {
+    #[serde(rename = "crate")]
+    krate: ApiCrate,
+    #[serde(default)]
+    versions: Vec<ApiVersion>,
+}
+#[derive(Deserialize)]
+struct ApiCrate {
+    description: Option<String>,
+    documentation: Option<String>,
+    homepage: Option<String>,
+    repository: Option<String>,
+    downloads: Option<u64>,
+}
+#[derive(Deserialize)]
+struct ApiVersion {
+    num: Version,
+    downloads: Option<u64>,
+}
+
+/// Sparse index the packages are downloaded from
+struct Upstream {
+    url: String,
+    config: IndexConfig,
+    /// Whether to fetch the metadata of the crates from the web API
+    metadata: bool,
+    last_api_request: Cell<Option<Instant>>,
+}
+
+impl Upstream {
+    fn open(url: &str, metadata: bool) -> anyhow::Result<Self> {
+        let url = url.trim_end_matches('/');
+        Ok(Self {
+            url: url.into(),
+            config: serde_json::from_str(&get_string(&format!("{}/config.json", url))?)?,
+            metadata,
+            last_api_request: Cell::new(None),
+        })
+    }
+    fn fetch_crate(&self, api: &str, name: &str) -> anyhow::Result<ApiCrateResponse> {
+        if let Some(last) = self.last_api_request.get() {
+            std::thread::sleep(API_INTERVAL.saturating_sub(last.elapsed()));
+        }
+        self.last_api_request.set(Some(Instant::now()));
+        let url = format!("{}/api/v1/crates/{}", api.trim_end_matches('/'), name);
+        Ok(serde_json::from_str(&get_string(&url)?)?)
+    }
+    /// Sidecar metadata of the versions of a crate, with the description, links and download
+    /// counts of the web API if enabled. Failures only cause a warning.
+    fn package_infos(&self, name: &str) -> impl Fn(&Version) -> PackageInfo {
+        let api = self.config.api.clone().filter(|_| self.metadata);
+        if self.metadata && api.is_none() {
+            warn!(
+                "The upstream index has no web API, not fetching the metadata of {}",
+                name
+            );
+        }
+        let response = api.as_ref().and_then(|api| {
+            info!("Fetching the metadata of {} from {}", name, api);
+            self.fetch_crate(api, name)
+                .map_err(|e| warn!("Could not fetch the metadata of {}: {:#}", name, e))
+                .ok()
+        });
+        let fetched = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        move |version| {
+            let (Some(api), Some(response)) = (&api, &response) else {
+                return PackageInfo::default();
+            };
+            PackageInfo {
+                description: response.krate.description.clone(),
+                documentation: response.krate.documentation.clone(),
+                homepage: response.krate.homepage.clone(),
+                repository: response.krate.repository.clone(),
+                environment: None,
+                upstream: Some(UpstreamInfo {
+                    api: api.clone(),
+                    fetched,
+                    downloads: response.krate.downloads,
+                    version_downloads: response
+                        .versions
+                        .iter()
+                        .find(|v| &v.num == version)
+                        .and_then(|v| v.downloads),
+                }),
+            }
+        }
+    }
+    fn entries(&self, name: &str) -> anyhow::Result<Vec<IndexMeta>> {
+        let index = get_string(&format!(
+            "{}/{}/{}",
+            self.url,
+            pkg_path(name).to_string_lossy(),
+            name.to_lowercase()
+        ))?;
+        index
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(parse_entry)
+            .collect()
+    }
+    /// Like `entries`, but `None` if the crate does not exist upstream
+    fn find_entries(&self, name: &str) -> anyhow::Result<Option<Vec<IndexMeta>>> {
+        match self.entries(name) {
+            Ok(entries) => Ok(Some(entries)),
+            Err(e) if matches!(e.downcast_ref(), Some(ureq::Error::Status(404, _))) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    /// Download the archive of the upstream entry of `name` `version`, verify its checksum and add
+    /// it
+    fn add(
+        &self,
+        registry: &Registry,
+        name: &str,
+        version: &Version,
+        meta: &IndexMeta,
+        info: &PackageInfo,
+    ) -> anyhow::Result<()> {
+        // The entry is written as is to the index, and its name used in paths
+        anyhow::ensure!(
+            meta.name == name && &meta.vers == version,
+            "The upstream index of {} has an entry for {} {}",
+            name,
+            meta.name,
+            meta.vers
+        );
+        meta.validate()?;
+        info!("Mirroring {} {}", meta.name, meta.vers);
+        let staging = crate::temp::tempdir()?;
+        let staged = staging.path().join("download.crate");
+        download(
+            &self
+                .config
+                .download_url(&meta.name, &meta.vers.to_string(), &meta.cksum),
+            &meta.cksum,
+            &staged,
+        )?;
+        registry.insert(meta, info, &staged)
+    }
+}
+
+/// Add the crates.io packages of a lockfile, downloaded from `upstream` (a sparse index URL),
+/// keeping their original checksums. With `metadata`, the description, links and download counts
+/// of the crates are fetched from the web API of the upstream registry.
+pub fn mirror(
+    registry: &Registry,
+    lockfile: &Path,
+    upstream: &str,
+    metadata: bool,
+) -> anyhow::Result<()> {
+    let lockfile: Lockfile = toml::from_str(&std::fs::read_to_string(lockfile)?)?;
+    let upstream = Upstream::open(upstream, metadata)?;
+    let mut locked: BTreeMap<String, Vec<LockedPackage>> = BTreeMap::new();
+    for package in lockfile.package {
+        if package
+            .source
+            .as_deref()
+            .map_or(false, |s| CRATES_IO_SOURCES.contains(&s))
+        {
+            locked
+                .entry(package.name.clone())
+                .or_default()
+                .push(package);
+        }
+    }
+    info!(
+        "Found {} crates.io packages in the lockfile",
+        locked.values().map(Vec::len).sum::<usize>()
+    );
+
+    let mut added = 0;
+    for (name, packages) in locked {
+        let existing = registry.read_package(&name)?;
+        let missing: Vec<_> = packages
+            .into_iter()
+            .filter(|p| !existing.iter().any(|m| m.vers == p.version))
+            .collect();
+        if missing.is_empty() {
+            continue;
+        }
+        let mut entries = upstream.entries(&name)?;
+        let infos = upstream.package_infos(&name);
+        for package in missing {
+            let Some(position) = entries.iter().position(|m| m.vers == package.version) else {
+                anyhow::bail!("{} {} not found upstream", name, package.version);
+            };
+            let meta = entries.swap_remove(position);
+            if let Some(checksum) = &package.checksum {
+                anyhow::ensure!(
+                    checksum == &meta.cksum,
+                    "Checksum of {} {} in the lockfile does not match the upstream index",
+                    name,
+                    package.version
+                );
+            }
+            upstream.add(registry, &name, &package.version, &meta, &infos(&meta.vers))?;
+            added += 1;
+        }
+    }
+    info!("Added {} packages", added);
+    Ok(())
+}
+
+/// Add a single version from `upstream`
+pub fn mirror_crate(
+    registry: &Registry,
+    name: &str,
+    version: &Version,
+    upstream: &str,
+    metadata: bool,
+) -> anyhow::Result<()> {
+    if registry
+        .read_package(name)?
+        .iter()
+        .any(|m| &m.vers == version)
+    {
+        warn!("{} {} is already in the registry", name, version);
+        return Ok(());
+    }
+    let upstream = Upstream::open(upstream, metadata)?;
+    let Some(meta) = upstream
+        .entries(name)?
+        .into_iter()
+        .find(|m| &m.vers == version)
+    else {
+        anyhow::bail!("{} {} not found upstream", name, version);
+    };
+    let info = upstream.package_infos(name)(version);
+    upstream.add(registry, name, version, &meta, &info)
+}
+
+/// Version whose archive differs from the one published upstream
+#[derive(Serialize)]
+pub struct Divergence {
+    pub name: String,
+    pub version: Version,
+    /// Checksum of the archive in the registry
+    pub local: String,
+    /// Checksum of the upstream archive
+    pub upstream: String,
+}
+
+#[derive(Default, Serialize)]
+pub struct UpstreamReport {
+    /// Versions identical to upstream
+    pub verified: usize,
+    /// Versions absent from upstream, e.g. internal crates
+    pub not_upstream: usize,
+    pub divergent: Vec<Divergence>,
+}
+
+/// Download again from `upstream` the versions of the registry (or of the crate `name`) that
+/// exist there, and compare them with the archives of the registry.
+pub fn verify_upstream(
+    registry: &Registry,
+    upstream: &str,
+    name: Option<&str>,
+) -> anyhow::Result<UpstreamReport> {
+    let upstream = Upstream::open(upstream, false)?;
+    let names = match name {
+        Some(name) => vec![name.to_string()],
+        None => registry.package_names()?,
+    };
+    let staging = crate::temp::tempdir()?;
+    let mut report = UpstreamReport::default();
+    for name in names {
+        let versions = registry.read_package(&name)?;
+        anyhow::ensure!(!versions.is_empty(), "{} not found in the registry", name);
+        let Some(entries) = upstream.find_entries(&name)? else {
+            debug!("{} not found upstream", name);
+            report.not_upstream += versions.len();
+            continue;
+        };
+        for meta in versions {
+            let Some(entry) = entries.iter().find(|m| m.vers == meta.vers) else {
+                debug!("{} {} not found upstream", name, meta.vers);
+                report.not_upstream += 1;
+                continue;
+            };
+            info!("Verifying {} {}", name, meta.vers);
+            let local = sha256_file(&registry.crate_path(&name, &meta.vers))?;
+            // Only download when the upstream index agrees with the registry
+            let upstream_checksum = if entry.cksum == local {
+                let staged = staging.path().join("download.crate");
+                fetch(
+                    &upstream
+                        .config
+                        .download_url(&name, &meta.vers.to_string(), &entry.cksum),
+                    &staged,
+                )?;
+                sha256_file(&staged)?
+            } else {
+                entry.cksum.clone()
+            };
+            if upstream_checksum == local {
+                report.verified += 1;
+            } else {
+                warn!("{} {} differs from upstream", name, meta.vers);
+                report.divergent.push(Divergence {
+                    name: name.clone(),
+                    version: meta.vers,
+                    local,
+                    upstream: upstream_checksum,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
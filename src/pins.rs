@@ -0,0 +1,62 @@
+//! Versions exempt from cleanup (pruning, removal), listed in `pins.json` at the root of the
+//! registry.
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use cargo_metadata::semver::Version;
+use log::*;
+
+use crate::Registry;
+
+const PINS: &str = "pins.json";
+
+/// Pinned versions per crate
+pub type Pins = BTreeMap<String, BTreeSet<Version>>;
+
+impl Registry {
+    fn pins_path(&self) -> PathBuf {
+        self.0.join(PINS)
+    }
+    pub fn read_pins(&self) -> anyhow::Result<Pins> {
+        let path = self.pins_path();
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+    fn write_pins(&self, pins: &Pins) -> anyhow::Result<()> {
+        Ok(std::fs::write(
+            self.pins_path(),
+            serde_json::to_string_pretty(pins)?,
+        )?)
+    }
+    pub fn is_pinned(&self, name: &str, version: &Version) -> anyhow::Result<bool> {
+        Ok(self
+            .read_pins()?
+            .get(name)
+            .map_or(false, |v| v.contains(version)))
+    }
+    /// Exempt a version from cleanup
+    pub fn pin(&self, name: &str, version: &Version) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        anyhow::ensure!(
+            self.read_package(name)?.iter().any(|m| &m.vers == version),
+            "{} {} not found in the registry",
+            name,
+            version
+        );
+        let mut pins = self.read_pins()?;
+        if !pins.entry(name.into()).or_default().insert(version.clone()) {
+            warn!("{} {} is already pinned", name, version);
+        }
+        self.write_pins(&pins)
+    }
+    pub fn unpin(&self, name: &str, version: &Version) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        let mut pins = self.read_pins()?;
+        let removed = pins.get_mut(name).map_or(false, |v| v.remove(version));
+        anyhow::ensure!(removed, "{} {} is not pinned", name, version);
+        pins.retain(|_, v| !v.is_empty());
+        self.write_pins(&pins)
+    }
+}
@@ -0,0 +1,156 @@
+//! Publish policies written as Rhai scripts.
+//!
+//! The script has access to:
+//! - `pkg`: the index entry that would be written (`name`, `vers`, `deps`, `features`,
+//!   `license`, ...),
+//! - `files`: the list of files in the `.crate` archive.
+//!
+//! It must evaluate to `true` (or `()`) to allow the publish, and to `false` or a string
+//! (the reason) to deny it.
+//!
+//! Scripts run with limits on time, operations, call depth and data sizes, so that a faulty
+//! policy (e.g. `loop {}`) fails the publish instead of hanging it, and cannot import modules,
+//! which would read arbitrary files.
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::IndexMeta;
+
+/// Enough for policies iterating over the files of large crates, while stopping within seconds
+const MAX_OPERATIONS: u64 = 50_000_000;
+const MAX_CALL_LEVELS: usize = 64;
+const MAX_STRING_SIZE: usize = 1 << 20;
+const MAX_ARRAY_SIZE: usize = 1 << 20;
+const MAX_MAP_SIZE: usize = 1 << 16;
+/// The size checks are linear in the size of the values, so growing an array one element at a
+/// time takes quadratic time to reach the limits above
+const MAX_DURATION: Duration = Duration::from_secs(10);
+
+fn engine() -> rhai::Engine {
+    let mut engine = rhai::Engine::new();
+    let start = Instant::now();
+    engine
+        .on_progress(move |_| (start.elapsed() > MAX_DURATION).then(|| "timeout".into()))
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_expr_depths(MAX_CALL_LEVELS, MAX_CALL_LEVELS)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(MAX_ARRAY_SIZE)
+        .set_max_map_size(MAX_MAP_SIZE)
+        .set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new());
+    engine
+}
+
+pub fn check(script: &Path, metadata: &IndexMeta, files: Vec<String>) -> anyhow::Result<()> {
+    let engine = engine();
+    let mut scope = rhai::Scope::new();
+    scope.push_constant("pkg", rhai::serde::to_dynamic(metadata)?);
+    scope.push_constant("files", rhai::serde::to_dynamic(files)?);
+    let result = engine
+        .eval_file_with_scope::<rhai::Dynamic>(&mut scope, script.into())
+        .map_err(|e| match *e {
+            rhai::EvalAltResult::ErrorTooManyOperations(_)
+            | rhai::EvalAltResult::ErrorStackOverflow(_)
+            | rhai::EvalAltResult::ErrorDataTooLarge(..)
+            | rhai::EvalAltResult::ErrorTerminated(..) => {
+                anyhow::anyhow!("Policy {:?} exceeded its limits: {}", script, e)
+            }
+            _ => anyhow::anyhow!("Failed to evaluate policy {:?}: {}", script, e),
+        })?;
+    if result.is_unit() || result.as_bool() == Ok(true) {
+        Ok(())
+    } else if result.as_bool() == Ok(false) {
+        anyhow::bail!("Denied by policy {:?}", script)
+    } else if let Some(reason) = result.clone().try_cast::<String>() {
+        anyhow::bail!("Denied by policy {:?}: {}", script, reason)
+    } else {
+        anyhow::bail!(
+            "Policy {:?} returned a {}, expected a boolean or a string",
+            script,
+            result.type_name()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_script(script: &str) -> anyhow::Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("policy.rhai");
+        std::fs::write(&path, script).unwrap();
+        let metadata = serde_json::from_value(serde_json::json!({
+            "name": "foo",
+            "vers": "1.0.0",
+            "deps": [],
+            "features": {},
+            "license": "MIT",
+            "cksum": "0".repeat(64),
+            "v": 1,
+            "yanked": false,
+        }))
+        .unwrap();
+        check(&path, &metadata, vec!["foo-1.0.0/Cargo.toml".into()])
+    }
+
+    #[test]
+    fn allow() {
+        check_script("true").unwrap();
+        check_script("let x = 1;").unwrap();
+        check_script(r#"pkg.name == "foo" && files.contains("foo-1.0.0/Cargo.toml")"#).unwrap();
+    }
+
+    #[test]
+    fn deny() {
+        let error = check_script("false").unwrap_err().to_string();
+        assert!(error.starts_with("Denied by policy"), "{}", error);
+        let error = check_script(r#"if pkg.license == "MIT" { return "no MIT"; } true"#)
+            .unwrap_err()
+            .to_string();
+        assert!(error.ends_with(": no MIT"), "{}", error);
+        let error = check_script("42").unwrap_err().to_string();
+        assert!(error.contains("returned a i64"), "{}", error);
+    }
+
+    #[test]
+    fn script_error() {
+        let error = check_script("pkg.missing(").unwrap_err().to_string();
+        assert!(error.starts_with("Failed to evaluate policy"), "{}", error);
+        let error = check_script("undefined_function()")
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("Failed to evaluate policy"), "{}", error);
+    }
+
+    #[test]
+    fn limits() {
+        for script in [
+            "loop {}",
+            "fn f(x) { f(x + 1) } f(0)",
+            r#"let s = "x"; loop { s += s; }"#,
+            "let a = [1]; loop { a += a; }",
+        ] {
+            let error = check_script(script).unwrap_err().to_string();
+            assert!(
+                error.contains("exceeded its limits"),
+                "{}: {}",
+                script,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn no_imports() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("module.rhai"), "export const X = 1;").unwrap();
+        let script = format!(
+            "import {:?} as m; m::X == 1",
+            dir.path().join("module.rhai").to_string_lossy()
+        );
+        let error = check_script(&script).unwrap_err().to_string();
+        assert!(error.starts_with("Failed to evaluate policy"), "{}", error);
+        check_script(r#"import "module" as m; true"#).unwrap_err();
+    }
+}
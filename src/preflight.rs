@@ -0,0 +1,105 @@
+//! Checks of the environment before packaging a workspace, reporting every problem at once
+//! rather than failing in the middle of a run.
+use std::path::Path;
+
+use cargo_metadata::semver::Version;
+use log::*;
+
+use crate::{PackageFlags, Registry};
+
+/// Oldest cargo supporting sparse registries, the default protocol of the index
+const MIN_CARGO_VERSION: Version = Version::new(1, 68, 0);
+
+/// Version of the `cargo` found in the path
+fn cargo_version() -> anyhow::Result<Version> {
+    let out = std::process::Command::new("cargo")
+        .arg("--version")
+        .output()?;
+    anyhow::ensure!(out.status.success(), "cargo --version failed");
+    let out = String::from_utf8_lossy(&out.stdout);
+    let Some(version) = out.split_whitespace().nth(1) else {
+        anyhow::bail!("Unexpected output of cargo --version: {}", out.trim());
+    };
+    Ok(version.parse()?)
+}
+
+fn in_git_repository(dir: &Path) -> bool {
+    dir.ancestors().any(|d| d.join(".git").exists())
+}
+
+fn check_writable(dir: &Path) -> anyhow::Result<()> {
+    let probe = dir.join(".depot-preflight");
+    std::fs::write(&probe, "")?;
+    std::fs::remove_file(probe)?;
+    Ok(())
+}
+
+impl Registry {
+    /// Check that the tools needed to add the packages of `workspace` are available, that the
+    /// registry is writable, and that its configuration is valid.
+    pub fn preflight(&self, workspace: &Path, flags: &PackageFlags) -> anyhow::Result<()> {
+        let mut problems = vec![];
+        #[cfg(feature = "serve")]
+        let needs_cargo = !flags.no_cargo || flags.verify;
+        #[cfg(not(feature = "serve"))]
+        let needs_cargo = !flags.no_cargo;
+        if needs_cargo {
+            match cargo_version() {
+                Ok(version) if version < MIN_CARGO_VERSION => problems.push(format!(
+                    "cargo {} is too old, {} or newer is required",
+                    version, MIN_CARGO_VERSION
+                )),
+                Ok(version) => debug!("Found cargo {}", version),
+                Err(e) => problems.push(format!("cargo is not available: {:#}", e)),
+            }
+        }
+        let needs_git = in_git_repository(workspace) || self.is_git_backed();
+        if needs_git {
+            if let Err(e) = std::process::Command::new("git").arg("--version").output() {
+                problems.push(format!("git is not available: {}", e));
+            }
+        }
+        for dir in [
+            self.0.clone(),
+            self.0.join(crate::INDEX),
+            self.0.join(crate::CRATES),
+        ] {
+            if dir.exists() {
+                if let Err(e) = check_writable(&dir) {
+                    problems.push(format!("{:?} is not writable: {:#}", dir, e));
+                }
+            }
+        }
+        match self.config() {
+            Ok(config) => {
+                for (field, url) in [
+                    ("dl", Some(config.dl.as_str())),
+                    ("api", config.api.as_deref()),
+                ] {
+                    let Some(url) = url else {
+                        continue;
+                    };
+                    if !["http://", "https://", "file://"]
+                        .iter()
+                        .any(|scheme| url.starts_with(scheme))
+                    {
+                        problems.push(format!(
+                            "The {} URL of index/config.json is not an HTTP(S) or file URL: {}",
+                            field, url
+                        ));
+                    }
+                }
+            }
+            Err(e) => problems.push(format!("Invalid index/config.json: {:#}", e)),
+        }
+        if let Err(e) = self.settings() {
+            problems.push(format!("{:#}", e));
+        }
+        anyhow::ensure!(
+            problems.is_empty(),
+            "Preflight checks failed:\n  - {}",
+            problems.join("\n  - ")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+//! Handling of the body of `cargo publish` requests
+//! (https://doc.rust-lang.org/cargo/reference/registry-web-api.html#publish).
+use std::collections::BTreeMap;
+
+use cargo_metadata::semver::{Version, VersionReq};
+use log::*;
+use serde::Deserialize;
+
+use crate::{crate_files, sha256_file, Dependency, IndexMeta, PackageInfo, Registry};
+
+#[derive(Deserialize)]
+struct PublishDependency {
+    name: String,
+    version_req: VersionReq,
+    features: Vec<String>,
+    optional: bool,
+    default_features: bool,
+    target: Option<cargo_platform::Platform>,
+    kind: cargo_metadata::DependencyKind,
+    registry: Option<String>,
+    /// Set when the dependency is renamed
+    explicit_name_in_toml: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PublishMetadata {
+    name: String,
+    vers: Version,
+    deps: Vec<PublishDependency>,
+    features: BTreeMap<String, Vec<String>>,
+    description: Option<String>,
+    documentation: Option<String>,
+    homepage: Option<String>,
+    repository: Option<String>,
+    license: Option<String>,
+    license_file: Option<cargo_metadata::camino::Utf8PathBuf>,
+    links: Option<String>,
+    #[serde(default)]
+    rust_version: Option<String>,
+}
+
+impl PublishMetadata {
+    fn into_index(self, cksum: String) -> (IndexMeta, PackageInfo) {
+        let deps = self
+            .deps
+            .into_iter()
+            .map(|d| {
+                let (name, package) = match d.explicit_name_in_toml {
+                    Some(rename) => (rename, Some(d.name)),
+                    None => (d.name, None),
+                };
+                Dependency {
+                    name,
+                    req: d.version_req,
+                    features: d.features,
+                    optional: d.optional,
+                    default_features: d.default_features,
+                    target: d.target,
+                    kind: d.kind,
+                    registry: d.registry,
+                    package,
+                }
+            })
+            .collect();
+        let info = PackageInfo {
+            description: self.description,
+            documentation: self.documentation,
+            homepage: self.homepage,
+            repository: self.repository,
+            environment: None,
+            upstream: None,
+        };
+        let mut meta = IndexMeta {
+            name: self.name,
+            vers: self.vers,
+            deps,
+            features: self.features,
+            features2: Default::default(),
+            license: self.license,
+            license_file: self.license_file,
+            cksum,
+            links: self.links,
+            rust_version: self.rust_version,
+            v: 1,
+            yanked: false,
+        };
+        // Cargo sends all the features in `features`
+        meta.split_features();
+        (meta, info)
+    }
+}
+
+/// Split a length-prefixed section from the body
+fn take<'a>(body: &mut &'a [u8]) -> anyhow::Result<&'a [u8]> {
+    anyhow::ensure!(body.len() >= 4, "Truncated request body");
+    let (len, rest) = body.split_at(4);
+    let len = u32::from_le_bytes(len.try_into()?) as usize;
+    anyhow::ensure!(rest.len() >= len, "Truncated request body");
+    let (section, rest) = rest.split_at(len);
+    *body = rest;
+    Ok(section)
+}
+
+impl Registry {
+    /// Add a crate from the body of a publish request: the JSON metadata followed by the
+    /// .crate archive, each prefixed with its length, sent with the token named `token`.
+    pub fn publish(&self, mut body: &[u8], token: &str) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        let metadata: PublishMetadata = serde_json::from_slice(take(&mut body)?)?;
+        let archive = take(&mut body)?;
+        info!("Publishing {} {}", metadata.name, metadata.vers);
+        self.check_freeze(&metadata.name, &metadata.vers, Some(token))?;
+        anyhow::ensure!(
+            !self
+                .read_package(&metadata.name)?
+                .iter()
+                .any(|m| m.vers == metadata.vers),
+            crate::Error::AlreadyPublished {
+                name: metadata.name,
+                version: metadata.vers
+            }
+        );
+        let staging = crate::temp::tempdir()?;
+        let staged = staging.path().join("upload.crate");
+        std::fs::write(&staged, archive)?;
+        anyhow::ensure!(
+            crate_files(&staged)?
+                .contains(&format!("{}-{}/Cargo.toml", metadata.name, metadata.vers)),
+            "The archive does not contain {} {}",
+            metadata.name,
+            metadata.vers
+        );
+        let (meta, info) = metadata.into_index(sha256_file(&staged)?);
+        meta.validate()?;
+        self.warn_links(&meta)?;
+        // The policy and generators of depot.toml apply as when adding with the CLI
+        #[cfg(feature = "policy")]
+        if let Some(script) = &self.settings()?.package.policy {
+            crate::policy::check(script, &meta, crate_files(&staged)?)?;
+        }
+        let artifacts = self.upload_artifacts(&meta, &staged)?;
+        self.insert(&meta, &info, &staged)?;
+        self.store_artifacts(&meta.name, &meta.vers, artifacts)
+    }
+    /// Run the generators enabled in the manifest of an uploaded archive
+    fn upload_artifacts(
+        &self,
+        meta: &IndexMeta,
+        archive: &std::path::Path,
+    ) -> anyhow::Result<Option<crate::generators::Artifacts>> {
+        let unpacked = crate::unpack_crate(archive)?;
+        let Some(dir) = std::fs::read_dir(&unpacked)?.next() else {
+            anyhow::bail!("The archive is empty");
+        };
+        let metadata = crate::builtin::metadata(&dir?.path())?;
+        let Some(p) = metadata.packages.first() else {
+            anyhow::bail!("No package in the archive");
+        };
+        anyhow::ensure!(
+            p.name == meta.name && p.version == meta.vers,
+            "The manifest of the archive is for {} {}, not {} {}",
+            p.name,
+            p.version,
+            meta.name,
+            meta.vers
+        );
+        let generators = crate::PackagingOverrides::new(p, &metadata)?.generators;
+        self.generate_artifacts(p, archive, &generators.unwrap_or_default())
+    }
+}
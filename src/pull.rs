@@ -0,0 +1,269 @@
+//! Replication of a remote registry served over HTTP, using its manifest.json to only fetch
+//! what changed.
+use std::io::Read;
+use std::path::Path;
+
+use cargo_metadata::semver::{Prerelease, Version};
+use log::*;
+use sha2::Digest;
+
+use crate::{pkg_path, sha256_file, IndexConfig, IndexMeta, Registry, RegistryManifest};
+
+/// What to do when a version exists on both sides with different checksums
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    /// Abort without modifying the registry
+    Fail,
+    /// Keep the local version
+    PreferOurs,
+    /// Replace the local version with the remote one
+    PreferTheirs,
+    /// Add the remote version as a pre-release with a suffix, e.g. 1.2.0-theirs
+    RenameWithSuffix,
+}
+
+pub(crate) fn get_string(url: &str) -> anyhow::Result<String> {
+    Ok(crate::http::get(url).call()?.into_string()?)
+}
+
+pub(crate) fn fetch(url: &str, dest: &Path) -> anyhow::Result<()> {
+    debug!("Downloading {}", url);
+    std::io::copy(
+        &mut crate::http::get(url).call()?.into_reader(),
+        &mut std::fs::File::create(dest)?,
+    )?;
+    Ok(())
+}
+
+pub(crate) fn download(url: &str, checksum: &str, dest: &Path) -> anyhow::Result<()> {
+    fetch(url, dest)?;
+    anyhow::ensure!(
+        sha256_file(dest)? == checksum,
+        "Checksum mismatch for {}",
+        url
+    );
+    Ok(())
+}
+
+/// Check a crate name of the remote manifest, which ends up in paths of the registry
+fn check_name(name: &str) -> anyhow::Result<()> {
+    cargo_util_schemas::manifest::PackageName::new(name)
+        .map_err(|e| anyhow::anyhow!("Invalid crate name in the remote manifest: {}", e))?;
+    anyhow::ensure!(
+        name.is_ascii(),
+        "Invalid crate name in the remote manifest: {:?} is not ASCII",
+        name
+    );
+    Ok(())
+}
+
+fn with_suffix(version: &Version, suffix: &str) -> anyhow::Result<Version> {
+    let mut version = version.clone();
+    version.pre = if version.pre.is_empty() {
+        Prerelease::new(suffix)?
+    } else {
+        Prerelease::new(&format!("{}.{}", version.pre, suffix))?
+    };
+    Ok(version)
+}
+
+/// Rewrite a .crate archive under a different version
+fn repack(src: &Path, dest: &Path, name: &str, old: &Version, new: &Version) -> anyhow::Result<()> {
+    let (old_prefix, new_prefix) = (format!("{}-{}", name, old), format!("{}-{}", name, new));
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(std::fs::File::open(src)?));
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        std::fs::File::create(dest)?,
+        flate2::Compression::default(),
+    ));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.strip_prefix(&old_prefix)?.to_path_buf();
+        let mut header = entry.header().clone();
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        if path == Path::new("Cargo.toml") {
+            let mut manifest: toml::Table = toml::from_str(std::str::from_utf8(&data)?)?;
+            if let Some(package) = manifest.get_mut("package").and_then(|p| p.as_table_mut()) {
+                package.insert("version".into(), new.to_string().into());
+            }
+            data = toml::to_string(&manifest)?.into_bytes();
+            header.set_size(data.len() as u64);
+        }
+        builder.append_data(
+            &mut header,
+            Path::new(&new_prefix).join(path),
+            data.as_slice(),
+        )?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+pub fn pull(
+    registry: &Registry,
+    remote: &str,
+    policy: ConflictPolicy,
+    suffix: &str,
+) -> anyhow::Result<()> {
+    // The local state read while downloading must not change before it is applied
+    let _lock = registry.lock()?;
+    let remote = remote.trim_end_matches('/');
+    info!("Fetching manifest from {}", remote);
+    let manifest: RegistryManifest =
+        serde_json::from_str(&get_string(&format!("{}/{}", remote, crate::MANIFEST))?)?;
+    let config: IndexConfig = serde_json::from_str(&get_string(&format!(
+        "{}/{}/config.json",
+        remote,
+        crate::INDEX
+    ))?)?;
+
+    // Download and verify everything before touching the registry
+    let staging = tempfile::tempdir_in(&registry.0)?;
+    // Staged archives, with the version they are added as
+    let mut archives = vec![];
+    let mut updates = vec![];
+    let mut conflicts = vec![];
+    // Local versions replaced by a different remote archive
+    let mut replaced = vec![];
+    for (name, hash) in &manifest.index {
+        check_name(name)?;
+        let index = registry.package_index(name);
+        if index.exists() && &sha256_file(&index)? == hash {
+            continue;
+        }
+        info!("Updating {}", name);
+        let remote_index = get_string(&format!(
+            "{}/{}/{}/{}",
+            remote,
+            crate::INDEX,
+            pkg_path(name).display(),
+            name
+        ))?;
+        anyhow::ensure!(
+            &format!("{:x}", sha2::Sha256::digest(remote_index.as_bytes())) == hash,
+            "The remote index file of {} does not match the hash of the remote manifest",
+            name
+        );
+        let mut versions = registry.read_package(name)?;
+        for line in remote_index.lines().filter(|l| !l.trim().is_empty()) {
+            let mut remote_meta: IndexMeta = serde_json::from_str(line)?;
+            anyhow::ensure!(
+                &remote_meta.name == name,
+                "The remote index file of {} has an entry for {}",
+                name,
+                remote_meta.name
+            );
+            let url = config.download_url(name, &remote_meta.vers.to_string(), &remote_meta.cksum);
+            let staged = staging.path().join(archives.len().to_string());
+            let local = versions.iter().position(|m| m.vers == remote_meta.vers);
+            match local {
+                Some(i) if versions[i].cksum == remote_meta.cksum => {
+                    versions[i].yanked = remote_meta.yanked;
+                }
+                Some(i) => {
+                    let conflict = format!("{} {}", name, remote_meta.vers);
+                    match policy {
+                        ConflictPolicy::Fail => {
+                            conflicts.push(conflict);
+                        }
+                        ConflictPolicy::PreferOurs => {
+                            conflicts.push(format!("{}: kept local version", conflict));
+                        }
+                        ConflictPolicy::PreferTheirs
+                            if registry.is_pinned(name, &remote_meta.vers)? =>
+                        {
+                            conflicts.push(format!("{}: pinned, kept local version", conflict));
+                        }
+                        ConflictPolicy::PreferTheirs => {
+                            conflicts.push(format!("{}: replaced with remote version", conflict));
+                            download(&url, &remote_meta.cksum, &staged)?;
+                            replaced.push((name, remote_meta.vers.clone()));
+                            archives.push((name, remote_meta.vers.clone(), staged));
+                            versions[i] = remote_meta;
+                        }
+                        ConflictPolicy::RenameWithSuffix => {
+                            let renamed = with_suffix(&remote_meta.vers, suffix)?;
+                            conflicts
+                                .push(format!("{}: added remote version as {}", conflict, renamed));
+                            if versions.iter().any(|m| m.vers == renamed) {
+                                continue;
+                            }
+                            let downloaded = staging.path().join("download");
+                            download(&url, &remote_meta.cksum, &downloaded)?;
+                            repack(&downloaded, &staged, name, &remote_meta.vers, &renamed)?;
+                            remote_meta.cksum = sha256_file(&staged)?;
+                            archives.push((name, renamed.clone(), staged));
+                            remote_meta.vers = renamed;
+                            versions.push(remote_meta);
+                        }
+                    }
+                }
+                None => {
+                    download(&url, &remote_meta.cksum, &staged)?;
+                    archives.push((name, remote_meta.vers.clone(), staged));
+                    versions.push(remote_meta);
+                }
+            }
+        }
+        // Validated by write_package, which must not fail once archives are moved in
+        for meta in &versions {
+            meta.validate()?;
+        }
+        updates.push((name, versions));
+    }
+
+    if !conflicts.is_empty() {
+        warn!("{} conflicts:\n{}", conflicts.len(), conflicts.join("\n"));
+    }
+    anyhow::ensure!(
+        policy != ConflictPolicy::Fail || conflicts.is_empty(),
+        "Versions with different checksums in the remote registry, see --on-conflict"
+    );
+
+    // Replaced archives only count for their growth
+    let mut additions = vec![];
+    for (name, version, staged) in &archives {
+        let previous = std::fs::metadata(registry.crate_path(name, version)).map_or(0, |m| m.len());
+        additions.push((
+            name.as_str(),
+            std::fs::metadata(staged)?.len().saturating_sub(previous),
+        ));
+    }
+    registry.check_quotas(&additions)?;
+
+    info!(
+        "Applying {} new archives and {} index updates",
+        archives.len(),
+        updates.len()
+    );
+    for (name, version, staged) in &archives {
+        std::fs::create_dir_all(registry.crate_path(name, version).parent().unwrap())?;
+        registry.sign_archive(name, version, staged)?;
+    }
+    for (name, version) in replaced {
+        // The other sidecars describe the previous archive
+        let artifacts = registry.artifacts_path(name, &version);
+        if artifacts.exists() {
+            std::fs::remove_dir_all(artifacts)?;
+        }
+        for path in [
+            registry.info_path(name, &version),
+            registry.api_diff_path(name, &version),
+        ] {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+        }
+    }
+    for (name, version, staged) in archives {
+        std::fs::rename(staged, registry.crate_path(name, &version))?;
+    }
+    for (name, versions) in updates {
+        std::fs::create_dir_all(registry.package_index(name).parent().unwrap())?;
+        let before = registry.read_package(name)?;
+        registry.write_package(name, &versions)?;
+        registry.record_index_changes(name, &before, &versions)?;
+    }
+    registry.update_manifest()?;
+    registry.commit_index(&format!("Pull from {}", remote))
+}
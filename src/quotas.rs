@@ -0,0 +1,226 @@
+//! Storage quotas of teams, each owning the crates whose names match some globs, listed in
+//! `quotas.json` at the root of the registry and enforced when versions are added.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{Registry, CRATES};
+
+const QUOTAS: &str = "quotas.json";
+
+#[derive(Serialize, Deserialize)]
+pub struct Quota {
+    /// Globs matching the names of the crates of the team, e.g. `infra-*`
+    pub crates: Vec<String>,
+    /// Maximum size of the files of these crates (archives and sidecar files)
+    pub limit_bytes: u64,
+    /// Only warn when a version exceeds the quota, instead of rejecting it
+    #[serde(default)]
+    pub warn_only: bool,
+}
+impl Quota {
+    /// Case-insensitive, like crate names
+    fn matches(&self, name: &str) -> anyhow::Result<bool> {
+        let options = glob::MatchOptions {
+            case_sensitive: false,
+            ..Default::default()
+        };
+        for pattern in &self.crates {
+            if glob::Pattern::new(pattern)?.matches_with(name, options) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Quotas by team name
+pub type Quotas = BTreeMap<String, Quota>;
+
+#[derive(Serialize)]
+pub struct Usage {
+    pub team: String,
+    pub crates: Vec<String>,
+    pub used_bytes: u64,
+    pub limit_bytes: u64,
+    pub warn_only: bool,
+}
+
+fn dir_size(dir: &Path) -> anyhow::Result<u64> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        size += if entry.file_type()?.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            entry.metadata()?.len()
+        };
+    }
+    Ok(size)
+}
+
+impl Registry {
+    fn quotas_path(&self) -> PathBuf {
+        self.0.join(QUOTAS)
+    }
+    pub fn read_quotas(&self) -> anyhow::Result<Quotas> {
+        let path = self.quotas_path();
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+    fn write_quotas(&self, quotas: &Quotas) -> anyhow::Result<()> {
+        Ok(std::fs::write(
+            self.quotas_path(),
+            serde_json::to_string_pretty(quotas)?,
+        )?)
+    }
+    /// Create or replace the quota of a team
+    pub fn set_quota(&self, team: &str, quota: Quota) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        for pattern in &quota.crates {
+            glob::Pattern::new(pattern)?;
+        }
+        let mut quotas = self.read_quotas()?;
+        quotas.insert(team.into(), quota);
+        self.write_quotas(&quotas)?;
+        info!("Set the quota of {}", team);
+        Ok(())
+    }
+    pub fn remove_quota(&self, team: &str) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        let mut quotas = self.read_quotas()?;
+        anyhow::ensure!(quotas.remove(team).is_some(), "{} has no quota", team);
+        self.write_quotas(&quotas)
+    }
+    /// Crates of a team among `names`, and the size of their files
+    fn team_usage(&self, quota: &Quota, names: &[String]) -> anyhow::Result<(Vec<String>, u64)> {
+        let mut crates = vec![];
+        let mut used_bytes = 0;
+        for name in names {
+            if quota.matches(name)? {
+                used_bytes += dir_size(&self.0.join(CRATES).join(name))?;
+                crates.push(name.clone());
+            }
+        }
+        Ok((crates, used_bytes))
+    }
+    /// Current usage of each team with a quota
+    pub fn quota_usage(&self) -> anyhow::Result<Vec<Usage>> {
+        let names = self.package_names()?;
+        let mut usage = vec![];
+        for (team, quota) in self.read_quotas()? {
+            let (crates, used_bytes) = self.team_usage(&quota, &names)?;
+            usage.push(Usage {
+                team,
+                crates,
+                used_bytes,
+                limit_bytes: quota.limit_bytes,
+                warn_only: quota.warn_only,
+            });
+        }
+        Ok(usage)
+    }
+    /// Fail if adding `size` bytes to a crate exceeds the quota of its team, or only warn if the
+    /// quota allows it
+    pub(crate) fn check_quota(&self, name: &str, size: u64) -> anyhow::Result<()> {
+        self.check_quotas(&[(name, size)])
+    }
+    /// Like `check_quota`, for bytes added to several crates at once
+    pub(crate) fn check_quotas(&self, additions: &[(&str, u64)]) -> anyhow::Result<()> {
+        let quotas = self.read_quotas()?;
+        if quotas.is_empty() {
+            return Ok(());
+        }
+        let mut names = self.package_names()?;
+        // The crates might not be in the index yet
+        for (name, _) in additions {
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+        for (team, quota) in &quotas {
+            let mut added = vec![];
+            let mut size = 0;
+            for (name, bytes) in additions {
+                if quota.matches(name)? {
+                    if !added.contains(name) {
+                        added.push(*name);
+                    }
+                    size += bytes;
+                }
+            }
+            if added.is_empty() {
+                continue;
+            }
+            let (_, used_bytes) = self.team_usage(quota, &names)?;
+            if used_bytes + size <= quota.limit_bytes {
+                continue;
+            }
+            let message = format!(
+                "Adding {} bytes to {} exceeds the quota of {} ({} of {} bytes used)",
+                size,
+                added.join(", "),
+                team,
+                used_bytes,
+                quota.limit_bytes
+            );
+            if quota.warn_only {
+                warn!("{}", message);
+            } else {
+                anyhow::bail!(message);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(crates: &[&str], limit_bytes: u64) -> Quota {
+        Quota {
+            crates: crates.iter().map(|c| c.to_string()).collect(),
+            limit_bytes,
+            warn_only: false,
+        }
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let quota = quota(&["Infra-*"], 0);
+        assert!(quota.matches("infra-core").unwrap());
+        assert!(quota.matches("INFRA-core").unwrap());
+        assert!(quota.matches("Infra-core").unwrap());
+        assert!(!quota.matches("core-infra").unwrap());
+    }
+
+    #[test]
+    fn several_crates() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = Registry(dir.path().into());
+        std::fs::create_dir_all(dir.path().join(crate::INDEX)).unwrap();
+        std::fs::create_dir_all(dir.path().join(CRATES).join("infra-a")).unwrap();
+        std::fs::write(dir.path().join(CRATES).join("infra-a/file"), [0; 60]).unwrap();
+        let mut quotas = Quotas::new();
+        quotas.insert("infra".into(), quota(&["infra-*"], 100));
+        registry.write_quotas(&quotas).unwrap();
+
+        registry.check_quota("infra-a", 40).unwrap();
+        registry.check_quota("other", 1000).unwrap();
+        registry
+            .check_quotas(&[("infra-a", 20), ("infra-b", 20), ("other", 1000)])
+            .unwrap();
+        let error = registry
+            .check_quotas(&[("infra-a", 20), ("infra-b", 21)])
+            .unwrap_err();
+        assert!(error.to_string().contains("infra-a, infra-b"));
+    }
+}
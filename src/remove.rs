@@ -0,0 +1,74 @@
+//! Hard deletion of published versions, e.g. after accidentally publishing secrets. Unlike
+//! yanking, this breaks the builds whose lockfiles reference the removed versions.
+use cargo_metadata::semver::Version;
+use itertools::Itertools;
+use log::*;
+
+use crate::changes::ChangeKind;
+use crate::{Registry, CRATES};
+
+impl Registry {
+    /// Delete the archive of a version, its sidecar files and its index entry
+    pub fn remove(&self, name: &str, version: &Version) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        anyhow::ensure!(
+            !self.is_pinned(name, version)?,
+            "{} {} is pinned, unpin it first",
+            name,
+            version
+        );
+        let mut versions = self.read_package(name)?;
+        let Some(position) = versions.iter().position(|m| &m.vers == version) else {
+            anyhow::bail!("{} {} not found in the index", name, version);
+        };
+        versions.remove(position);
+        if versions.is_empty() {
+            std::fs::remove_file(self.package_index(name))?;
+            self.update_latest(name)?;
+        } else {
+            self.write_package(name, &versions)?;
+        }
+        self.remove_version_aliases(name, version)?;
+        let artifacts = self.artifacts_path(name, version);
+        if artifacts.exists() {
+            info!("Removing {:?}", artifacts);
+            std::fs::remove_dir_all(artifacts)?;
+        }
+        for path in [
+            self.crate_path(name, version),
+            self.info_path(name, version),
+            self.api_diff_path(name, version),
+            self.signature_path(name, version),
+        ] {
+            if path.exists() {
+                info!("Removing {:?}", path);
+                std::fs::remove_file(path)?;
+            }
+        }
+        self.update_manifest()?;
+        self.record_change(ChangeKind::Remove, name, Some(version))?;
+        self.commit_index(&format!("Remove {} {}", name, version))
+    }
+    /// Delete every version of a crate
+    pub fn purge_crate(&self, name: &str) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        let index = self.package_index(name);
+        anyhow::ensure!(index.exists(), "{} not found in the index", name);
+        if let Some(pinned) = self.read_pins()?.get(name) {
+            anyhow::bail!(
+                "{} has pinned versions ({}), unpin them first",
+                name,
+                pinned.iter().join(", ")
+            );
+        }
+        std::fs::remove_file(index)?;
+        let dir = self.0.join(CRATES).join(name);
+        if dir.exists() {
+            info!("Removing {:?}", dir);
+            std::fs::remove_dir_all(dir)?;
+        }
+        self.update_manifest()?;
+        self.record_change(ChangeKind::Remove, name, None)?;
+        self.commit_index(&format!("Remove {}", name))
+    }
+}
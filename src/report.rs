@@ -0,0 +1,129 @@
+//! Registry-wide reports.
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+
+use crate::{IndexMeta, Registry};
+
+#[derive(Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LicenseStatus {
+    Ok,
+    /// Neither `license` nor `license_file`
+    Missing,
+    /// `license_file` only
+    File,
+    /// `license` is not a valid SPDX expression
+    NonSpdx,
+}
+
+#[derive(Serialize)]
+pub struct LicenseEntry {
+    pub name: String,
+    pub version: String,
+    pub license: Option<String>,
+    pub license_file: Option<String>,
+    pub status: LicenseStatus,
+    /// Registry dependencies (normal and build), recursively, as `name@version`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dependencies: Option<Vec<String>>,
+}
+
+/// Newest non-yanked version of a registry dependency matching the requirement
+fn resolve(
+    registry: &Registry,
+    name: &str,
+    req: &cargo_metadata::semver::VersionReq,
+) -> Option<IndexMeta> {
+    registry
+        .read_package(name)
+        .ok()?
+        .into_iter()
+        .filter(|m| !m.yanked && req.matches(&m.vers))
+        .max_by(|a, b| a.vers.cmp(&b.vers))
+}
+
+/// Dependencies hosted in this registry, recursively
+fn closure(registry: &Registry, meta: &IndexMeta) -> Vec<String> {
+    let mut seen = BTreeSet::new();
+    let mut queue = vec![];
+    let mut visit = |meta: &IndexMeta, queue: &mut Vec<IndexMeta>| {
+        for dep in &meta.deps {
+            if dep.kind == cargo_metadata::DependencyKind::Development || dep.registry.is_some() {
+                continue;
+            }
+            let name = dep.package.as_ref().unwrap_or(&dep.name);
+            if let Some(resolved) = resolve(registry, name, &dep.req) {
+                if seen.insert(format!("{}@{}", resolved.name, resolved.vers)) {
+                    queue.push(resolved);
+                }
+            }
+        }
+    };
+    visit(meta, &mut queue);
+    while let Some(meta) = queue.pop() {
+        visit(&meta, &mut queue);
+    }
+    seen.into_iter().collect()
+}
+
+/// License information of every version in the registry
+pub fn licenses(registry: &Registry, dependencies: bool) -> anyhow::Result<Vec<LicenseEntry>> {
+    let mut entries = vec![];
+    for name in registry.package_names()? {
+        for meta in registry.read_package(&name)? {
+            let status = match (&meta.license, &meta.license_file) {
+                (Some(license), _) if spdx::Expression::parse(license).is_err() => {
+                    LicenseStatus::NonSpdx
+                }
+                (Some(_), _) => LicenseStatus::Ok,
+                (None, Some(_)) => LicenseStatus::File,
+                (None, None) => LicenseStatus::Missing,
+            };
+            entries.push(LicenseEntry {
+                dependencies: dependencies.then(|| closure(registry, &meta)),
+                name: meta.name,
+                version: meta.vers.to_string(),
+                license: meta.license,
+                license_file: meta.license_file.map(|f| f.to_string()),
+                status,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.into()
+    }
+}
+
+pub fn licenses_csv(entries: &[LicenseEntry]) -> anyhow::Result<String> {
+    let mut out = String::from("name,version,license,license_file,status,dependencies\n");
+    for e in entries {
+        let fields = [
+            e.name.clone(),
+            e.version.clone(),
+            e.license.clone().unwrap_or_default(),
+            e.license_file.clone().unwrap_or_default(),
+            serde_json::to_value(&e.status)?
+                .as_str()
+                .unwrap_or_default()
+                .into(),
+            e.dependencies
+                .as_ref()
+                .map(|d| d.join(" "))
+                .unwrap_or_default(),
+        ];
+        out += &fields
+            .iter()
+            .map(|f| csv_field(f))
+            .collect::<Vec<_>>()
+            .join(",");
+        out.push('\n');
+    }
+    Ok(out)
+}
@@ -0,0 +1,94 @@
+//! Dependency resolution against the registry, to check whether a combination of dependencies
+//! resolves without publishing anything.
+use std::path::Path;
+
+use log::*;
+
+use crate::serve::LocalIndex;
+use crate::{Lockfile, Registry};
+
+const REGISTRY_NAME: &str = "depot";
+
+/// Resolve the `[dependencies]` of a manifest-like file with cargo, and print the chosen
+/// versions. By default, crates.io is replaced by the registry, so that nothing is fetched from
+/// it; with `crates_io`, the crates.io dependencies of the registry packages and the
+/// dependencies with `registry = "crates-io"` are resolved from crates.io.
+pub fn resolve(registry: &Registry, spec: &Path, crates_io: bool) -> anyhow::Result<()> {
+    let spec: toml::Table = toml::from_str(&std::fs::read_to_string(spec)?)?;
+    let Some(toml::Value::Table(dependencies)) = spec.get("dependencies") else {
+        anyhow::bail!("The spec should have a [dependencies] table");
+    };
+    let mut dependencies = dependencies.clone();
+    if crates_io {
+        for (name, dep) in dependencies.iter_mut() {
+            let table = match dep {
+                toml::Value::String(version) => {
+                    let mut table = toml::Table::new();
+                    table.insert("version".into(), version.clone().into());
+                    *dep = table.into();
+                    dep.as_table_mut().unwrap()
+                }
+                toml::Value::Table(table) => table,
+                _ => anyhow::bail!("Invalid dependency {}", name),
+            };
+            table.entry("registry").or_insert(REGISTRY_NAME.into());
+        }
+    }
+
+    let index = LocalIndex::start(registry)?;
+    let project = crate::temp::tempdir()?;
+    let mut manifest = toml::Table::new();
+    manifest.insert(
+        "package".into(),
+        toml::toml! {
+            name = "depot-resolve"
+            version = "0.0.0"
+            edition = "2021"
+        }
+        .into(),
+    );
+    manifest.insert("dependencies".into(), dependencies.into());
+    std::fs::write(
+        project.path().join("Cargo.toml"),
+        toml::to_string(&manifest)?,
+    )?;
+    std::fs::create_dir(project.path().join("src"))?;
+    std::fs::write(project.path().join("src").join("lib.rs"), "")?;
+    let config = if crates_io {
+        format!("[registries.{}]\nindex = {:?}\n", REGISTRY_NAME, index.url)
+    } else {
+        format!(
+            "[source.crates-io]\nreplace-with = {:?}\n[source.{}]\nregistry = {:?}\n",
+            REGISTRY_NAME, REGISTRY_NAME, index.url
+        )
+    };
+    std::fs::create_dir(project.path().join(".cargo"))?;
+    std::fs::write(project.path().join(".cargo").join("config.toml"), config)?;
+
+    info!("Resolving with cargo");
+    // A separate cargo home, as the port of the index changes at every run
+    let out = std::process::Command::new("cargo")
+        .args(["generate-lockfile", "--quiet"])
+        .env("CARGO_HOME", project.path().join("cargo-home"))
+        .current_dir(project.path())
+        .output()?;
+    anyhow::ensure!(
+        out.status.success(),
+        "Resolution failed:\n{}",
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    let lockfile: Lockfile =
+        toml::from_str(&std::fs::read_to_string(project.path().join("Cargo.lock"))?)?;
+    for package in lockfile.package {
+        let Some(source) = package.source else {
+            continue;
+        };
+        let origin = if !crates_io || source.ends_with(&index.url) {
+            REGISTRY_NAME
+        } else {
+            "crates.io"
+        };
+        println!("{} {} ({})", package.name, package.version, origin);
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+//! Crates that only some tokens may download when serving, e.g. because of licensing
+//! constraints, listed in `restrictions.json` at the root of the registry.
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use log::*;
+
+use crate::Registry;
+
+const RESTRICTIONS: &str = "restrictions.json";
+
+/// Names of the tokens allowed to download each restricted crate, by lowercase crate name
+pub type Restrictions = BTreeMap<String, BTreeSet<String>>;
+
+impl Registry {
+    fn restrictions_path(&self) -> PathBuf {
+        self.0.join(RESTRICTIONS)
+    }
+    pub fn read_restrictions(&self) -> anyhow::Result<Restrictions> {
+        let path = self.restrictions_path();
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+    fn write_restrictions(&self, restrictions: &Restrictions) -> anyhow::Result<()> {
+        Ok(std::fs::write(
+            self.restrictions_path(),
+            serde_json::to_string_pretty(restrictions)?,
+        )?)
+    }
+    /// Allow the tokens to download the crate, restricting it if it was not already
+    pub fn restrict(&self, name: &str, tokens: &[String]) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        let existing = self.read_tokens()?;
+        for token in tokens {
+            anyhow::ensure!(existing.contains_key(token), "No token named {}", token);
+        }
+        let mut restrictions = self.read_restrictions()?;
+        restrictions
+            .entry(name.to_lowercase())
+            .or_default()
+            .extend(tokens.iter().cloned());
+        self.write_restrictions(&restrictions)?;
+        info!(
+            "Restricted {} to tokens {:?}",
+            name,
+            restrictions[&name.to_lowercase()]
+        );
+        Ok(())
+    }
+    /// Allow everyone to download the crate again
+    pub fn unrestrict(&self, name: &str) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        let mut restrictions = self.read_restrictions()?;
+        anyhow::ensure!(
+            restrictions.remove(&name.to_lowercase()).is_some(),
+            "{} is not restricted",
+            name
+        );
+        self.write_restrictions(&restrictions)
+    }
+    /// Tokens allowed to download the crate, or `None` if anyone can
+    pub fn allowed_tokens(&self, name: &str) -> anyhow::Result<Option<BTreeSet<String>>> {
+        Ok(self.read_restrictions()?.remove(&name.to_lowercase()))
+    }
+}
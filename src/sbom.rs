@@ -0,0 +1,283 @@
+//! Software bill of materials of the whole registry, in the CycloneDX 1.5 or SPDX 2.3 JSON
+//! formats: every version with its checksum, license and declared dependencies. Dependencies
+//! hosted in the registry (normal and build) are resolved to their newest matching version,
+//! like `report licenses --dependencies`, and linked in the dependency graph.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use serde_json::json;
+
+use crate::{IndexMeta, Registry};
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum SbomFormat {
+    Cyclonedx,
+    Spdx,
+}
+
+fn uuid() -> anyhow::Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes).map_err(|e| anyhow::anyhow!("{}", e))?;
+    // Version 4, variant 1
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    });
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+fn now() -> anyhow::Result<String> {
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    Ok(crate::freeze::Time(seconds).into())
+}
+
+/// Percent-encoding of a purl qualifier value
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Declared dependency, e.g. `serde ^1.0 (build, optional, from <index>)`
+fn describe(dep: &crate::Dependency) -> String {
+    let mut details = vec![];
+    match dep.kind {
+        cargo_metadata::DependencyKind::Development => details.push("dev".to_string()),
+        cargo_metadata::DependencyKind::Build => details.push("build".to_string()),
+        _ => {}
+    }
+    if dep.optional {
+        details.push("optional".into());
+    }
+    if let Some(registry) = &dep.registry {
+        details.push(format!("from {}", registry));
+    }
+    let name = dep.package.as_ref().unwrap_or(&dep.name);
+    if details.is_empty() {
+        format!("{} {}", name, dep.req)
+    } else {
+        format!("{} {} ({})", name, dep.req, details.join(", "))
+    }
+}
+
+struct Entries {
+    /// Versions of each crate
+    crates: BTreeMap<String, Vec<IndexMeta>>,
+    /// Sparse index URL, the repository of the purls
+    index: Option<String>,
+}
+
+impl Entries {
+    fn read(registry: &Registry) -> anyhow::Result<Self> {
+        let mut crates = BTreeMap::new();
+        for name in registry.package_names()? {
+            crates.insert(name.clone(), registry.read_package(&name)?);
+        }
+        let index = registry
+            .config()?
+            .base_url()
+            .map(crate::cargo_config::sparse_index);
+        Ok(Self { crates, index })
+    }
+    fn versions(&self) -> impl Iterator<Item = &IndexMeta> {
+        self.crates.values().flatten()
+    }
+    fn purl(&self, meta: &IndexMeta) -> String {
+        let mut purl = format!("pkg:cargo/{}@{}", meta.name, meta.vers);
+        if let Some(index) = &self.index {
+            purl += &format!("?repository_url={}", encode(index));
+        }
+        purl
+    }
+    /// Newest non-yanked versions of the registry dependencies (normal and build)
+    fn resolved_dependencies<'a>(&'a self, meta: &'a IndexMeta) -> Vec<&'a IndexMeta> {
+        meta.deps
+            .iter()
+            .filter(|d| d.kind != cargo_metadata::DependencyKind::Development)
+            .filter(|d| d.registry.is_none())
+            .filter_map(|d| {
+                self.crates
+                    .get(d.package.as_ref().unwrap_or(&d.name))?
+                    .iter()
+                    .filter(|m| !m.yanked && d.req.matches(&m.vers))
+                    .max_by(|a, b| a.vers.cmp(&b.vers))
+            })
+            .collect()
+    }
+}
+
+fn valid_license(meta: &IndexMeta) -> Option<&str> {
+    meta.license
+        .as_deref()
+        .filter(|l| spdx::Expression::parse(l).is_ok())
+}
+
+fn cyclonedx(entries: &Entries) -> anyhow::Result<serde_json::Value> {
+    let mut components = vec![];
+    let mut dependencies = vec![];
+    for meta in entries.versions() {
+        let purl = entries.purl(meta);
+        let licenses = match (valid_license(meta), &meta.license) {
+            (Some(expression), _) => json!([{ "expression": expression }]),
+            (None, Some(name)) => json!([{ "license": { "name": name } }]),
+            (None, None) => json!([]),
+        };
+        let mut properties = meta
+            .deps
+            .iter()
+            .map(|d| json!({ "name": "cargo:dependency", "value": describe(d) }))
+            .collect::<Vec<_>>();
+        if meta.yanked {
+            properties.push(json!({ "name": "cargo:yanked", "value": "true" }));
+        }
+        components.push(json!({
+            "type": "library",
+            "bom-ref": purl,
+            "name": meta.name,
+            "version": meta.vers.to_string(),
+            "purl": purl,
+            "hashes": [{ "alg": "SHA-256", "content": meta.cksum }],
+            "licenses": licenses,
+            "properties": properties,
+        }));
+        dependencies.push(json!({
+            "ref": purl,
+            "dependsOn": entries
+                .resolved_dependencies(meta)
+                .into_iter()
+                .map(|d| entries.purl(d))
+                .collect::<Vec<_>>(),
+        }));
+    }
+    Ok(json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "serialNumber": format!("urn:uuid:{}", uuid()?),
+        "version": 1,
+        "metadata": {
+            "timestamp": now()?,
+            "tools": {
+                "components": [{
+                    "type": "application",
+                    "name": "cargo-depot",
+                    "version": env!("CARGO_PKG_VERSION"),
+                }],
+            },
+        },
+        "components": components,
+        "dependencies": dependencies,
+    }))
+}
+
+fn spdx_id(meta: &IndexMeta) -> String {
+    let id = format!("{}-{}", meta.name, meta.vers)
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '.' | '-' => c,
+            _ => '-',
+        })
+        .collect::<String>();
+    format!("SPDXRef-Package-{}", id)
+}
+
+fn spdx(registry: &Registry, entries: &Entries) -> anyhow::Result<serde_json::Value> {
+    let config = registry.config()?;
+    let mut packages = vec![];
+    let mut relationships = vec![];
+    for meta in entries.versions() {
+        let id = spdx_id(meta);
+        let mut package = json!({
+            "SPDXID": id,
+            "name": meta.name,
+            "versionInfo": meta.vers.to_string(),
+            "downloadLocation": config.download_url(&meta.name, &meta.vers.to_string(), &meta.cksum),
+            "filesAnalyzed": false,
+            "checksums": [{ "algorithm": "SHA256", "checksumValue": meta.cksum }],
+            "licenseConcluded": "NOASSERTION",
+            "licenseDeclared": valid_license(meta).unwrap_or("NOASSERTION"),
+            "copyrightText": "NOASSERTION",
+            "externalRefs": [{
+                "referenceCategory": "PACKAGE-MANAGER",
+                "referenceType": "purl",
+                "referenceLocator": entries.purl(meta),
+            }],
+        });
+        if let (None, Some(license)) = (valid_license(meta), &meta.license) {
+            package["licenseComments"] = format!("Not an SPDX expression: {}", license).into();
+        }
+        let mut comment = vec![];
+        if meta.yanked {
+            comment.push("Yanked.".to_string());
+        }
+        if !meta.deps.is_empty() {
+            comment.push(format!(
+                "Declared dependencies: {}.",
+                meta.deps
+                    .iter()
+                    .map(describe)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ));
+        }
+        if !comment.is_empty() {
+            package["comment"] = comment.join(" ").into();
+        }
+        packages.push(package);
+        relationships.push(json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": id,
+        }));
+        for dep in entries.resolved_dependencies(meta) {
+            relationships.push(json!({
+                "spdxElementId": id,
+                "relationshipType": "DEPENDS_ON",
+                "relatedSpdxElement": spdx_id(dep),
+            }));
+        }
+    }
+    let namespace = format!(
+        "{}/sbom/{}",
+        config.base_url().unwrap_or("https://cargo-depot.invalid"),
+        uuid()?
+    );
+    Ok(json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": "cargo-depot registry",
+        "documentNamespace": namespace,
+        "creationInfo": {
+            "created": now()?,
+            "creators": [format!("Tool: cargo-depot-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": packages,
+        "relationships": relationships,
+    }))
+}
+
+/// Bill of materials covering every version of the registry
+pub fn sbom(registry: &Registry, format: SbomFormat) -> anyhow::Result<serde_json::Value> {
+    let entries = Entries::read(registry)?;
+    match format {
+        SbomFormat::Cyclonedx => cyclonedx(&entries),
+        SbomFormat::Spdx => spdx(registry, &entries),
+    }
+}
@@ -0,0 +1,371 @@
+//! Minimal HTTP server for the registry files, to avoid setting up a web server for local use.
+use std::path::{Component, Path, PathBuf};
+
+use log::*;
+
+use crate::dist::DIST;
+use crate::signing::PUBLIC_KEY;
+use crate::{Error, IndexConfig, Registry, CRATES, INDEX, MANIFEST};
+
+fn url_path(url: &str) -> Option<&Path> {
+    Some(Path::new(
+        url.split(['?', '#']).next()?.trim_start_matches('/'),
+    ))
+}
+
+/// Map a request URL to a file in the registry, rejecting paths outside of the served
+/// directories.
+fn resolve(registry: &Registry, url: &str) -> Option<PathBuf> {
+    let path = url_path(url)?;
+    // Hidden files include the git repository of the index
+    if !path.components().all(|c| match c {
+        Component::Normal(c) => !c.to_string_lossy().starts_with('.'),
+        _ => false,
+    }) {
+        return None;
+    }
+    let served = [INDEX, CRATES, DIST].iter().any(|d| path.starts_with(d))
+        || [MANIFEST, PUBLIC_KEY].iter().any(|f| path == Path::new(f));
+    let path = registry.0.join(path);
+    (served && path.is_file()).then_some(path)
+}
+
+/// Crate whose index file, archives or binaries are requested
+fn requested_crate(url: &str) -> Option<String> {
+    let path = url_path(url)?;
+    let mut components = path.iter().map(|c| c.to_string_lossy());
+    let root = components.next()?;
+    if root == INDEX {
+        let name = components.last()?;
+        (name != "config.json").then(|| name.into_owned())
+    } else if root == CRATES || root == DIST {
+        components.next().map(|c| c.into_owned())
+    } else {
+        None
+    }
+}
+
+fn query_param<'a>(url: &'a str, key: &str) -> Option<&'a str> {
+    let (_, query) = url.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|p| p.split_once('='))
+        .find_map(|(k, v)| (k == key).then_some(v))
+}
+
+/// Maximum number of events returned by `/changes`, clients request the next ones with a higher
+/// `since`
+const CHANGES_PAGE: usize = 1000;
+
+/// Events of the changes feed after the `since` sequence number, excluding the restricted crates
+/// that `user` cannot access
+fn changes_response(
+    registry: &Registry,
+    url: &str,
+    user: Option<&str>,
+) -> anyhow::Result<tiny_http::Response<std::io::Cursor<Vec<u8>>>> {
+    let Ok(since) = query_param(url, "since").unwrap_or("0").parse() else {
+        return Ok(error_response(400, "Invalid since parameter"));
+    };
+    let mut changes = vec![];
+    for change in registry.read_changes(since)? {
+        if changes.len() == CHANGES_PAGE {
+            break;
+        }
+        if let Some(allowed) = registry.allowed_tokens(&change.name)? {
+            if !user.map_or(false, |u| allowed.contains(u)) {
+                continue;
+            }
+        }
+        changes.push(change);
+    }
+    Ok(json_response(
+        200,
+        serde_json::json!({ "changes": changes }),
+    ))
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => "application/json",
+        Some("crate") => "application/x-tar",
+        Some("txt") => "text/plain; charset=utf-8",
+        // Index files have no extension
+        None => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+fn json_response(
+    status: u16,
+    body: serde_json::Value,
+) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    tiny_http::Response::from_data(body.to_string())
+        .with_status_code(status)
+        .with_header(
+            tiny_http::Header::from_bytes("Content-Type", "application/json")
+                .expect("valid header"),
+        )
+}
+
+fn error_response(status: u16, detail: &str) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    json_response(status, serde_json::json!({"errors": [{"detail": detail}]}))
+}
+
+/// Route an authorized API request, see
+/// https://doc.rust-lang.org/cargo/reference/registry-web-api.html
+fn api_call(
+    registry: &Registry,
+    request: &mut tiny_http::Request,
+    user: &str,
+    max_upload: u64,
+) -> anyhow::Result<Option<serde_json::Value>> {
+    let path = request
+        .url()
+        .trim_start_matches("/api/v1/crates/")
+        .to_string();
+    let segments = path.split('/').collect::<Vec<_>>();
+    Ok(Some(match (request.method(), segments.as_slice()) {
+        (tiny_http::Method::Put, ["new"]) => {
+            // Checked before reading, and while reading for requests without Content-Length
+            let too_large = || Error::UploadTooLarge { limit: max_upload };
+            if request.body_length().is_some_and(|l| l as u64 > max_upload) {
+                return Err(too_large().into());
+            }
+            let mut body = vec![];
+            std::io::Read::read_to_end(
+                &mut std::io::Read::take(request.as_reader(), max_upload + 1),
+                &mut body,
+            )?;
+            if body.len() as u64 > max_upload {
+                return Err(too_large().into());
+            }
+            registry.publish(&body, user)?;
+            serde_json::json!({
+                "warnings": {"invalid_categories": [], "invalid_badges": [], "other": []}
+            })
+        }
+        (tiny_http::Method::Delete, [name, version, "yank"]) => {
+            registry.yank(name, &version.parse()?)?;
+            serde_json::json!({"ok": true})
+        }
+        (tiny_http::Method::Put, [name, version, "unyank"]) => {
+            registry.unyank(name, &version.parse()?)?;
+            serde_json::json!({"ok": true})
+        }
+        _ => return Ok(None),
+    }))
+}
+
+/// Name of the token sent in the Authorization header, if it is valid
+fn authenticate(
+    registry: &Registry,
+    request: &tiny_http::Request,
+) -> Result<Option<String>, tiny_http::Response<std::io::Cursor<Vec<u8>>>> {
+    let Some(token) = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .map(|h| h.value.as_str().trim_start_matches("Bearer ").to_string())
+    else {
+        return Ok(None);
+    };
+    match registry.check_token(&token) {
+        Ok(Some(user)) => Ok(Some(user)),
+        Ok(None) => Err(error_response(403, "Invalid token")),
+        Err(e) => {
+            warn!("Failed to check token: {}", e);
+            Err(error_response(500, "Failed to check token"))
+        }
+    }
+}
+
+/// Requests under `/api/v1/crates/`, which require a token created with `token create`
+fn handle_api(
+    registry: &Registry,
+    request: &mut tiny_http::Request,
+    max_upload: u64,
+) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    let user = match authenticate(registry, request) {
+        Ok(Some(user)) => user,
+        Ok(None) => return error_response(403, "Missing token"),
+        Err(response) => return response,
+    };
+    info!("{} {} (token {})", request.method(), request.url(), user);
+    match api_call(registry, request, &user, max_upload) {
+        Ok(Some(response)) => json_response(200, response),
+        Ok(None) => error_response(404, "Not found"),
+        Err(e) if matches!(e.downcast_ref(), Some(Error::UploadTooLarge { .. })) => {
+            warn!("{} {} rejected: {}", request.method(), request.url(), e);
+            error_response(413, &e.to_string())
+        }
+        Err(e) => {
+            warn!("{} {} failed: {}", request.method(), request.url(), e);
+            error_response(400, &e.to_string())
+        }
+    }
+}
+
+fn respond(
+    registry: &Registry,
+    mut request: tiny_http::Request,
+    api: Option<&str>,
+    max_upload: u64,
+) -> anyhow::Result<()> {
+    if api.is_some() && request.url().starts_with("/api/v1/crates/") {
+        let response = handle_api(registry, &mut request, max_upload);
+        return Ok(request.respond(response)?);
+    }
+    if !matches!(
+        request.method(),
+        tiny_http::Method::Get | tiny_http::Method::Head
+    ) {
+        return Ok(request.respond(tiny_http::Response::empty(405))?);
+    }
+    let config = registry.config()?;
+    if config.auth_required {
+        // Cargo retries with the token after a 401 on config.json
+        match authenticate(registry, &request) {
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(request.respond(error_response(401, "Authentication required"))?),
+            Err(response) => return Ok(request.respond(response)?),
+        }
+    }
+    if url_path(request.url()) == Some(Path::new("changes")) {
+        let user = match authenticate(registry, &request) {
+            Ok(user) => user,
+            Err(response) => return Ok(request.respond(response)?),
+        };
+        let response = changes_response(registry, request.url(), user.as_deref())?;
+        debug!(
+            "{} {} {}",
+            request.method(),
+            request.url(),
+            response.status_code().0
+        );
+        return Ok(request.respond(response)?);
+    }
+    if let Some(allowed) = requested_crate(request.url())
+        .map(|name| registry.allowed_tokens(&name))
+        .transpose()?
+        .flatten()
+    {
+        match authenticate(registry, &request) {
+            Ok(Some(user)) if allowed.contains(&user) => {}
+            Ok(Some(user)) => {
+                info!(
+                    "{} {} denied to token {}",
+                    request.method(),
+                    request.url(),
+                    user
+                );
+                return Ok(request.respond(error_response(403, "Restricted crate"))?);
+            }
+            Ok(None) => return Ok(request.respond(error_response(401, "Authentication required"))?),
+            Err(response) => return Ok(request.respond(response)?),
+        }
+    }
+    let Some(path) = resolve(registry, request.url()) else {
+        debug!("{} {} 404", request.method(), request.url());
+        return Ok(request.respond(tiny_http::Response::empty(404))?);
+    };
+    debug!("{} {} 200", request.method(), request.url());
+    if let (Some(api), true) = (api, path.ends_with(Path::new(INDEX).join("config.json"))) {
+        // Advertise the API to cargo
+        let mut config = config;
+        config.api = Some(api.into());
+        return Ok(request.respond(json_response(200, serde_json::to_value(config)?))?);
+    }
+    let header =
+        tiny_http::Header::from_bytes("Content-Type", content_type(&path)).expect("valid header");
+    Ok(request
+        .respond(tiny_http::Response::from_file(std::fs::File::open(path)?).with_header(header))?)
+}
+
+/// Index and archives served on an ephemeral local port, without authentication, for cargo to
+/// read them. The server stops when dropped.
+pub(crate) struct LocalIndex {
+    server: std::sync::Arc<tiny_http::Server>,
+    pub url: String,
+}
+
+impl LocalIndex {
+    pub fn start(registry: &Registry) -> anyhow::Result<Self> {
+        let server = std::sync::Arc::new(
+            tiny_http::Server::http("127.0.0.1:0").map_err(|e| anyhow::anyhow!(e))?,
+        );
+        let Some(address) = server.server_addr().to_ip() else {
+            anyhow::bail!("Failed to get the address of the local index server");
+        };
+        let url = format!("sparse+http://{}/{}/", address, INDEX);
+        let dl = IndexConfig::from_url(&format!("http://{}", address)).dl;
+        let root = registry.0.clone();
+        let thread_server = server.clone();
+        std::thread::spawn(move || {
+            let registry = Registry(root);
+            for request in thread_server.incoming_requests() {
+                let url = request.url().to_string();
+                let response = match resolve(&registry, &url) {
+                    Some(path) if path.ends_with(Path::new(INDEX).join("config.json")) => {
+                        registry.config().and_then(|mut config| {
+                            config.dl = dl.clone();
+                            config.auth_required = false;
+                            Ok(json_response(200, serde_json::to_value(config)?))
+                        })
+                    }
+                    Some(path)
+                        if path.starts_with(registry.0.join(INDEX))
+                            || path.starts_with(registry.0.join(CRATES)) =>
+                    {
+                        std::fs::read(path)
+                            .map(tiny_http::Response::from_data)
+                            .map_err(Into::into)
+                    }
+                    _ => Ok(tiny_http::Response::from_data(vec![]).with_status_code(404)),
+                };
+                let res = response.and_then(|r| Ok(request.respond(r)?));
+                if let Err(e) = res {
+                    warn!("Failed to respond to {}: {}", url, e);
+                }
+            }
+        });
+        Ok(Self { server, url })
+    }
+}
+
+impl Drop for LocalIndex {
+    fn drop(&mut self) {
+        self.server.unblock();
+    }
+}
+
+/// Serve the index, the archives and the prebuilt binaries until the process is stopped, and
+/// optionally accept `cargo publish` requests.
+pub fn serve(
+    registry: &Registry,
+    address: &str,
+    port: u16,
+    allow_publish: bool,
+    max_upload: u64,
+) -> anyhow::Result<()> {
+    let api = if allow_publish {
+        let Some(url) = registry.config()?.base_url().map(String::from) else {
+            anyhow::bail!("Publishing requires the download URL layout created by --url");
+        };
+        Some(url)
+    } else {
+        None
+    };
+    let address = format!("{}:{}", address, port);
+    let server = tiny_http::Server::http(&address).map_err(|e| anyhow::anyhow!(e))?;
+    info!(
+        "Serving {:?} on http://{}, use sparse+http://{}/{}/ as the index URL",
+        registry.0, address, address, INDEX
+    );
+    for request in server.incoming_requests() {
+        if let Err(e) = respond(registry, request, api.as_deref(), max_upload) {
+            warn!("Failed to respond: {}", e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,171 @@
+//! Settings of the registry, stored in `depot.toml` at its root: defaults of the command line
+//! flags, and mappings of the registries of dependencies.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::Registry;
+
+pub const SETTINGS: &str = "depot.toml";
+
+const CRATES_IO: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+/// Registry that the index entries point the dependencies on another registry to
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum RegistryMapping {
+    /// The original registry (`keep`)
+    Keep,
+    /// This registry (`depot`), where the dependencies must be added as well
+    Depot,
+    /// Another registry, by index URL
+    Index(String),
+}
+impl TryFrom<String> for RegistryMapping {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> anyhow::Result<Self> {
+        Ok(match value.as_str() {
+            "keep" => Self::Keep,
+            "depot" => Self::Depot,
+            url if url.contains("://") => Self::Index(value),
+            _ => anyhow::bail!("Expected `keep`, `depot` or an index URL, got {:?}", value),
+        })
+    }
+}
+impl From<RegistryMapping> for String {
+    fn from(mapping: RegistryMapping) -> Self {
+        match mapping {
+            RegistryMapping::Keep => "keep".into(),
+            RegistryMapping::Depot => "depot".into(),
+            RegistryMapping::Index(url) => url,
+        }
+    }
+}
+
+/// Defaults of the packaging flags. Boolean flags are enabled when set either here or on the
+/// command line, and the other values given on the command line take precedence.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PackageDefaults {
+    pub features: Option<String>,
+    #[serde(default)]
+    pub all_features: bool,
+    #[serde(default)]
+    pub verify_msrv: bool,
+    #[serde(default)]
+    pub keep_bins: bool,
+    #[serde(default)]
+    pub keep_examples: bool,
+    #[serde(default)]
+    pub verify: bool,
+    /// Relative to the root of the registry
+    pub policy: Option<PathBuf>,
+    #[serde(default)]
+    pub check_features: Vec<String>,
+    #[serde(default)]
+    pub check_target: Vec<String>,
+    #[serde(default)]
+    pub no_cargo: bool,
+    #[serde(default)]
+    pub offline: bool,
+    #[serde(default)]
+    pub frozen: bool,
+    #[serde(default)]
+    pub locked: bool,
+    /// Cargo configuration values, as with `--config`
+    #[serde(default)]
+    pub config: Vec<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    /// URL of the registry, used when initializing and migrating it
+    pub url: Option<String>,
+    /// Name of the registry in the cargo configuration printed by `init`
+    pub name: Option<String>,
+    /// Sparse index used by `mirror`, `mirror-crate` and `verify-upstream`
+    pub upstream: Option<String>,
+    /// Fetch the metadata of mirrored crates from the web API of the upstream registry
+    #[serde(default)]
+    pub upstream_metadata: bool,
+    #[serde(default)]
+    pub package: PackageDefaults,
+    /// Mappings of the registries of dependencies, by index URL. Registries other than crates.io
+    /// are mapped to this registry unless listed.
+    #[serde(default)]
+    pub registries: BTreeMap<String, RegistryMapping>,
+    /// Windows during which publishing is rejected
+    #[serde(default)]
+    pub freeze: Vec<crate::freeze::Freeze>,
+    /// Generators of artifacts, by name, enabled per crate in `[package.metadata.depot]`
+    #[serde(default)]
+    pub generators: BTreeMap<String, crate::generators::Generator>,
+    /// Minisign secret key signing the archives, relative to the root of the registry
+    pub signing_key: Option<PathBuf>,
+}
+
+/// Index URL of a package source, without the `registry+` prefix of git indexes
+fn index_url(source: &str) -> &str {
+    source
+        .strip_prefix("registry+")
+        .unwrap_or(source)
+        .trim_end_matches('/')
+}
+
+impl Settings {
+    /// Registry of a dependency in the index entry, `None` for this registry, i.e. for path and
+    /// git dependencies, and for other registries unless mapped otherwise.
+    pub(crate) fn dependency_registry(&self, dep: &cargo_metadata::Dependency) -> Option<String> {
+        let source = dep.source.as_ref().filter(|_| dep.path.is_none())?;
+        if source == CRATES_IO {
+            return Some(source.clone());
+        }
+        if !source.starts_with("registry+") && !source.starts_with("sparse+") {
+            return None;
+        }
+        match self
+            .registries
+            .iter()
+            .find(|(url, _)| index_url(url) == index_url(source))
+            .map(|(_, mapping)| mapping)
+        {
+            Some(RegistryMapping::Keep) => Some(source.clone()),
+            Some(RegistryMapping::Index(url)) => Some(url.clone()),
+            Some(RegistryMapping::Depot) | None => None,
+        }
+    }
+}
+
+/// Settings of the registry at `root`, the defaults if it has no `depot.toml`
+pub fn read(root: &Path) -> anyhow::Result<Settings> {
+    let path = root.join(SETTINGS);
+    if !path.exists() {
+        return Ok(Default::default());
+    }
+    let mut settings: Settings = toml::from_str(&std::fs::read_to_string(&path)?)
+        .with_context(|| format!("Invalid {:?}", path))?;
+    settings.package.policy = settings.package.policy.map(|p| root.join(p));
+    settings.signing_key = settings.signing_key.map(|p| root.join(p));
+    #[cfg(feature = "package")]
+    for freeze in &settings.freeze {
+        freeze
+            .validate()
+            .with_context(|| format!("Invalid freeze in {:?}", path))?;
+    }
+    #[cfg(feature = "package")]
+    for (name, generator) in &settings.generators {
+        generator
+            .validate()
+            .with_context(|| format!("Invalid generator {} in {:?}", name, path))?;
+    }
+    Ok(settings)
+}
+
+impl Registry {
+    pub fn settings(&self) -> anyhow::Result<Settings> {
+        read(&self.0)
+    }
+}
@@ -0,0 +1,206 @@
+//! Detached [minisign](https://jedisct1.github.io/minisign/) signatures of the archives, written
+//! next to them as `<crate>-<version>.crate.sig` when `signing-key` is set in `depot.toml`. The
+//! public key is published at the root of the registry, so that consumers can verify the
+//! provenance of the archives with `minisign -V` or `cargo depot verify --signatures`.
+#[cfg(feature = "package")]
+use std::path::Path;
+use std::path::PathBuf;
+
+use cargo_metadata::semver::Version;
+#[cfg(feature = "package")]
+use log::*;
+use serde::Serialize;
+
+use crate::Registry;
+
+pub const PUBLIC_KEY: &str = "minisign.pub";
+/// Environment variable with the password of an encrypted signing key
+#[cfg(feature = "package")]
+const PASSWORD_VAR: &str = "CARGO_DEPOT_SIGNING_PASSWORD";
+
+impl Registry {
+    pub fn signature_path(&self, name: &str, version: &Version) -> PathBuf {
+        self.crate_path(name, version).with_extension("crate.sig")
+    }
+}
+
+/// Identifies the archive in the trusted comment, so that a signature cannot be passed off for
+/// another version
+#[cfg(feature = "package")]
+fn signed_file(name: &str, version: &Version) -> String {
+    format!("file:{}-{}.crate", name, version)
+}
+
+#[derive(Default, Serialize)]
+pub struct VerificationReport {
+    pub versions: usize,
+    pub missing_archives: Vec<String>,
+    pub checksum_mismatches: Vec<String>,
+    pub missing_signatures: Vec<String>,
+    pub invalid_signatures: Vec<String>,
+}
+
+impl VerificationReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing_archives.is_empty()
+            && self.checksum_mismatches.is_empty()
+            && self.missing_signatures.is_empty()
+            && self.invalid_signatures.is_empty()
+    }
+}
+
+/// Write a new secret key to `path`, encrypted with the password of
+/// `CARGO_DEPOT_SIGNING_PASSWORD` if set
+#[cfg(feature = "package")]
+pub fn generate_key(path: &Path) -> anyhow::Result<()> {
+    let password = std::env::var(PASSWORD_VAR).unwrap_or_default();
+    if password.is_empty() {
+        warn!("{} is not set, the key will not be encrypted", PASSWORD_VAR);
+    }
+    let keys = minisign::KeyPair::generate_encrypted_keypair(Some(password))?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to create {:?}: {}", path, e))?;
+    std::io::Write::write_all(&mut file, &keys.sk.to_box(None)?.to_bytes())?;
+    info!("Wrote the secret key to {:?}", path);
+    Ok(())
+}
+
+#[cfg(feature = "package")]
+impl Registry {
+    fn signing_key(&self) -> anyhow::Result<Option<minisign::SecretKey>> {
+        let Some(path) = self.settings()?.signing_key else {
+            return Ok(None);
+        };
+        // Unencrypted keys (`minisign -G -W`) have an empty password; never prompt for one
+        let password = std::env::var(PASSWORD_VAR).unwrap_or_default();
+        let key = minisign::SecretKey::from_file(&path, Some(password))
+            .map_err(|e| anyhow::anyhow!("Failed to read the signing key {:?}: {}", path, e))?;
+        Ok(Some(key))
+    }
+    pub fn public_key(&self) -> anyhow::Result<minisign::PublicKey> {
+        let path = self.0.join(PUBLIC_KEY);
+        anyhow::ensure!(
+            path.exists(),
+            "No public key at {:?}, set signing-key in depot.toml to sign the archives",
+            path
+        );
+        Ok(minisign::PublicKey::from_file(path)?)
+    }
+    /// Sign the archive of a version, before it is moved into the registry, and publish the
+    /// public key, if a signing key is configured. Otherwise, remove the signature of a replaced
+    /// archive.
+    pub(crate) fn sign_archive(
+        &self,
+        name: &str,
+        version: &Version,
+        archive: &Path,
+    ) -> anyhow::Result<()> {
+        let signature = self.signature_path(name, version);
+        let Some(key) = self.signing_key()? else {
+            if signature.exists() {
+                warn!(
+                    "No signing key, removing the previous signature {:?}",
+                    signature
+                );
+                std::fs::remove_file(signature)?;
+            }
+            return Ok(());
+        };
+        let public_key = minisign::PublicKey::from_secret_key(&key)?;
+        let public_key_path = self.0.join(PUBLIC_KEY);
+        let public_key = public_key.to_box()?.into_string();
+        if std::fs::read_to_string(&public_key_path).ok().as_ref() != Some(&public_key) {
+            info!("Publishing the public key at {:?}", public_key_path);
+            crate::write_atomic(&public_key_path, &public_key)?;
+        }
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        let signed = minisign::sign(
+            None,
+            &key,
+            std::fs::File::open(archive)?,
+            Some(&format!(
+                "timestamp:{}\t{}",
+                timestamp,
+                signed_file(name, version)
+            )),
+            Some("signature from cargo-depot"),
+        )?;
+        crate::write_atomic(&signature, signed.into_string())?;
+        debug!("Signed {} {}", name, version);
+        Ok(())
+    }
+    /// Verify the signature of the archive of a version
+    pub fn verify_signature(
+        &self,
+        public_key: &minisign::PublicKey,
+        name: &str,
+        version: &Version,
+    ) -> anyhow::Result<()> {
+        let signature = minisign::SignatureBox::from_file(self.signature_path(name, version))?;
+        minisign::verify(
+            public_key,
+            &signature,
+            std::fs::File::open(self.crate_path(name, version))?,
+            true,
+            false,
+            false,
+        )?;
+        let comment = signature.trusted_comment()?;
+        anyhow::ensure!(
+            comment
+                .split('\t')
+                .any(|field| field == signed_file(name, version)),
+            "The signature is for another file: {}",
+            comment
+        );
+        Ok(())
+    }
+    /// Check the archives against the checksums of the index, and their signatures if
+    /// `signatures` is set, for every crate or only `name`
+    pub fn verify_archives(
+        &self,
+        name: Option<&str>,
+        signatures: bool,
+    ) -> anyhow::Result<VerificationReport> {
+        let public_key = if signatures {
+            Some(self.public_key()?)
+        } else {
+            None
+        };
+        let names = match name {
+            Some(name) => vec![name.to_string()],
+            None => self.package_names()?,
+        };
+        let mut report = VerificationReport::default();
+        for name in names {
+            for meta in self.read_package(&name)? {
+                report.versions += 1;
+                let id = format!("{} {}", meta.name, meta.vers);
+                let archive = self.crate_path(&meta.name, &meta.vers);
+                if !archive.exists() {
+                    report.missing_archives.push(id);
+                    continue;
+                }
+                if crate::sha256_file(&archive)? != meta.cksum {
+                    report.checksum_mismatches.push(id.clone());
+                }
+                let Some(public_key) = &public_key else {
+                    continue;
+                };
+                if !self.signature_path(&meta.name, &meta.vers).exists() {
+                    report.missing_signatures.push(id);
+                } else if let Err(e) = self.verify_signature(public_key, &meta.name, &meta.vers) {
+                    report.invalid_signatures.push(format!("{}: {}", id, e));
+                }
+            }
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,280 @@
+//! Crate sources given on the command line, made available as local directories.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log::*;
+
+/// A local directory with the sources, which might be temporary
+pub struct Source {
+    pub path: PathBuf,
+    _tempdir: Option<tempfile::TempDir>,
+}
+
+impl Source {
+    /// The archives to add, when the source is a .crate file or a directory of them rather than a
+    /// workspace
+    pub fn archives(&self) -> anyhow::Result<Vec<PathBuf>> {
+        if self.path.is_file() {
+            return Ok(match self.path.extension() == Some("crate".as_ref()) {
+                true => vec![self.path.clone()],
+                false => vec![],
+            });
+        }
+        if !self.path.is_dir() || self.path.join("Cargo.toml").exists() {
+            return Ok(vec![]);
+        }
+        let mut archives = vec![];
+        for entry in std::fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension() == Some("crate".as_ref()) {
+                archives.push(path);
+            }
+        }
+        archives.sort();
+        Ok(archives)
+    }
+}
+
+/// Find the workspace at the first level of a directory
+fn find_workspace(dir: &Path) -> Option<PathBuf> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|d| d.ok())
+        .filter(|d| d.path().join("Cargo.toml").exists())
+        .map(|d| d.path())
+        .next()
+}
+
+fn fetch_tarball(url: &str) -> anyhow::Result<Source> {
+    info!("Downloading from {}", url);
+    let tar = flate2::read::GzDecoder::new(cargo_depot::http::get(url).call()?.into_reader());
+    let mut archive = tar::Archive::new(tar);
+    let output = cargo_depot::temp::tempdir()?;
+    archive.unpack(&output)?;
+    let Some(workspace) = find_workspace(output.path()) else {
+        anyhow::bail!("Failed to find cargo workspace at the first level of the tarball");
+    };
+    Ok(Source {
+        path: workspace,
+        _tempdir: Some(output),
+    })
+}
+
+/// `oci://<image>#<path>`: extract a workspace, a .crate file or a directory of them from a
+/// container image
+fn fetch_oci(spec: &str, engine: &str) -> anyhow::Result<Source> {
+    let Some((image, path)) = spec.split_once('#') else {
+        anyhow::bail!("Expected oci://<image>#<path in image>, got oci://{}", spec);
+    };
+    info!("Extracting {} from image {}", path, image);
+    // The container is never started, but images without a default command (e.g. scratch or
+    // distroless ones) require one to be created
+    let out = std::process::Command::new(engine)
+        .args(["create", image, "cargo-depot-extract"])
+        .output()?;
+    anyhow::ensure!(
+        out.status.success(),
+        "Failed to create container from {}: {}",
+        image,
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let container = String::from_utf8(out.stdout)?.trim().to_string();
+    let output = cargo_depot::temp::tempdir()?;
+    // Keep the name of .crate files, which is how they are recognized
+    let dest = match Path::new(path).file_name() {
+        Some(file) if path.ends_with(".crate") => output.path().join(file),
+        _ => output.path().join("source"),
+    };
+    let copy = std::process::Command::new(engine)
+        .args(["cp", &format!("{}:{}", container, path)])
+        .arg(&dest)
+        .status();
+    let remove = std::process::Command::new(engine)
+        .args(["rm", &container])
+        .output();
+    anyhow::ensure!(copy?.success(), "Failed to copy {} from {}", path, image);
+    if !remove?.status.success() {
+        warn!("Failed to remove container {}", container);
+    }
+    let source = Source {
+        path: dest,
+        _tempdir: Some(output),
+    };
+    anyhow::ensure!(
+        source.path.join("Cargo.toml").exists() || !source.archives()?.is_empty(),
+        "No cargo workspace or .crate files at {} in {}",
+        path,
+        image
+    );
+    Ok(source)
+}
+
+/// `file://<repository>#<ref>`: extract a commit, tag or branch (by default `HEAD`) of a local
+/// repository, which can be bare
+fn fetch_git(spec: &str) -> anyhow::Result<Source> {
+    let (repository, reference) = spec.split_once('#').unwrap_or((spec, "HEAD"));
+    info!("Extracting {} from {}", reference, repository);
+    let out = std::process::Command::new("git")
+        .args(["-C", repository, "archive", "--format=tar", reference])
+        .output()?;
+    anyhow::ensure!(
+        out.status.success(),
+        "Failed to extract {} from {}: {}",
+        reference,
+        repository,
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    let output = cargo_depot::temp::tempdir()?;
+    tar::Archive::new(out.stdout.as_slice()).unpack(&output)?;
+    anyhow::ensure!(
+        output.path().join("Cargo.toml").exists(),
+        "No cargo workspace at the root of {} at {}",
+        repository,
+        reference
+    );
+    Ok(Source {
+        path: output.path().into(),
+        _tempdir: Some(output),
+    })
+}
+
+/// Remote git repository: `git+<url>`, `ssh://` and `git://` URLs, and URLs ending in `.git`
+fn is_git_url(source: &str) -> bool {
+    let path = source.split(['?', '#']).next().unwrap_or(source);
+    source.starts_with("git+")
+        || source.starts_with("ssh://")
+        || source.starts_with("git://")
+        || path.ends_with(".git")
+}
+
+/// Clone a git repository at the tag or branch given after `#`, or at `?rev=<commit>`,
+/// `?branch=<branch>` or `?tag=<tag>` (by default, the default branch)
+fn clone_git(source: &str) -> anyhow::Result<Source> {
+    let source = source.strip_prefix("git+").unwrap_or(source);
+    let (url, fragment) = source.split_once('#').unwrap_or((source, ""));
+    let (url, query) = url.split_once('?').unwrap_or((url, ""));
+    let mut reference = (!fragment.is_empty()).then_some(("branch", fragment));
+    for (key, value) in query.split('&').filter_map(|q| q.split_once('=')) {
+        anyhow::ensure!(
+            ["rev", "branch", "tag"].contains(&key),
+            "Unsupported git URL parameter {:?}, expected rev, branch or tag",
+            key
+        );
+        reference = Some((key, value));
+    }
+    info!(
+        "Cloning {}{}",
+        url,
+        reference.map_or(String::new(), |(_, r)| format!(" at {}", r))
+    );
+    let output = cargo_depot::temp::tempdir()?;
+    let dest = output.path().join("repository");
+    let git = |args: &[&str], dir: &Path| -> anyhow::Result<()> {
+        let out = std::process::Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()?;
+        anyhow::ensure!(
+            out.status.success(),
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+        Ok(())
+    };
+    let dest_str = dest.to_string_lossy();
+    match reference {
+        Some(("rev", rev)) => {
+            git(&["clone", "--quiet", url, &dest_str], output.path())?;
+            git(&["checkout", "--quiet", "--detach", rev], &dest)?;
+        }
+        // Tags and branches
+        Some((_, name)) => git(
+            &[
+                "clone",
+                "--quiet",
+                "--depth=1",
+                "--branch",
+                name,
+                url,
+                &dest_str,
+            ],
+            output.path(),
+        )?,
+        None => git(
+            &["clone", "--quiet", "--depth=1", url, &dest_str],
+            output.path(),
+        )?,
+    }
+    anyhow::ensure!(
+        dest.join("Cargo.toml").exists(),
+        "No cargo workspace at the root of {}",
+        url
+    );
+    Ok(Source {
+        path: dest,
+        _tempdir: Some(output),
+    })
+}
+
+pub fn fetch(source: &str, container_engine: &str) -> anyhow::Result<Source> {
+    // Before the git URLs, as local repositories may end with .git
+    if let Some(spec) = source.strip_prefix("file://") {
+        fetch_git(spec)
+    } else if is_git_url(source) {
+        clone_git(source)
+    } else if source.starts_with("https://") || source.starts_with("http://") {
+        fetch_tarball(source)
+    } else if let Some(spec) = source.strip_prefix("oci://") {
+        fetch_oci(spec, container_engine)
+    } else {
+        Ok(Source {
+            path: source.into(),
+            _tempdir: None,
+        })
+    }
+}
+
+/// Fetch sources with up to `jobs` concurrent downloads, and call `process` on each of them in
+/// order as soon as it is available, so that the registry is only modified by one thread.
+pub fn fetch_all(
+    sources: &[String],
+    container_engine: &str,
+    jobs: usize,
+    mut process: impl FnMut(Source) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::scope(|s| {
+        for _ in 0..jobs.clamp(1, sources.len().max(1)) {
+            let (tx, next) = (tx.clone(), &next);
+            s.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let Some(source) = sources.get(i) else {
+                    break;
+                };
+                if tx.send((i, fetch(source, container_engine))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+        let mut fetched = BTreeMap::new();
+        let res = (0..sources.len()).try_for_each(|i| {
+            let source = loop {
+                if let Some(source) = fetched.remove(&i) {
+                    break source;
+                }
+                let (j, source) = rx
+                    .recv()
+                    .map_err(|_| anyhow::anyhow!("A download thread panicked"))?;
+                fetched.insert(j, source);
+            };
+            process(source?)
+        });
+        // Do not start the remaining downloads after a failure
+        next.store(sources.len(), Ordering::SeqCst);
+        res
+    })
+}
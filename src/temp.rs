@@ -0,0 +1,110 @@
+//! Location and cleanup of the temporary directories used for downloads and unpacked crates.
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use fs2::FileExt;
+use log::*;
+
+const PREFIX: &str = "cargo-depot-";
+/// Directories left by crashed runs are removed after this duration
+const STALE_AFTER: Duration = Duration::from_secs(24 * 3600);
+/// File of the directory of a run, locked while the run is alive, with its process id
+const OWNER: &str = ".owner";
+
+struct Root {
+    path: PathBuf,
+    keep_on_failure: bool,
+    /// Holds the lock on the owner file
+    owner: std::fs::File,
+}
+
+static ROOT: Mutex<Option<Root>> = Mutex::new(None);
+
+/// Create the directory holding the temporary files of this run under `parent` (by default the
+/// system temporary directory), after removing stale ones left by previous runs.
+pub fn init(parent: Option<&Path>, keep_on_failure: bool) -> anyhow::Result<()> {
+    let parent = parent.map_or_else(std::env::temp_dir, Path::to_path_buf);
+    std::fs::create_dir_all(&parent)?;
+    remove_stale(&parent)?;
+    let path = tempfile::Builder::new()
+        .prefix(PREFIX)
+        .disable_cleanup(true)
+        .tempdir_in(&parent)?
+        .path()
+        .to_path_buf();
+    debug!("Using temporary directory {:?}", path);
+    let mut owner = std::fs::File::create(path.join(OWNER))?;
+    owner.lock_exclusive()?;
+    write!(owner, "{}", std::process::id())?;
+    *ROOT.lock().unwrap() = Some(Root {
+        path,
+        keep_on_failure,
+        owner,
+    });
+    Ok(())
+}
+
+fn remove_stale(parent: &Path) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(parent)? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with(PREFIX) || !entry.file_type()?.is_dir()
+        {
+            continue;
+        }
+        let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+        if age > STALE_AFTER && !in_use(&entry.path()) {
+            info!("Removing stale temporary directory {:?}", entry.path());
+            if let Err(e) = std::fs::remove_dir_all(entry.path()) {
+                warn!("Failed to remove {:?}: {}", entry.path(), e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the run owning a directory is still alive, i.e. holds the lock on its owner file
+fn in_use(dir: &Path) -> bool {
+    let Ok(owner) = std::fs::File::open(dir.join(OWNER)) else {
+        return false;
+    };
+    if owner.try_lock_shared().is_ok() {
+        return false;
+    }
+    let pid = std::fs::read_to_string(dir.join(OWNER)).unwrap_or_default();
+    debug!(
+        "Keeping {:?}, used by the running process {}",
+        dir,
+        pid.trim()
+    );
+    true
+}
+
+/// Create a temporary directory, inside the directory of the run if [`init`] was called.
+pub fn tempdir() -> anyhow::Result<tempfile::TempDir> {
+    let root = ROOT.lock().unwrap();
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(PREFIX);
+    Ok(match &*root {
+        Some(root) => builder
+            .disable_cleanup(root.keep_on_failure)
+            .tempdir_in(&root.path)?,
+        None => builder.tempdir()?,
+    })
+}
+
+/// Remove the temporary files of the run, unless it failed and they should be kept for
+/// debugging.
+pub fn finish(success: bool) {
+    let Some(root) = ROOT.lock().unwrap().take() else {
+        return;
+    };
+    // Before removing the directory, which fails on Windows while the file is open
+    drop(root.owner);
+    if !success && root.keep_on_failure {
+        warn!("Keeping temporary files in {:?}", root.path);
+    } else if let Err(e) = std::fs::remove_dir_all(&root.path) {
+        warn!("Failed to remove {:?}: {}", root.path, e);
+    }
+}
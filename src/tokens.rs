@@ -0,0 +1,72 @@
+//! Tokens authorizing the web API, stored hashed in `tokens.json` at the root of the registry.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use itertools::Itertools;
+use log::*;
+use sha2::Digest;
+
+use crate::Registry;
+
+const TOKENS: &str = "tokens.json";
+
+fn hash(token: &str) -> String {
+    format!("{:x}", sha2::Sha256::digest(token.as_bytes()))
+}
+
+impl Registry {
+    fn tokens_path(&self) -> PathBuf {
+        self.0.join(TOKENS)
+    }
+    /// Token hashes by name
+    pub fn read_tokens(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        let path = self.tokens_path();
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+    fn write_tokens(&self, tokens: &BTreeMap<String, String>) -> anyhow::Result<()> {
+        Ok(std::fs::write(
+            self.tokens_path(),
+            serde_json::to_string_pretty(tokens)?,
+        )?)
+    }
+    /// Create a new token, which is only returned here
+    pub fn create_token(&self, name: &str) -> anyhow::Result<String> {
+        let _lock = self.lock()?;
+        let mut tokens = self.read_tokens()?;
+        anyhow::ensure!(
+            !tokens.contains_key(name),
+            "A token named {} already exists",
+            name
+        );
+        let mut bytes = [0u8; 32];
+        getrandom::fill(&mut bytes).map_err(|e| anyhow::anyhow!("{}", e))?;
+        let token = format!(
+            "depot_{}",
+            bytes.iter().map(|b| format!("{:02x}", b)).join("")
+        );
+        tokens.insert(name.into(), hash(&token));
+        self.write_tokens(&tokens)?;
+        info!("Created token {}", name);
+        Ok(token)
+    }
+    pub fn revoke_token(&self, name: &str) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        let mut tokens = self.read_tokens()?;
+        anyhow::ensure!(tokens.remove(name).is_some(), "No token named {}", name);
+        self.write_tokens(&tokens)?;
+        info!("Revoked token {}", name);
+        Ok(())
+    }
+    /// Name of the token, if it is valid
+    pub fn check_token(&self, token: &str) -> anyhow::Result<Option<String>> {
+        let hash = hash(token);
+        Ok(self
+            .read_tokens()?
+            .into_iter()
+            .find(|(_, h)| h == &hash)
+            .map(|(name, _)| name))
+    }
+}
@@ -0,0 +1,366 @@
+//! Interactive terminal browser over the registry.
+use cargo_metadata::semver::Version;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListState, Paragraph, Wrap};
+
+use crate::pins::Pins;
+use crate::{IndexMeta, Registry};
+
+#[derive(PartialEq)]
+enum Focus {
+    Search,
+    Crates,
+    Versions,
+}
+
+struct App<'a> {
+    registry: &'a Registry,
+    names: Vec<String>,
+    query: String,
+    filtered: Vec<String>,
+    crates: ListState,
+    versions: Vec<IndexMeta>,
+    versions_state: ListState,
+    focus: Focus,
+    /// Versions marked as candidates for pruning
+    marked: Vec<(String, Version)>,
+    pins: Pins,
+    /// Version to yank once confirmed
+    confirm_yank: Option<(String, Version)>,
+    status: String,
+}
+
+/// Case-insensitive subsequence match
+fn fuzzy_match(query: &str, candidate: &str) -> bool {
+    let mut candidate = candidate.chars().flat_map(char::to_lowercase);
+    query
+        .chars()
+        .flat_map(char::to_lowercase)
+        .all(|q| candidate.any(|c| c == q))
+}
+
+fn step(state: &mut ListState, len: usize, delta: isize) {
+    if len == 0 {
+        state.select(None);
+        return;
+    }
+    let current = state.selected().unwrap_or(0) as isize;
+    state.select(Some((current + delta).clamp(0, len as isize - 1) as usize));
+}
+
+impl<'a> App<'a> {
+    fn new(registry: &'a Registry) -> anyhow::Result<Self> {
+        let mut app = Self {
+            registry,
+            names: registry.package_names()?,
+            query: String::new(),
+            filtered: vec![],
+            crates: ListState::default(),
+            versions: vec![],
+            versions_state: ListState::default(),
+            focus: Focus::Crates,
+            marked: vec![],
+            pins: registry.read_pins()?,
+            confirm_yank: None,
+            status: String::new(),
+        };
+        app.filter()?;
+        Ok(app)
+    }
+    fn filter(&mut self) -> anyhow::Result<()> {
+        self.filtered = self
+            .names
+            .iter()
+            .filter(|n| fuzzy_match(&self.query, n))
+            .cloned()
+            .collect();
+        // Substring matches first
+        self.filtered.sort_by_key(|n| !n.contains(&self.query));
+        self.crates.select((!self.filtered.is_empty()).then_some(0));
+        self.load_versions()
+    }
+    fn selected_crate(&self) -> Option<&String> {
+        self.crates.selected().and_then(|i| self.filtered.get(i))
+    }
+    fn selected_version(&self) -> Option<&IndexMeta> {
+        self.versions_state
+            .selected()
+            .and_then(|i| self.versions.get(i))
+    }
+    fn load_versions(&mut self) -> anyhow::Result<()> {
+        self.versions = match self.selected_crate() {
+            Some(name) => self.registry.read_package(name)?,
+            None => vec![],
+        };
+        self.versions.sort_by(|a, b| b.vers.cmp(&a.vers));
+        self.versions_state
+            .select((!self.versions.is_empty()).then_some(0));
+        Ok(())
+    }
+    /// Ask for confirmation before yanking the selected version
+    fn request_yank(&mut self) {
+        let (Some(name), Some(meta)) = (self.selected_crate(), self.selected_version()) else {
+            return;
+        };
+        let (name, vers, yanked) = (name.clone(), meta.vers.clone(), meta.yanked);
+        if yanked {
+            self.status = format!("{}@{} is already yanked", name, vers);
+            return;
+        }
+        self.status = format!("Yank {}@{}? (y/n)", name, vers);
+        self.confirm_yank = Some((name, vers));
+    }
+    fn yank(&mut self, name: &str, vers: &Version) -> anyhow::Result<()> {
+        // Shown in the status line rather than ending the session
+        if let Err(e) = self.registry.yank(name, vers) {
+            self.status = format!("Failed to yank {}@{}: {:#}", name, vers, e);
+            return Ok(());
+        }
+        self.status = format!("Yanked {}@{}", name, vers);
+        let selected = self.versions_state.selected();
+        self.load_versions()?;
+        self.versions_state.select(selected);
+        Ok(())
+    }
+    fn toggle_mark(&mut self) {
+        let (Some(name), Some(meta)) = (self.selected_crate(), self.selected_version()) else {
+            return;
+        };
+        let key = (name.clone(), meta.vers.clone());
+        if self
+            .pins
+            .get(name)
+            .map_or(false, |v| v.contains(&meta.vers))
+        {
+            self.status = format!("{}@{} is pinned", key.0, key.1);
+            return;
+        }
+        if let Some(i) = self.marked.iter().position(|m| m == &key) {
+            self.marked.remove(i);
+            self.status = format!("Unmarked {}@{}", key.0, key.1);
+        } else {
+            self.status = format!("Marked {}@{} as prune candidate", key.0, key.1);
+            self.marked.push(key);
+        }
+    }
+    /// Returns false when the application should exit
+    fn handle(&mut self, key: KeyCode) -> anyhow::Result<bool> {
+        if let Some((name, vers)) = self.confirm_yank.take() {
+            if key == KeyCode::Char('y') {
+                self.yank(&name, &vers)?;
+            } else {
+                self.status = "Yank cancelled".into();
+            }
+            return Ok(true);
+        }
+        if self.focus == Focus::Search {
+            match key {
+                KeyCode::Char(c) => {
+                    self.query.push(c);
+                    self.filter()?;
+                }
+                KeyCode::Backspace => {
+                    self.query.pop();
+                    self.filter()?;
+                }
+                KeyCode::Enter | KeyCode::Esc | KeyCode::Down | KeyCode::Tab => {
+                    self.focus = Focus::Crates;
+                }
+                _ => {}
+            }
+            return Ok(true);
+        }
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+            KeyCode::Char('/') => self.focus = Focus::Search,
+            KeyCode::Tab | KeyCode::Right | KeyCode::Enter => self.focus = Focus::Versions,
+            KeyCode::BackTab | KeyCode::Left => self.focus = Focus::Crates,
+            KeyCode::Up | KeyCode::Char('k') | KeyCode::Down | KeyCode::Char('j') => {
+                let delta = if matches!(key, KeyCode::Up | KeyCode::Char('k')) {
+                    -1
+                } else {
+                    1
+                };
+                if self.focus == Focus::Crates {
+                    step(&mut self.crates, self.filtered.len(), delta);
+                    self.load_versions()?;
+                } else {
+                    step(&mut self.versions_state, self.versions.len(), delta);
+                }
+            }
+            KeyCode::Char('y') => self.request_yank(),
+            KeyCode::Char('m') => self.toggle_mark(),
+            _ => {}
+        }
+        Ok(true)
+    }
+    fn details(&self) -> Vec<Line> {
+        let Some(meta) = self.selected_version() else {
+            return vec![];
+        };
+        let mut lines = vec![
+            Line::from(format!("Checksum: {}", meta.cksum)),
+            Line::from(format!(
+                "License:  {}",
+                meta.license.as_deref().unwrap_or("-")
+            )),
+            Line::from(format!("Yanked:   {}", meta.yanked)),
+        ];
+        if let Some(name) = self.selected_crate() {
+            let info = self
+                .registry
+                .read_info(name, &meta.vers)
+                .unwrap_or_default();
+            let downloads = info
+                .upstream
+                .as_ref()
+                .and_then(|u| Some(format!("{} (from {})", u.downloads?, u.api)));
+            for (key, value) in [
+                ("Docs:    ", info.documentation),
+                ("Homepage:", info.homepage),
+                ("Repo:    ", info.repository),
+                ("Downloads:", downloads),
+            ] {
+                if let Some(value) = value {
+                    lines.push(Line::from(format!("{} {}", key, value)));
+                }
+            }
+        }
+        lines.extend([Line::from(""), Line::from("Features:".bold())]);
+        for (feature, enables) in meta.all_features() {
+            lines.push(Line::from(format!(
+                "  {} = [{}]",
+                feature,
+                enables.join(", ")
+            )));
+        }
+        lines.push(Line::from(""));
+        lines.push(Line::from("Dependencies:".bold()));
+        for dep in &meta.deps {
+            let mut line = format!("  {} {} ({})", dep.name, dep.req, dep.kind);
+            if dep.optional {
+                line += " optional";
+            }
+            if let Some(package) = &dep.package {
+                line += &format!(" package={}", package);
+            }
+            if let Some(registry) = &dep.registry {
+                line += &format!(" registry={}", registry);
+            }
+            lines.push(Line::from(line));
+        }
+        lines
+    }
+    fn draw(&mut self, frame: &mut ratatui::Frame) {
+        let [search, main, help] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [crates, right] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)])
+                .areas(main);
+        let [versions, details] =
+            Layout::vertical([Constraint::Length(8), Constraint::Min(0)]).areas(right);
+
+        let focused = |f: Focus| {
+            if self.focus == f {
+                Style::new().yellow()
+            } else {
+                Style::new()
+            }
+        };
+        let highlight = Style::new().add_modifier(Modifier::REVERSED);
+
+        frame.render_widget(
+            Paragraph::new(self.query.as_str()).block(
+                Block::bordered()
+                    .title("Search")
+                    .style(focused(Focus::Search)),
+            ),
+            search,
+        );
+        frame.render_stateful_widget(
+            List::new(self.filtered.iter().map(String::as_str))
+                .block(
+                    Block::bordered()
+                        .title(format!("Crates ({})", self.filtered.len()))
+                        .style(focused(Focus::Crates)),
+                )
+                .highlight_style(highlight),
+            crates,
+            &mut self.crates,
+        );
+        let name = self.selected_crate().cloned().unwrap_or_default();
+        let version_items = self.versions.iter().map(|v| {
+            let mut line = v.vers.to_string();
+            if v.yanked {
+                line += " (yanked)";
+            }
+            if self.pins.get(&name).map_or(false, |p| p.contains(&v.vers)) {
+                line += " [pinned]";
+            }
+            if self.marked.contains(&(name.clone(), v.vers.clone())) {
+                line += " [prune]";
+            }
+            line
+        });
+        frame.render_stateful_widget(
+            List::new(version_items)
+                .block(
+                    Block::bordered()
+                        .title("Versions")
+                        .style(focused(Focus::Versions)),
+                )
+                .highlight_style(highlight),
+            versions,
+            &mut self.versions_state,
+        );
+        frame.render_widget(
+            Paragraph::new(self.details())
+                .wrap(Wrap { trim: false })
+                .block(Block::bordered().title("Details")),
+            details,
+        );
+        let help_text = if self.status.is_empty() {
+            "/ search  ↑↓ navigate  ←→ switch pane  y yank  m mark for pruning  q quit".into()
+        } else {
+            self.status.clone()
+        };
+        frame.render_widget(Paragraph::new(help_text).dim(), help);
+    }
+    fn run(&mut self, terminal: &mut ratatui::DefaultTerminal) -> anyhow::Result<()> {
+        loop {
+            terminal.draw(|f| self.draw(f))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !self.handle(key.code)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Run the browser until the user exits, then print the versions marked for pruning.
+pub fn run(registry: &Registry) -> anyhow::Result<()> {
+    let mut app = App::new(registry)?;
+    // Logs would be written over the interface
+    let max_level = log::max_level();
+    log::set_max_level(log::LevelFilter::Off);
+    let mut terminal = ratatui::init();
+    let res = app.run(&mut terminal);
+    ratatui::restore();
+    log::set_max_level(max_level);
+    res?;
+    if !app.marked.is_empty() {
+        println!("Prune candidates:");
+        for (name, version) in &app.marked {
+            println!("{}@{}", name, version);
+        }
+    }
+    Ok(())
+}
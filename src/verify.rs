@@ -0,0 +1,101 @@
+//! Build verification of packaged crates. `cargo package` resolves the dependencies of the
+//! packaged manifest from crates.io, while the index entry points the path, git and alternative
+//! registry dependencies to this registry, so the crate is built against the registry instead.
+use anyhow::Context;
+use cargo_metadata::DependencyKind;
+use log::*;
+
+use crate::serve::LocalIndex;
+use crate::settings::Settings;
+use crate::{unpack_crate, PackageFlags, Registry};
+
+const REGISTRY_NAME: &str = "depot";
+
+/// Point the dependencies to the registries of the index entry, with `REGISTRY_NAME` for this
+/// registry, in the manifest normalized by `cargo package`
+fn rewrite_manifest(
+    p: &cargo_metadata::Package,
+    manifest: &mut toml::Table,
+    settings: &Settings,
+) -> anyhow::Result<()> {
+    // Not needed to build the crate, and possibly not in the registry yet, e.g. with cycles
+    manifest.remove("dev-dependencies");
+    if let Some(toml::Value::Table(targets)) = manifest.get_mut("target") {
+        for (_, target) in targets.iter_mut() {
+            if let Some(target) = target.as_table_mut() {
+                target.remove("dev-dependencies");
+            }
+        }
+    }
+    for dep in p
+        .dependencies
+        .iter()
+        .filter(|d| d.kind != DependencyKind::Development)
+    {
+        let registry = settings.dependency_registry(dep);
+        if registry.is_some() && registry == dep.source {
+            continue;
+        }
+        let key = dep.rename.as_ref().unwrap_or(&dep.name);
+        let section = match dep.kind {
+            DependencyKind::Build => "build-dependencies",
+            _ => "dependencies",
+        };
+        let mut table = &mut *manifest;
+        if let Some(target) = &dep.target {
+            table = table
+                .get_mut("target")
+                .and_then(|t| t.get_mut(target.to_string()))
+                .and_then(|t| t.as_table_mut())
+                .with_context(|| format!("Target {} not found in the packaged manifest", target))?;
+        }
+        let Some(entry) = table.get_mut(section).and_then(|s| s.get_mut(key)) else {
+            anyhow::bail!("Dependency {} not found in the packaged manifest", key);
+        };
+        if let toml::Value::String(req) = entry {
+            let mut table = toml::Table::new();
+            table.insert("version".into(), req.clone().into());
+            *entry = table.into();
+        }
+        let Some(entry) = entry.as_table_mut() else {
+            anyhow::bail!("Invalid dependency {} in the packaged manifest", key);
+        };
+        entry.remove("registry");
+        entry.remove("registry-index");
+        match registry {
+            Some(url) => entry.insert("registry-index".into(), url.into()),
+            None => entry.insert("registry".into(), REGISTRY_NAME.into()),
+        };
+    }
+    Ok(())
+}
+
+/// Build a packaged crate, with the dependencies pointing to the registry resolved from it
+pub(crate) fn verify_build(
+    registry: &Registry,
+    p: &cargo_metadata::Package,
+    crate_file: &std::path::Path,
+    flags: &PackageFlags,
+) -> anyhow::Result<()> {
+    info!("Verifying that {} builds against the registry", p.name);
+    let index = LocalIndex::start(registry)?;
+    let unpacked = unpack_crate(crate_file)?;
+    let dir = unpacked.path().join(format!("{}-{}", p.name, p.version));
+    let manifest_path = dir.join("Cargo.toml");
+    let mut manifest: toml::Table = toml::from_str(&std::fs::read_to_string(&manifest_path)?)?;
+    rewrite_manifest(p, &mut manifest, &registry.settings()?)?;
+    std::fs::write(&manifest_path, toml::to_string(&manifest)?)?;
+    std::fs::create_dir(unpacked.path().join(".cargo"))?;
+    std::fs::write(
+        unpacked.path().join(".cargo").join("config.toml"),
+        format!("[registries.{}]\nindex = {:?}\n", REGISTRY_NAME, index.url),
+    )?;
+    // A separate cargo home, as the port of the index changes at every run
+    let out = std::process::Command::new("cargo")
+        .args(["build", &flags.features.flags()])
+        .env("CARGO_HOME", unpacked.path().join("cargo-home"))
+        .current_dir(dir)
+        .status()?;
+    anyhow::ensure!(out.success(), "{} does not build from its package", p.name);
+    Ok(())
+}